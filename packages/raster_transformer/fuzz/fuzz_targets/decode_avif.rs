@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raster_transformer::avif::decode_avif;
+use raster_transformer::limits::DecodeLimits;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_avif(data, &DecodeLimits::default());
+});
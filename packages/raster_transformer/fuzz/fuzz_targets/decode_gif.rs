@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raster_transformer::gif::decode_gif_regions;
+use raster_transformer::limits::DecodeLimits;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_gif_regions(data, &DecodeLimits::default());
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raster_transformer::limits::DecodeLimits;
+use raster_transformer::png::{decode_png, png_metadata};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_png(data, &DecodeLimits::default());
+    let _ = png_metadata(data);
+});
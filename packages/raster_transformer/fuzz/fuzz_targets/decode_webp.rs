@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raster_transformer::limits::DecodeLimits;
+use raster_transformer::webp::{decode_webp, decode_webp_regions, inspect_webp_frames};
+
+fuzz_target!(|data: &[u8]| {
+    let limits = DecodeLimits::default();
+    let _ = decode_webp(data, &limits);
+    let _ = decode_webp_regions(data, &limits);
+    let _ = inspect_webp_frames(data);
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use raster_transformer::transform_one_image_impl;
+
+const EXTNAMES: [&str; 3] = ["webp", "png", "gif"];
+
+// The first byte picks the output format and scale, the rest is the image;
+// the input format is sniffed from the bytes.
+fuzz_target!(|data: &[u8]| {
+    let Some((&params, image)) = data.split_first() else {
+        return;
+    };
+
+    let output = EXTNAMES[usize::from(params & 0b11) % EXTNAMES.len()];
+    let scale = [0.5, 1.0][usize::from(params >> 2 & 1)];
+    let min_delay = u32::from(params >> 3 & 0b11) * 20;
+
+    let _ = transform_one_image_impl("webp", output, image, scale, min_delay, 50.0);
+});
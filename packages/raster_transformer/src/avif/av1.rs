@@ -0,0 +1,462 @@
+//! AV1 coding for AVIF sequences and AVIF input: rav1e encodes, rav1d
+//! decodes. rav1d doesn't build for wasm32 yet, so decoding is native only.
+
+use super::isobmff::Av1Track;
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use image::RgbaImage;
+use rav1e::prelude::{
+    ChromaSamplePosition, ChromaSampling, ColorDescription, ColorPrimaries, Config, Context,
+    EncoderConfig, EncoderStatus, FrameType, MatrixCoefficients, PixelRange, Rational,
+    SpeedSettings, TransferCharacteristics,
+};
+
+fn encode_error(e: impl ToString) -> TransformError {
+    TransformError::encode(ImageFormat::Avif, e)
+}
+
+/// Maps `quality` (0-100) onto the AV1 quantizer with the curve ravif uses
+/// for stills, so sequences and stills of one quality look alike.
+pub fn av1_quantizer(quality: f32) -> usize {
+    let q = quality.clamp(0.0, 100.0) / 100.0;
+    let x = if q >= 0.85 {
+        (1.0 - q) * 3.0
+    } else if q > 0.25 {
+        1.0 - 0.125 - q * 0.5
+    } else {
+        1.0 - q
+    };
+    (x * 255.0).round() as usize
+}
+
+/// BT.601 full-range luma and chroma of an sRGB pixel.
+fn rgb_to_yuv(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    (y, (b - y) / 1.772 + 128.0, (r - y) / 1.402 + 128.0)
+}
+
+/// The 4:2:0 planes of `frame`, chroma averaged over each 2x2 block.
+fn yuv420_planes(frame: &RgbaImage) -> [Vec<u8>; 3] {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let mut y_plane = vec![0; width * height];
+    let mut u_sum = vec![0f32; chroma_width * chroma_height];
+    let mut v_sum = vec![0f32; chroma_width * chroma_height];
+    let mut counts = vec![0f32; chroma_width * chroma_height];
+
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        let [r, g, b, _] = pixel.0.map(f32::from);
+        let (luma, u, v) = rgb_to_yuv(r, g, b);
+        let (x, y) = (x as usize, y as usize);
+        y_plane[y * width + x] = luma.round().clamp(0.0, 255.0) as u8;
+        let i = (y / 2) * chroma_width + x / 2;
+        u_sum[i] += u;
+        v_sum[i] += v;
+        counts[i] += 1.0;
+    }
+
+    let average = |sums: Vec<f32>| {
+        sums.iter()
+            .zip(&counts)
+            .map(|(sum, count)| (sum / count).round().clamp(0.0, 255.0) as u8)
+            .collect()
+    };
+    [y_plane, average(u_sum), average(v_sum)]
+}
+
+/// rav1e's smallest frame on either side.
+const MIN_CODED_SIZE: u32 = 16;
+
+/// `frame` grown to `width` x `height` by repeating its last column and
+/// row, which keeps the padding cheap to code.
+fn pad_edges(frame: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        *frame.get_pixel(x.min(frame.width() - 1), y.min(frame.height() - 1))
+    })
+}
+
+/// Feeds frames to rav1e one at a time and keeps only the packets.
+pub struct Av1Encoder {
+    ctx: Context<u8>,
+    alpha: bool,
+    track: Av1Track,
+}
+
+impl Av1Encoder {
+    /// Colour as 8-bit 4:2:0, which every AV1 decoder handles; `alpha`
+    /// encodes just the alpha channel as monochrome instead. Frames smaller
+    /// than rav1e allows are padded, see [`Av1Track::width`].
+    pub fn new(width: u32, height: u32, quality: f32, speed: u8, alpha: bool) -> Result<Self> {
+        let (width, height) = (width.max(MIN_CODED_SIZE), height.max(MIN_CODED_SIZE));
+        let config = EncoderConfig {
+            width: width as usize,
+            height: height as usize,
+            time_base: Rational::new(1, 1000),
+            bit_depth: 8,
+            chroma_sampling: if alpha {
+                ChromaSampling::Cs400
+            } else {
+                ChromaSampling::Cs420
+            },
+            chroma_sample_position: ChromaSamplePosition::Unknown,
+            pixel_range: PixelRange::Full,
+            color_description: (!alpha).then_some(ColorDescription {
+                color_primaries: ColorPrimaries::BT709,
+                transfer_characteristics: TransferCharacteristics::SRGB,
+                matrix_coefficients: MatrixCoefficients::BT601,
+            }),
+            quantizer: av1_quantizer(quality),
+            // packets come out in input order, one per frame
+            low_latency: true,
+            speed_settings: SpeedSettings::from_preset(speed),
+            ..EncoderConfig::default()
+        };
+
+        let ctx = Config::new()
+            .with_encoder_config(config)
+            .with_threads(1)
+            .new_context()
+            .map_err(encode_error)?;
+        let track = Av1Track {
+            width,
+            height,
+            config: ctx.container_sequence_header(),
+            samples: vec![],
+            sync: vec![],
+        };
+
+        Ok(Self { ctx, alpha, track })
+    }
+
+    pub fn add_frame(&mut self, frame: &RgbaImage) -> Result<()> {
+        let padded;
+        let frame = if frame.dimensions() == (self.track.width, self.track.height) {
+            frame
+        } else {
+            padded = pad_edges(frame, self.track.width, self.track.height);
+            &padded
+        };
+        let width = frame.width() as usize;
+        let mut input = self.ctx.new_frame();
+        if self.alpha {
+            let alpha = frame.pixels().map(|p| p.0[3]).collect::<Vec<_>>();
+            input.planes[0].copy_from_raw_u8(&alpha, width, 1);
+        } else {
+            let planes = yuv420_planes(frame);
+            input.planes[0].copy_from_raw_u8(&planes[0], width, 1);
+            input.planes[1].copy_from_raw_u8(&planes[1], width.div_ceil(2), 1);
+            input.planes[2].copy_from_raw_u8(&planes[2], width.div_ceil(2), 1);
+        }
+
+        self.ctx.send_frame(input).map_err(encode_error)?;
+        self.receive_packets()
+    }
+
+    fn receive_packets(&mut self) -> Result<()> {
+        loop {
+            match self.ctx.receive_packet() {
+                Ok(packet) => {
+                    if packet.frame_type == FrameType::KEY {
+                        self.track.sync.push(self.track.samples.len() as u32);
+                    }
+                    self.track.samples.push(packet.data);
+                }
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::NeedMoreData | EncoderStatus::LimitReached) => {
+                    return Ok(());
+                }
+                Err(e) => return Err(encode_error(e)),
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Result<Av1Track> {
+        self.ctx.flush();
+        self.receive_packets()?;
+
+        Ok(self.track)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use decoder::Av1Decoder;
+
+/// Stands in for rav1d on wasm32, failing as AVIF input would without it.
+#[cfg(target_arch = "wasm32")]
+pub enum Av1Decoder {}
+
+#[cfg(target_arch = "wasm32")]
+impl Av1Decoder {
+    pub fn new(_max_pixels: Option<u64>) -> Result<Self> {
+        Err(TransformError::UnsupportedFormat(
+            "AVIF input needs a native build".into(),
+        ))
+    }
+
+    pub fn decode_rgb(&mut self, _sample: &[u8], _out: &mut RgbaImage) -> Result<()> {
+        match *self {}
+    }
+
+    pub fn decode_alpha(&mut self, _sample: &[u8], _out: &mut RgbaImage) -> Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod decoder {
+    use crate::error::{Result, TransformError};
+    use crate::format::ImageFormat;
+    use image::RgbaImage;
+    use rav1d::include::dav1d::data::Dav1dData;
+    use rav1d::include::dav1d::dav1d::{Dav1dContext, Dav1dSettings};
+    use rav1d::include::dav1d::headers::{
+        DAV1D_MC_BT601, DAV1D_MC_BT709, DAV1D_MC_BT2020_CL, DAV1D_MC_BT2020_NCL, DAV1D_MC_IDENTITY,
+        DAV1D_MC_SMPTE240, DAV1D_PIXEL_LAYOUT_I400, DAV1D_PIXEL_LAYOUT_I420,
+        DAV1D_PIXEL_LAYOUT_I422,
+    };
+    use rav1d::include::dav1d::picture::Dav1dPicture;
+    use rav1d::src::lib::{
+        dav1d_close, dav1d_data_create, dav1d_data_unref, dav1d_default_settings,
+        dav1d_get_picture, dav1d_open, dav1d_picture_unref, dav1d_send_data,
+    };
+    use std::mem::MaybeUninit;
+    use std::ptr::NonNull;
+
+    fn decode_error(message: impl ToString) -> TransformError {
+        TransformError::decode(ImageFormat::Avif, message)
+    }
+
+    /// One plane of a picture, as 8-bit samples.
+    struct Plane<'a> {
+        data: &'a [u8],
+        stride: usize,
+        high_bit_depth: bool,
+        shift: u32,
+    }
+
+    impl Plane<'_> {
+        fn get(&self, x: usize, y: usize) -> u8 {
+            let row = y * self.stride;
+            if self.high_bit_depth {
+                let i = row + x * 2;
+                let v = u16::from_ne_bytes([self.data[i], self.data[i + 1]]);
+                (v >> self.shift).min(255) as u8
+            } else {
+                self.data[row + x]
+            }
+        }
+    }
+
+    /// A decoded picture, released on drop.
+    struct Picture(Dav1dPicture);
+
+    impl Drop for Picture {
+        fn drop(&mut self) {
+            unsafe { dav1d_picture_unref(NonNull::new(&mut self.0)) };
+        }
+    }
+
+    impl Picture {
+        fn size(&self) -> (u32, u32) {
+            (self.0.p.w as u32, self.0.p.h as u32)
+        }
+
+        fn plane(&self, index: usize, height: usize) -> Option<Plane<'_>> {
+            let ptr = self.0.data[index]?;
+            let stride = usize::try_from(self.0.stride[index.min(1)]).ok()?;
+            // SAFETY: dav1d allocates `stride` bytes for each of the
+            // plane's rows and keeps them alive until the picture is unref'd
+            let data =
+                unsafe { std::slice::from_raw_parts(ptr.as_ptr() as *const u8, stride * height) };
+            Some(Plane {
+                data,
+                stride,
+                high_bit_depth: self.0.p.bpc > 8,
+                shift: (self.0.p.bpc - 8).max(0) as u32,
+            })
+        }
+
+        fn matrix(&self) -> (u32, bool) {
+            self.0
+                .seq_hdr
+                // SAFETY: the sequence header lives as long as the picture
+                .map(|hdr| unsafe { (hdr.as_ref().mtrx, hdr.as_ref().color_range != 0) })
+                .unwrap_or((DAV1D_MC_BT601, true))
+        }
+
+        /// Writes the colour planes into `out`'s RGB channels.
+        fn write_rgb(&self, out: &mut RgbaImage) -> Result<()> {
+            let (width, height) = out.dimensions();
+            let (width, height) = (width as usize, height as usize);
+            let layout = self.0.p.layout;
+            let (ss_x, ss_y) = match layout {
+                DAV1D_PIXEL_LAYOUT_I420 => (1, 1),
+                DAV1D_PIXEL_LAYOUT_I422 => (1, 0),
+                _ => (0, 0),
+            };
+            let luma = self
+                .plane(0, height)
+                .ok_or_else(|| decode_error("no luma"))?;
+            let chroma = if layout == DAV1D_PIXEL_LAYOUT_I400 {
+                None
+            } else {
+                let chroma_height = (height + ss_y) >> ss_y;
+                Some((
+                    self.plane(1, chroma_height)
+                        .ok_or_else(|| decode_error("no chroma"))?,
+                    self.plane(2, chroma_height)
+                        .ok_or_else(|| decode_error("no chroma"))?,
+                ))
+            };
+
+            let (mtrx, full_range) = self.matrix();
+            let (kr, kb) = match mtrx {
+                DAV1D_MC_BT709 => (0.2126, 0.0722),
+                DAV1D_MC_SMPTE240 => (0.212, 0.087),
+                DAV1D_MC_BT2020_NCL | DAV1D_MC_BT2020_CL => (0.2627, 0.0593),
+                _ => (0.299, 0.114),
+            };
+            let identity = mtrx == DAV1D_MC_IDENTITY;
+            let (y_offset, y_scale, c_scale) = if full_range {
+                (0.0, 1.0, 1.0)
+            } else {
+                (16.0, 255.0 / 219.0, 255.0 / 224.0)
+            };
+
+            for y in 0..height {
+                for x in 0..width {
+                    let luma = (f32::from(luma.get(x, y)) - y_offset) * y_scale;
+                    let (u, v) = match &chroma {
+                        Some((u, v)) => (
+                            (f32::from(u.get(x >> ss_x, y >> ss_y)) - 128.0) * c_scale,
+                            (f32::from(v.get(x >> ss_x, y >> ss_y)) - 128.0) * c_scale,
+                        ),
+                        None => (0.0, 0.0),
+                    };
+                    let [r, g, b] = if identity {
+                        // GBR stored in the Y, U and V planes
+                        [v + 128.0, luma, u + 128.0]
+                    } else {
+                        let r = luma + 2.0 * (1.0 - kr) * v;
+                        let b = luma + 2.0 * (1.0 - kb) * u;
+                        [r, (luma - kr * r - kb * b) / (1.0 - kr - kb), b]
+                    };
+                    let pixel = out.get_pixel_mut(x as u32, y as u32);
+                    pixel.0[..3]
+                        .copy_from_slice(&[r, g, b].map(|c| c.round().clamp(0.0, 255.0) as u8));
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Writes the luma plane into `out`'s alpha channel.
+        fn write_alpha(&self, out: &mut RgbaImage) -> Result<()> {
+            let plane = self
+                .plane(0, out.height() as usize)
+                .ok_or_else(|| decode_error("no alpha"))?;
+            let (_, full_range) = self.matrix();
+
+            for (x, y, pixel) in out.enumerate_pixels_mut() {
+                let a = plane.get(x as usize, y as usize);
+                pixel.0[3] = if full_range {
+                    a
+                } else {
+                    ((f32::from(a) - 16.0) * 255.0 / 219.0)
+                        .round()
+                        .clamp(0.0, 255.0) as u8
+                };
+            }
+
+            Ok(())
+        }
+    }
+
+    /// A single-threaded rav1d instance that hands back one picture per
+    /// temporal unit.
+    pub struct Av1Decoder {
+        ctx: Dav1dContext,
+    }
+
+    impl Drop for Av1Decoder {
+        fn drop(&mut self) {
+            let mut ctx = Some(self.ctx);
+            unsafe { dav1d_close(NonNull::new(&mut ctx)) };
+        }
+    }
+
+    impl Av1Decoder {
+        /// `max_pixels` caps each frame before rav1d allocates it.
+        pub fn new(max_pixels: Option<u64>) -> Result<Self> {
+            let mut settings = MaybeUninit::<Dav1dSettings>::uninit();
+            // SAFETY: `dav1d_default_settings` initialises every field
+            let mut settings = unsafe {
+                dav1d_default_settings(NonNull::new(settings.as_mut_ptr()).unwrap());
+                settings.assume_init()
+            };
+            settings.n_threads = 1;
+            settings.max_frame_delay = 1;
+            settings.frame_size_limit = max_pixels
+                .map(|pixels| u32::try_from(pixels).unwrap_or(u32::MAX))
+                .unwrap_or(0);
+
+            let mut ctx = None;
+            let res = unsafe { dav1d_open(NonNull::new(&mut ctx), NonNull::new(&mut settings)) };
+            match ctx {
+                Some(ctx) if res.0 == 0 => Ok(Self { ctx }),
+                _ => Err(decode_error(format!("dav1d_open failed: {}", res.0))),
+            }
+        }
+
+        fn picture(&mut self, sample: &[u8]) -> Result<Picture> {
+            let mut data = Dav1dData::default();
+            let buf = unsafe { dav1d_data_create(NonNull::new(&mut data), sample.len()) };
+            if buf.is_null() {
+                return Err(decode_error("out of memory"));
+            }
+            // SAFETY: `dav1d_data_create` allocated `sample.len()` bytes
+            unsafe { std::ptr::copy_nonoverlapping(sample.as_ptr(), buf, sample.len()) };
+
+            let result = loop {
+                if data.sz > 0 {
+                    let res = unsafe { dav1d_send_data(Some(self.ctx), NonNull::new(&mut data)) };
+                    if res.0 != 0 && res.0 != -libc::EAGAIN {
+                        break Err(decode_error(format!("dav1d_send_data failed: {}", res.0)));
+                    }
+                }
+                let mut picture = Picture(Dav1dPicture::default());
+                let res =
+                    unsafe { dav1d_get_picture(Some(self.ctx), NonNull::new(&mut picture.0)) };
+                match res.0 {
+                    0 => break Ok(picture),
+                    e if e == -libc::EAGAIN && data.sz > 0 => {}
+                    e if e == -libc::EAGAIN => break Err(decode_error("no picture in sample")),
+                    e => break Err(decode_error(format!("dav1d_get_picture failed: {e}"))),
+                }
+            };
+            if data.sz > 0 {
+                unsafe { dav1d_data_unref(NonNull::new(&mut data)) };
+            }
+
+            result
+        }
+
+        /// Decodes the next sample into the RGB channels of `out`, which it
+        /// has to match in size.
+        pub fn decode_rgb(&mut self, sample: &[u8], out: &mut RgbaImage) -> Result<()> {
+            let picture = self.picture(sample)?;
+            if picture.size() != out.dimensions() {
+                return Err(decode_error("frame size differs from the container"));
+            }
+            picture.write_rgb(out)
+        }
+
+        pub fn decode_alpha(&mut self, sample: &[u8], out: &mut RgbaImage) -> Result<()> {
+            let picture = self.picture(sample)?;
+            if picture.size() != out.dimensions() {
+                return Err(decode_error("alpha size differs from the image"));
+            }
+            picture.write_alpha(out)
+        }
+    }
+}
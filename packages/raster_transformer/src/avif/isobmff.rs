@@ -0,0 +1,1154 @@
+//! The ISOBMFF boxes AVIF needs: the `meta` item tree of a still and the
+//! `moov` tracks of a sequence, read and written.
+
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::limits::DecodeLimits;
+
+/// `auxC`/`auxi` type of an alpha plane.
+pub const ALPHA_URN: &str = "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha";
+/// The HEVC-era alpha URN, still written by some encoders.
+const ALPHA_URN_HEVC: &str = "urn:mpeg:hevc:2015:auxid:1";
+
+/// Milliseconds, like every other duration in the shared model.
+pub const TIMESCALE: u32 = 1000;
+
+fn malformed(message: &str) -> TransformError {
+    TransformError::decode(ImageFormat::Avif, message)
+}
+
+/// Big-endian cursor over a box body.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(malformed("truncated box"));
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// An unsigned field of 0, 4 or 8 bytes, as `iloc` sizes them.
+    fn uint(&mut self, size: u8) -> Result<u64> {
+        match size {
+            0 => Ok(0),
+            4 => self.u32().map(u64::from),
+            8 => self.u64(),
+            _ => Err(malformed("unsupported iloc field size")),
+        }
+    }
+
+    /// A `u16` id in version 0 boxes and a `u32` one after.
+    fn id(&mut self, wide: bool) -> Result<u32> {
+        if wide {
+            self.u32()
+        } else {
+            self.u16().map(u32::from)
+        }
+    }
+
+    /// Version and flags of a full box.
+    fn full_box(&mut self) -> Result<(u8, u32)> {
+        let word = self.u32()?;
+        Ok(((word >> 24) as u8, word & 0xff_ffff))
+    }
+
+    fn c_string(&mut self) -> Result<&'a str> {
+        let len = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| malformed("unterminated string"))?;
+        let text = self.bytes(len)?;
+        self.skip(1)?;
+        std::str::from_utf8(text).map_err(|_| malformed("invalid string"))
+    }
+
+    /// A table's entry count, rejected when `entry_size`-byte entries would
+    /// run past the box, so it can't size an allocation on its own.
+    fn count(&mut self, entry_size: usize) -> Result<u32> {
+        let count = self.u32()?;
+        if (count as usize).saturating_mul(entry_size) > self.data.len() {
+            return Err(malformed("table runs past its box"));
+        }
+        Ok(count)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+}
+
+/// Child boxes of `data` as `(type, body)`.
+fn boxes(data: &[u8]) -> impl Iterator<Item = Result<([u8; 4], &[u8])>> {
+    let mut reader = Reader::new(data);
+    std::iter::from_fn(move || {
+        if reader.data.is_empty() {
+            return None;
+        }
+        let mut next = || {
+            let available = reader.data.len() as u64;
+            let size = reader.u32()?;
+            let kind: [u8; 4] = reader.bytes(4)?.try_into().unwrap();
+            let body_len = match size {
+                0 => available - 8,
+                1 => reader
+                    .u64()?
+                    .checked_sub(16)
+                    .ok_or_else(|| malformed("box too small"))?,
+                _ => u64::from(size)
+                    .checked_sub(8)
+                    .ok_or_else(|| malformed("box too small"))?,
+            };
+            let body_len = usize::try_from(body_len).map_err(|_| malformed("box too large"))?;
+            Ok((kind, reader.bytes(body_len)?))
+        };
+        let item = next();
+        if item.is_err() {
+            reader.data = &[];
+        }
+        Some(item)
+    })
+}
+
+fn find<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    for child in boxes(data) {
+        let (child_kind, body) = child?;
+        if &child_kind == kind {
+            return Ok(Some(body));
+        }
+    }
+    Ok(None)
+}
+
+fn require<'a>(data: &'a [u8], kind: &[u8; 4]) -> Result<&'a [u8]> {
+    find(data, kind)?.ok_or_else(|| {
+        malformed(&format!(
+            "missing {} box",
+            String::from_utf8_lossy(kind.as_slice())
+        ))
+    })
+}
+
+/// The ICC profile of a `colr` box, if it carries one rather than `nclx`.
+fn colr_icc(body: &[u8]) -> Option<Vec<u8>> {
+    match body.get(..4)? {
+        b"prof" | b"rICC" => Some(body[4..].to_vec()),
+        _ => None,
+    }
+}
+
+fn is_alpha_urn(urn: &str) -> bool {
+    urn == ALPHA_URN || urn == ALPHA_URN_HEVC
+}
+
+/// The part of the coded picture a `clap` box keeps, in whole pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CleanAperture {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CleanAperture {
+    /// Reads a `clap` body against the coded size. Its offsets are from the
+    /// centre of the picture; only crops landing on whole pixels inside it
+    /// are accepted.
+    fn read(body: &[u8], coded_width: u32, coded_height: u32) -> Result<Self> {
+        let mut r = Reader::new(body);
+        let mut fields = [0i64; 8];
+        for (i, field) in fields.iter_mut().enumerate() {
+            // the offsets' numerators are signed
+            *field = match i {
+                4 | 6 => i64::from(r.i32()?),
+                _ => i64::from(r.u32()?),
+            };
+        }
+        let [width_n, width_d, height_n, height_d, x_n, x_d, y_n, y_d] = fields;
+
+        let exact = |n: i64, d: i64| (d > 0 && n % d == 0).then(|| n / d);
+        let edge = |offset_n: i64, offset_d: i64, size: i64, coded: u32| {
+            exact(
+                2 * offset_n + (i64::from(coded) - size) * offset_d,
+                2 * offset_d,
+            )
+        };
+        let width = exact(width_n, width_d);
+        let height = exact(height_n, height_d);
+        let x = width.and_then(|width| edge(x_n, x_d, width, coded_width));
+        let y = height.and_then(|height| edge(y_n, y_d, height, coded_height));
+
+        match (x, y, width, height) {
+            (Some(x), Some(y), Some(width), Some(height))
+                if x >= 0
+                    && y >= 0
+                    && width > 0
+                    && height > 0
+                    && x + width <= i64::from(coded_width)
+                    && y + height <= i64::from(coded_height) =>
+            {
+                Ok(Self {
+                    x: x as u32,
+                    y: y as u32,
+                    width: width as u32,
+                    height: height as u32,
+                })
+            }
+            _ => Err(TransformError::UnsupportedFormat(
+                "AVIF clean aperture off the pixel grid".into(),
+            )),
+        }
+    }
+}
+
+/// The primary image item, plus its alpha auxiliary when there is one.
+pub struct StillItems {
+    /// Coded size, from `ispe`.
+    pub width: u32,
+    pub height: u32,
+    pub crop: Option<CleanAperture>,
+    pub color: Vec<u8>,
+    pub alpha: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Item {
+    kind: [u8; 4],
+    /// `(construction method, offset, length)` of each extent.
+    extents: Vec<(u8, u64, u64)>,
+    properties: Vec<u16>,
+}
+
+enum Property<'a> {
+    Size(u32, u32),
+    /// A `clap` body, read once the coded size is known.
+    Crop(&'a [u8]),
+    Icc(Vec<u8>),
+    Auxiliary(&'a str),
+    Other,
+}
+
+/// Reads the `meta` box: `pitm`, `iinf`, `iloc`, `iref` and the `iprp`
+/// properties the decoder needs.
+pub fn read_still(file: &[u8]) -> Result<StillItems> {
+    let mut meta = Reader::new(require(file, b"meta")?);
+    meta.full_box()?;
+    let meta = meta.rest();
+
+    let mut primary = None;
+    let mut items: std::collections::BTreeMap<u32, Item> = Default::default();
+    let mut aux_links = vec![];
+    let mut properties = vec![];
+    let mut idat: &[u8] = &[];
+
+    for child in boxes(meta) {
+        let (kind, body) = child?;
+        let mut r = Reader::new(body);
+        match &kind {
+            b"pitm" => {
+                let (version, _) = r.full_box()?;
+                primary = Some(r.id(version > 0)?);
+            }
+            b"iinf" => {
+                let (version, _) = r.full_box()?;
+                r.id(version > 0)?;
+                for infe in boxes(r.rest()) {
+                    let (infe_kind, infe) = infe?;
+                    if &infe_kind != b"infe" {
+                        continue;
+                    }
+                    let mut infe = Reader::new(infe);
+                    let (version, _) = infe.full_box()?;
+                    if version < 2 {
+                        continue;
+                    }
+                    let id = infe.id(version > 2)?;
+                    infe.u16()?;
+                    items.entry(id).or_default().kind = infe.bytes(4)?.try_into().unwrap();
+                }
+            }
+            b"iloc" => {
+                let (version, _) = r.full_box()?;
+                let sizes = r.u8()?;
+                let (offset_size, length_size) = (sizes >> 4, sizes & 0xf);
+                let sizes = r.u8()?;
+                let base_offset_size = sizes >> 4;
+                let index_size = if version > 0 { sizes & 0xf } else { 0 };
+                let count = r.id(version > 1)?;
+                for _ in 0..count {
+                    let id = r.id(version > 1)?;
+                    let method = if version > 0 {
+                        (r.u16()? & 0xf) as u8
+                    } else {
+                        0
+                    };
+                    r.u16()?;
+                    let base = r.uint(base_offset_size)?;
+                    let extent_count = r.u16()?;
+                    let item = items.entry(id).or_default();
+                    for _ in 0..extent_count {
+                        r.uint(index_size)?;
+                        let offset = base
+                            .checked_add(r.uint(offset_size)?)
+                            .ok_or_else(|| malformed("iloc offset overflow"))?;
+                        let length = r.uint(length_size)?;
+                        item.extents.push((method, offset, length));
+                    }
+                }
+            }
+            b"iref" => {
+                let (version, _) = r.full_box()?;
+                for reference in boxes(r.rest()) {
+                    let (ref_kind, reference) = reference?;
+                    let mut reference = Reader::new(reference);
+                    let from = reference.id(version > 0)?;
+                    let count = reference.u16()?;
+                    for _ in 0..count {
+                        let to = reference.id(version > 0)?;
+                        if &ref_kind == b"auxl" {
+                            aux_links.push((from, to));
+                        }
+                    }
+                }
+            }
+            b"iprp" => {
+                for ipco in boxes(require(body, b"ipco")?) {
+                    let (prop_kind, prop) = ipco?;
+                    let mut prop = Reader::new(prop);
+                    properties.push(match &prop_kind {
+                        b"ispe" => {
+                            prop.full_box()?;
+                            Property::Size(prop.u32()?, prop.u32()?)
+                        }
+                        b"clap" => Property::Crop(prop.rest()),
+                        b"colr" => colr_icc(prop.rest()).map_or(Property::Other, Property::Icc),
+                        b"auxC" => {
+                            prop.full_box()?;
+                            Property::Auxiliary(prop.c_string()?)
+                        }
+                        _ => Property::Other,
+                    });
+                }
+                for ipma in boxes(body) {
+                    let (ipma_kind, ipma) = ipma?;
+                    if &ipma_kind != b"ipma" {
+                        continue;
+                    }
+                    let mut ipma = Reader::new(ipma);
+                    let (version, flags) = ipma.full_box()?;
+                    for _ in 0..ipma.u32()? {
+                        let id = ipma.id(version > 0)?;
+                        let item = items.entry(id).or_default();
+                        for _ in 0..ipma.u8()? {
+                            let index = if flags & 1 != 0 {
+                                ipma.u16()? & 0x7fff
+                            } else {
+                                u16::from(ipma.u8()? & 0x7f)
+                            };
+                            item.properties.push(index);
+                        }
+                    }
+                }
+            }
+            b"idat" => idat = body,
+            _ => {}
+        }
+    }
+
+    let primary = primary.ok_or_else(|| malformed("missing primary item"))?;
+    let property = |item: &Item, pick: &dyn Fn(&Property) -> bool| {
+        item.properties
+            .iter()
+            .filter_map(|&index| properties.get(usize::from(index).checked_sub(1)?))
+            .find(|p| pick(p))
+    };
+    let item_data = |item: &Item| -> Result<Vec<u8>> {
+        let mut data = vec![];
+        for &(method, offset, length) in &item.extents {
+            let source = match method {
+                0 => file,
+                1 => idat,
+                _ => return Err(malformed("unsupported iloc construction method")),
+            };
+            let start = usize::try_from(offset).map_err(|_| malformed("item out of bounds"))?;
+            let extent = if length == 0 {
+                source.get(start..)
+            } else {
+                usize::try_from(length)
+                    .ok()
+                    .and_then(|length| source.get(start..start.checked_add(length)?))
+            };
+            data.extend_from_slice(extent.ok_or_else(|| malformed("item out of bounds"))?);
+        }
+        Ok(data)
+    };
+
+    let color = items
+        .get(&primary)
+        .ok_or_else(|| malformed("missing primary item"))?;
+    if &color.kind != b"av01" {
+        return Err(TransformError::UnsupportedFormat(format!(
+            "AVIF primary item of type {}",
+            String::from_utf8_lossy(&color.kind)
+        )));
+    }
+    let Some(Property::Size(width, height)) = property(color, &|p| matches!(p, Property::Size(..)))
+    else {
+        return Err(malformed("missing ispe property"));
+    };
+    let crop = match property(color, &|p| matches!(p, Property::Crop(_))) {
+        Some(Property::Crop(clap)) => Some(CleanAperture::read(clap, *width, *height)?),
+        _ => None,
+    };
+    let icc = match property(color, &|p| matches!(p, Property::Icc(_))) {
+        Some(Property::Icc(icc)) => Some(icc.clone()),
+        _ => None,
+    };
+
+    let alpha = aux_links
+        .iter()
+        .filter(|&&(_, to)| to == primary)
+        .filter_map(|(from, _)| items.get(from))
+        .find(|item| {
+            property(
+                item,
+                &|p| matches!(p, Property::Auxiliary(urn) if is_alpha_urn(urn)),
+            )
+            .is_some()
+        })
+        .map(item_data)
+        .transpose()?;
+
+    Ok(StillItems {
+        width: *width,
+        height: *height,
+        crop,
+        color: item_data(color)?,
+        alpha,
+        icc,
+    })
+}
+
+/// The colour track of a sequence, with its alpha track's samples when it
+/// has one. Samples are in decode order, which AV1 also shows them in.
+pub struct SequenceTracks<'a> {
+    /// Coded size, from the sample entry.
+    pub width: u32,
+    pub height: u32,
+    pub crop: Option<CleanAperture>,
+    pub samples: Vec<&'a [u8]>,
+    pub durations: Vec<u32>,
+    pub alpha: Option<Vec<&'a [u8]>>,
+    /// Plays, `0` for infinite or unknown.
+    pub loop_count: u32,
+    pub icc: Option<Vec<u8>>,
+}
+
+struct Track<'a> {
+    id: u32,
+    handler: [u8; 4],
+    width: u32,
+    height: u32,
+    crop: Option<CleanAperture>,
+    /// `None` when the duration is indefinite.
+    duration: Option<u64>,
+    /// Segment duration of a repeating edit list.
+    repeat: Option<u64>,
+    aux_for: Vec<u32>,
+    codec: [u8; 4],
+    auxiliary: Option<&'a str>,
+    icc: Option<Vec<u8>>,
+    samples: Vec<&'a [u8]>,
+    durations: Vec<u32>,
+}
+
+fn read_track<'a>(file: &'a [u8], trak: &'a [u8], limits: &DecodeLimits) -> Result<Track<'a>> {
+    let mut tkhd = Reader::new(require(trak, b"tkhd")?);
+    let (version, _) = tkhd.full_box()?;
+    let (id, duration) = if version == 1 {
+        tkhd.skip(16)?;
+        let id = tkhd.u32()?;
+        tkhd.skip(4)?;
+        let duration = tkhd.u64()?;
+        (id, (duration != u64::MAX).then_some(duration))
+    } else {
+        tkhd.skip(8)?;
+        let id = tkhd.u32()?;
+        tkhd.skip(4)?;
+        let duration = tkhd.u32()?;
+        (id, (duration != u32::MAX).then_some(u64::from(duration)))
+    };
+
+    let mut aux_for = vec![];
+    if let Some(tref) = find(trak, b"tref")? {
+        for reference in boxes(tref) {
+            let (kind, reference) = reference?;
+            let mut reference = Reader::new(reference);
+            while kind == *b"auxl" && !reference.data.is_empty() {
+                aux_for.push(reference.u32()?);
+            }
+        }
+    }
+
+    let mut repeat = None;
+    if let Some(elst) = find(trak, b"edts")?
+        .map(|edts| find(edts, b"elst"))
+        .transpose()?
+        .flatten()
+    {
+        let mut elst = Reader::new(elst);
+        let (version, flags) = elst.full_box()?;
+        if flags & 1 != 0 && elst.u32()? > 0 {
+            repeat = Some(if version == 1 {
+                elst.u64()?
+            } else {
+                u64::from(elst.u32()?)
+            });
+        }
+    }
+
+    let mdia = require(trak, b"mdia")?;
+    let mut mdhd = Reader::new(require(mdia, b"mdhd")?);
+    let (version, _) = mdhd.full_box()?;
+    mdhd.skip(if version == 1 { 16 } else { 8 })?;
+    let timescale = mdhd.u32()?;
+    if timescale == 0 {
+        return Err(malformed("zero timescale"));
+    }
+
+    let mut hdlr = Reader::new(require(mdia, b"hdlr")?);
+    hdlr.full_box()?;
+    hdlr.skip(4)?;
+    let handler = hdlr.bytes(4)?.try_into().unwrap();
+
+    let stbl = require(require(mdia, b"minf")?, b"stbl")?;
+
+    let mut stsd = Reader::new(require(stbl, b"stsd")?);
+    stsd.full_box()?;
+    stsd.u32()?;
+    let (codec, entry) = boxes(stsd.rest())
+        .next()
+        .ok_or_else(|| malformed("empty stsd box"))??;
+    let mut sizes = Reader::new(entry);
+    sizes.skip(24)?;
+    let width = u32::from(sizes.u16()?);
+    let height = u32::from(sizes.u16()?);
+    let mut auxiliary = None;
+    let mut icc = None;
+    let mut crop = None;
+    if let Some(children) = entry.get(78..) {
+        for child in boxes(children) {
+            let (kind, body) = child?;
+            let mut body = Reader::new(body);
+            match &kind {
+                b"auxi" => {
+                    body.full_box()?;
+                    auxiliary = Some(body.c_string()?);
+                }
+                b"clap" => crop = Some(CleanAperture::read(body.rest(), width, height)?),
+                b"colr" => icc = colr_icc(body.rest()),
+                _ => {}
+            }
+        }
+    }
+
+    let mut stsz = Reader::new(require(stbl, b"stsz")?);
+    stsz.full_box()?;
+    let uniform_size = stsz.u32()?;
+    let sample_count = match uniform_size {
+        0 => stsz.count(4)?,
+        size => {
+            let count = stsz.u32()?;
+            if u64::from(count) * u64::from(size) > file.len() as u64 {
+                return Err(malformed("samples run past the file"));
+            }
+            count
+        }
+    };
+    limits.check_frames(width, height, sample_count)?;
+    let sizes = (0..sample_count)
+        .map(|_| match uniform_size {
+            0 => stsz.u32(),
+            size => Ok(size),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let chunk_offsets = if let Some(stco) = find(stbl, b"stco")? {
+        let mut stco = Reader::new(stco);
+        stco.full_box()?;
+        (0..stco.count(4)?)
+            .map(|_| stco.u32().map(u64::from))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        let mut co64 = Reader::new(require(stbl, b"co64")?);
+        co64.full_box()?;
+        (0..co64.count(8)?)
+            .map(|_| co64.u64())
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let mut stsc = Reader::new(require(stbl, b"stsc")?);
+    stsc.full_box()?;
+    let runs = (0..stsc.count(12)?)
+        .map(|_| {
+            let first_chunk = stsc.u32()?;
+            let per_chunk = stsc.u32()?;
+            stsc.u32()?;
+            Ok((first_chunk, per_chunk))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut samples = Vec::with_capacity(sizes.len().min(file.len()));
+    let mut sizes_iter = sizes.iter();
+    'chunks: for (i, &offset) in chunk_offsets.iter().enumerate() {
+        let chunk = i as u64 + 1;
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|&&(first, _)| u64::from(first) <= chunk)
+            .map_or(0, |&(_, per_chunk)| per_chunk);
+        let mut pos = usize::try_from(offset).map_err(|_| malformed("chunk out of bounds"))?;
+        for _ in 0..per_chunk {
+            let Some(&size) = sizes_iter.next() else {
+                break 'chunks;
+            };
+            let end = pos
+                .checked_add(size as usize)
+                .ok_or_else(|| malformed("sample out of bounds"))?;
+            samples.push(
+                file.get(pos..end)
+                    .ok_or_else(|| malformed("sample out of bounds"))?,
+            );
+            pos = end;
+        }
+    }
+    if samples.len() != sizes.len() {
+        return Err(malformed("sample table does not cover every sample"));
+    }
+
+    let mut stts = Reader::new(require(stbl, b"stts")?);
+    stts.full_box()?;
+    let mut durations = Vec::with_capacity(samples.len());
+    for _ in 0..stts.count(8)? {
+        let count = stts.u32()?;
+        let delta = stts.u32()?;
+        let ms = (u64::from(delta) * u64::from(TIMESCALE) + u64::from(timescale) / 2)
+            / u64::from(timescale);
+        let count = (count as usize).min(samples.len() - durations.len());
+        durations.extend(std::iter::repeat_n(
+            u32::try_from(ms).unwrap_or(u32::MAX),
+            count,
+        ));
+    }
+    durations.resize(samples.len(), 0);
+
+    Ok(Track {
+        id,
+        handler,
+        width,
+        height,
+        crop,
+        duration,
+        repeat,
+        aux_for,
+        codec,
+        auxiliary,
+        icc,
+        samples,
+        durations,
+    })
+}
+
+/// Reads the `moov` box, or `None` when there is none and the file is a
+/// still.
+pub fn read_sequence<'a>(
+    file: &'a [u8],
+    limits: &DecodeLimits,
+) -> Result<Option<SequenceTracks<'a>>> {
+    let Some(moov) = find(file, b"moov")? else {
+        return Ok(None);
+    };
+
+    let mut tracks = vec![];
+    for child in boxes(moov) {
+        let (kind, body) = child?;
+        if &kind == b"trak" {
+            tracks.push(read_track(file, body, limits)?);
+        }
+    }
+
+    let Some(color) = tracks.iter().position(|t| &t.handler == b"pict") else {
+        return Ok(None);
+    };
+    let alpha = tracks.iter().position(|t| {
+        t.aux_for.contains(&tracks[color].id) && t.auxiliary.is_some_and(is_alpha_urn)
+    });
+    let color_track = &tracks[color];
+    if &color_track.codec != b"av01" {
+        return Err(TransformError::UnsupportedFormat(format!(
+            "AVIF sequence of {}",
+            String::from_utf8_lossy(&color_track.codec)
+        )));
+    }
+
+    // a repeating edit list plays for the track duration, indefinitely if
+    // that is unset; without one the count is unknown and browsers loop
+    let loop_count = match (color_track.repeat, color_track.duration) {
+        (Some(segment), Some(duration)) if segment > 0 => {
+            u32::try_from(duration.div_ceil(segment)).unwrap_or(0)
+        }
+        _ => 0,
+    };
+
+    let mut tracks = tracks.into_iter().map(Some).collect::<Vec<_>>();
+    let color_track = tracks[color].take().unwrap();
+    let alpha_track = alpha.and_then(|i| tracks[i].take());
+    let alpha = alpha_track
+        .filter(|t| t.samples.len() == color_track.samples.len())
+        .map(|t| t.samples);
+
+    Ok(Some(SequenceTracks {
+        width: color_track.width,
+        height: color_track.height,
+        crop: color_track.crop,
+        samples: color_track.samples,
+        durations: color_track.durations,
+        alpha,
+        loop_count,
+        icc: color_track.icc,
+    }))
+}
+
+/// An encoded AV1 stream and its `av1C` configuration record.
+pub struct Av1Track {
+    /// Coded size, which may be padded past the image's.
+    pub width: u32,
+    pub height: u32,
+    pub config: Vec<u8>,
+    pub samples: Vec<Vec<u8>>,
+    /// Indices of key frames.
+    pub sync: Vec<u32>,
+}
+
+pub struct SequenceMux<'a> {
+    /// Display size; a `clap` box crops the coded frames to it.
+    pub width: u32,
+    pub height: u32,
+    pub durations: &'a [u32],
+    pub loop_count: u32,
+    pub color: Av1Track,
+    pub alpha: Option<Av1Track>,
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&(u32::from(version) << 24 | flags).to_be_bytes());
+        body(out);
+    });
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// The identity transform of `mvhd` and `tkhd`.
+fn put_matrix(out: &mut Vec<u8>) {
+    for value in [0x1_0000, 0, 0, 0, 0x1_0000, 0, 0, 0, 0x4000_0000u32] {
+        put_u32(out, value);
+    }
+}
+
+/// sRGB primaries and transfer with the BT.601 matrix, full range: what
+/// `av1` encodes.
+fn write_nclx(out: &mut Vec<u8>) {
+    write_box(out, b"colr", |out| {
+        out.extend_from_slice(b"nclx");
+        put_u16(out, 1);
+        put_u16(out, 13);
+        put_u16(out, 6);
+        out.push(0x80);
+    });
+}
+
+/// Keeps the top-left `width` x `height` of the coded picture.
+fn write_clap(out: &mut Vec<u8>, mux: &SequenceMux, track: &Av1Track) {
+    write_box(out, b"clap", |out| {
+        for value in [mux.width, 1, mux.height, 1] {
+            put_u32(out, value);
+        }
+        // offsets from the centre, in halves
+        put_u32(out, mux.width.wrapping_sub(track.width));
+        put_u32(out, 2);
+        put_u32(out, mux.height.wrapping_sub(track.height));
+        put_u32(out, 2);
+    });
+}
+
+fn is_cropped(mux: &SequenceMux) -> bool {
+    (mux.color.width, mux.color.height) != (mux.width, mux.height)
+}
+
+fn write_auxiliary_type(out: &mut Vec<u8>, kind: &[u8; 4]) {
+    write_full_box(out, kind, 0, 0, |out| {
+        out.extend_from_slice(ALPHA_URN.as_bytes());
+        out.push(0);
+    });
+}
+
+/// The first frame as the primary item, so readers without sequence
+/// support still show a still. Its samples double as the item data.
+fn write_meta(out: &mut Vec<u8>, mux: &SequenceMux, offsets: &[u32; 2]) {
+    let tracks = std::iter::once(&mux.color)
+        .chain(&mux.alpha)
+        .collect::<Vec<_>>();
+    let cropped = is_cropped(mux);
+
+    write_full_box(out, b"meta", 0, 0, |out| {
+        write_full_box(out, b"hdlr", 0, 0, |out| {
+            put_u32(out, 0);
+            out.extend_from_slice(b"pict");
+            out.extend_from_slice(&[0; 13]);
+        });
+        write_full_box(out, b"pitm", 0, 0, |out| put_u16(out, 1));
+        write_full_box(out, b"iloc", 0, 0, |out| {
+            out.extend_from_slice(&[0x44, 0x00]);
+            put_u16(out, tracks.len() as u16);
+            for (i, track) in tracks.iter().enumerate() {
+                put_u16(out, i as u16 + 1);
+                put_u16(out, 0);
+                put_u16(out, 1);
+                put_u32(out, offsets[i]);
+                put_u32(out, track.samples[0].len() as u32);
+            }
+        });
+        write_full_box(out, b"iinf", 0, 0, |out| {
+            put_u16(out, tracks.len() as u16);
+            for i in 0..tracks.len() {
+                write_full_box(out, b"infe", 2, 0, |out| {
+                    put_u16(out, i as u16 + 1);
+                    put_u16(out, 0);
+                    out.extend_from_slice(b"av01");
+                    out.push(0);
+                });
+            }
+        });
+        if mux.alpha.is_some() {
+            write_full_box(out, b"iref", 0, 0, |out| {
+                write_box(out, b"auxl", |out| {
+                    put_u16(out, 2);
+                    put_u16(out, 1);
+                    put_u16(out, 1);
+                });
+            });
+        }
+        write_box(out, b"iprp", |out| {
+            write_box(out, b"ipco", |out| {
+                // 1: ispe, 2: pixi rgb, 3: av1C rgb, 4: colr
+                // 5: pixi alpha, 6: av1C alpha, 7: auxC, then clap
+                write_full_box(out, b"ispe", 0, 0, |out| {
+                    put_u32(out, mux.color.width);
+                    put_u32(out, mux.color.height);
+                });
+                write_full_box(out, b"pixi", 0, 0, |out| {
+                    out.extend_from_slice(&[3, 8, 8, 8])
+                });
+                write_box(out, b"av1C", |out| out.extend_from_slice(&mux.color.config));
+                write_nclx(out);
+                if let Some(alpha) = &mux.alpha {
+                    write_full_box(out, b"pixi", 0, 0, |out| out.extend_from_slice(&[1, 8]));
+                    write_box(out, b"av1C", |out| out.extend_from_slice(&alpha.config));
+                    write_auxiliary_type(out, b"auxC");
+                }
+                if cropped {
+                    write_clap(out, mux, &mux.color);
+                }
+            });
+            // the transformative clap has to be essential and come last
+            let clap = cropped.then_some(0x80 | if mux.alpha.is_some() { 8 } else { 5 });
+            write_full_box(out, b"ipma", 0, 0, |out| {
+                put_u32(out, tracks.len() as u32);
+                put_u16(out, 1);
+                let color = [1, 2, 0x83, 4].into_iter().chain(clap).collect::<Vec<_>>();
+                out.push(color.len() as u8);
+                out.extend_from_slice(&color);
+                if mux.alpha.is_some() {
+                    put_u16(out, 2);
+                    let alpha = [1, 5, 0x86, 7].into_iter().chain(clap).collect::<Vec<_>>();
+                    out.push(alpha.len() as u8);
+                    out.extend_from_slice(&alpha);
+                }
+            });
+        });
+    });
+}
+
+fn write_track(out: &mut Vec<u8>, mux: &SequenceMux, track: &Av1Track, id: u32, chunk_offset: u32) {
+    let media_duration = mux.durations.iter().map(|&d| u64::from(d)).sum::<u64>();
+    let duration = match mux.loop_count {
+        0 => u64::MAX,
+        plays => media_duration * u64::from(plays),
+    };
+    let alpha = id != 1;
+
+    write_box(out, b"trak", |out| {
+        write_full_box(out, b"tkhd", 1, if alpha { 1 } else { 3 }, |out| {
+            put_u64(out, 0);
+            put_u64(out, 0);
+            put_u32(out, id);
+            put_u32(out, 0);
+            put_u64(out, duration);
+            out.extend_from_slice(&[0; 16]);
+            put_matrix(out);
+            put_u32(out, mux.width << 16);
+            put_u32(out, mux.height << 16);
+        });
+        if alpha {
+            write_box(out, b"tref", |out| {
+                write_box(out, b"auxl", |out| put_u32(out, 1))
+            });
+        }
+        write_box(out, b"edts", |out| {
+            write_full_box(out, b"elst", 1, 1, |out| {
+                put_u32(out, 1);
+                put_u64(out, media_duration);
+                put_u64(out, 0);
+                put_u16(out, 1);
+                put_u16(out, 0);
+            });
+        });
+        write_box(out, b"mdia", |out| {
+            write_full_box(out, b"mdhd", 1, 0, |out| {
+                put_u64(out, 0);
+                put_u64(out, 0);
+                put_u32(out, TIMESCALE);
+                put_u64(out, media_duration);
+                // "und"
+                put_u16(out, 0x55c4);
+                put_u16(out, 0);
+            });
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                put_u32(out, 0);
+                out.extend_from_slice(if alpha { b"auxv" } else { b"pict" });
+                out.extend_from_slice(&[0; 13]);
+            });
+            write_box(out, b"minf", |out| {
+                write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        put_u32(out, 1);
+                        write_full_box(out, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_box(out, b"stbl", |out| {
+                    write_stbl(out, mux, track, alpha, chunk_offset)
+                });
+            });
+        });
+    });
+}
+
+fn write_stbl(out: &mut Vec<u8>, mux: &SequenceMux, track: &Av1Track, alpha: bool, offset: u32) {
+    write_full_box(out, b"stsd", 0, 0, |out| {
+        put_u32(out, 1);
+        write_box(out, b"av01", |out| {
+            out.extend_from_slice(&[0; 6]);
+            put_u16(out, 1);
+            out.extend_from_slice(&[0; 16]);
+            put_u16(out, track.width as u16);
+            put_u16(out, track.height as u16);
+            put_u32(out, 0x48_0000);
+            put_u32(out, 0x48_0000);
+            put_u32(out, 0);
+            put_u16(out, 1);
+            out.extend_from_slice(&[0; 32]);
+            put_u16(out, 0x18);
+            put_u16(out, 0xffff);
+            write_box(out, b"av1C", |out| out.extend_from_slice(&track.config));
+            // intra prediction used, any number of references
+            write_full_box(out, b"ccst", 0, 0, |out| put_u32(out, 0x7c00_0000));
+            if is_cropped(mux) {
+                write_clap(out, mux, track);
+            }
+            if alpha {
+                write_auxiliary_type(out, b"auxi");
+            } else {
+                write_nclx(out);
+            }
+        });
+    });
+
+    write_full_box(out, b"stts", 0, 0, |out| {
+        let mut runs: Vec<(u32, u32)> = vec![];
+        for &duration in mux.durations {
+            match runs.last_mut() {
+                Some((count, delta)) if *delta == duration => *count += 1,
+                _ => runs.push((1, duration)),
+            }
+        }
+        put_u32(out, runs.len() as u32);
+        for (count, delta) in runs {
+            put_u32(out, count);
+            put_u32(out, delta);
+        }
+    });
+    if track.sync.len() != track.samples.len() {
+        write_full_box(out, b"stss", 0, 0, |out| {
+            put_u32(out, track.sync.len() as u32);
+            for &index in &track.sync {
+                put_u32(out, index + 1);
+            }
+        });
+    }
+    write_full_box(out, b"stsc", 0, 0, |out| {
+        put_u32(out, 1);
+        put_u32(out, 1);
+        put_u32(out, track.samples.len() as u32);
+        put_u32(out, 1);
+    });
+    write_full_box(out, b"stsz", 0, 0, |out| {
+        put_u32(out, 0);
+        put_u32(out, track.samples.len() as u32);
+        for sample in &track.samples {
+            put_u32(out, sample.len() as u32);
+        }
+    });
+    write_full_box(out, b"stco", 0, 0, |out| {
+        put_u32(out, 1);
+        put_u32(out, offset);
+    });
+}
+
+fn write_moov(out: &mut Vec<u8>, mux: &SequenceMux, offsets: &[u32; 2]) {
+    let track_count = 1 + mux.alpha.is_some() as u32;
+    write_box(out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 1, 0, |out| {
+            put_u64(out, 0);
+            put_u64(out, 0);
+            put_u32(out, TIMESCALE);
+            put_u64(
+                out,
+                match mux.loop_count {
+                    0 => u64::MAX,
+                    plays => {
+                        mux.durations.iter().map(|&d| u64::from(d)).sum::<u64>() * u64::from(plays)
+                    }
+                },
+            );
+            put_u32(out, 0x1_0000);
+            put_u16(out, 0x100);
+            out.extend_from_slice(&[0; 10]);
+            put_matrix(out);
+            out.extend_from_slice(&[0; 24]);
+            put_u32(out, track_count + 1);
+        });
+        write_track(out, mux, &mux.color, 1, offsets[0]);
+        if let Some(alpha) = &mux.alpha {
+            write_track(out, mux, alpha, 2, offsets[1]);
+        }
+    });
+}
+
+/// `ftyp`, `meta`, `moov` and one `mdat` with the colour samples followed by
+/// the alpha samples, each track a single chunk.
+pub fn write_sequence(mux: &SequenceMux) -> Result<Vec<u8>> {
+    let too_large = || TransformError::encode(ImageFormat::Avif, "sequence larger than 4GiB");
+    let color_len = mux.color.samples.iter().map(Vec::len).sum::<usize>();
+    let alpha_len = mux
+        .alpha
+        .iter()
+        .flat_map(|t| &t.samples)
+        .map(Vec::len)
+        .sum::<usize>();
+
+    let mut head = vec![];
+    write_box(&mut head, b"ftyp", |out| {
+        out.extend_from_slice(b"avis");
+        put_u32(out, 0);
+        for brand in [b"avis", b"avif", b"msf1", b"iso8", b"mif1", b"miaf"] {
+            out.extend_from_slice(brand);
+        }
+    });
+    // the boxes have the same size whatever the offsets, so lay them out
+    // once to learn where the samples start
+    let ftyp_len = head.len();
+    let mut sized = head.clone();
+    write_meta(&mut sized, mux, &[0; 2]);
+    write_moov(&mut sized, mux, &[0; 2]);
+    let data_start = sized.len() + 8;
+    u32::try_from(data_start + color_len + alpha_len).map_err(|_| too_large())?;
+    let offsets = [data_start as u32, (data_start + color_len) as u32];
+    let mdat_len = (8 + color_len + alpha_len) as u32;
+
+    head.truncate(ftyp_len);
+    write_meta(&mut head, mux, &offsets);
+    write_moov(&mut head, mux, &offsets);
+    debug_assert_eq!(head.len() + 8, data_start);
+
+    head.reserve(mdat_len as usize);
+    put_u32(&mut head, mdat_len);
+    head.extend_from_slice(b"mdat");
+    for track in std::iter::once(&mux.color).chain(&mux.alpha) {
+        for sample in &track.samples {
+            head.extend_from_slice(sample);
+        }
+    }
+
+    Ok(head)
+}
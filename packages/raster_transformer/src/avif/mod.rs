@@ -0,0 +1,280 @@
+//! AVIF stills through ravif, and sequences through rav1e with a muxer of
+//! our own; both pure Rust, so encoding also works on wasm32.
+
+mod av1;
+mod isobmff;
+
+use crate::core::{RGBA8AnimatedImageData, RGBA8ImageDataType, RGBA8StaticImageData};
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::limits::DecodeLimits;
+use crate::metadata::ImageMetadata;
+use av1::{Av1Decoder, Av1Encoder};
+use image::codecs::avif::AvifEncoder;
+use image::{EncodableLayout, ExtendedColorType, ImageEncoder, Rgba, RgbaImage};
+use isobmff::{
+    CleanAperture, SequenceMux, SequenceTracks, read_sequence, read_still, write_sequence,
+};
+
+/// rav1e speed preset, 1 (slowest) to 10 (fastest).
+pub const AVIF_ENCODER_SPEED: u8 = 4;
+
+pub fn avif_quality(quality: f32) -> u8 {
+    quality.clamp(1.0, 100.0).round() as u8
+}
+
+/// Sequences decode to an animation, everything else to the primary item.
+/// AV1 decoding goes through rav1d, which has no wasm32 build yet.
+pub fn decode_avif(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8ImageDataType> {
+    match read_sequence(data, limits)? {
+        Some(tracks) if tracks.samples.len() > 1 => {
+            decode_avif_sequence(tracks, limits).map(RGBA8ImageDataType::Animated)
+        }
+        _ => decode_avif_still(data, limits).map(RGBA8ImageDataType::Static),
+    }
+}
+
+fn apply_crop(img: RgbaImage, crop: Option<CleanAperture>) -> RgbaImage {
+    match crop {
+        Some(c) => image::imageops::crop_imm(&img, c.x, c.y, c.width, c.height).to_image(),
+        None => img,
+    }
+}
+
+fn decode_avif_still(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8StaticImageData> {
+    let items = read_still(data)?;
+    limits.tracker(items.width, items.height)?;
+
+    let mut img = RgbaImage::from_pixel(items.width, items.height, Rgba([0, 0, 0, 255]));
+    Av1Decoder::new(limits.max_pixels)?.decode_rgb(&items.color, &mut img)?;
+    if let Some(alpha) = &items.alpha {
+        Av1Decoder::new(limits.max_pixels)?.decode_alpha(alpha, &mut img)?;
+    }
+    let img = apply_crop(img, items.crop);
+
+    Ok(RGBA8StaticImageData {
+        width: img.width(),
+        height: img.height(),
+        data: img,
+        metadata: ImageMetadata {
+            icc: items.icc,
+            ..ImageMetadata::default()
+        },
+    })
+}
+
+fn decode_avif_sequence(
+    tracks: SequenceTracks,
+    limits: &DecodeLimits,
+) -> Result<RGBA8AnimatedImageData> {
+    let (width, height) = (tracks.width, tracks.height);
+    let mut tracker = limits.tracker(width, height)?;
+    let mut color = Av1Decoder::new(limits.max_pixels)?;
+    let mut alpha = tracks
+        .alpha
+        .as_ref()
+        .map(|samples| Ok::<_, TransformError>((Av1Decoder::new(limits.max_pixels)?, samples)))
+        .transpose()?;
+
+    let mut frames = Vec::with_capacity(tracks.samples.len());
+    for (i, (sample, &duration)) in tracks.samples.iter().zip(&tracks.durations).enumerate() {
+        tracker.frame(width, height, duration)?;
+        let mut frame = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+        color.decode_rgb(sample, &mut frame)?;
+        if let Some((decoder, samples)) = &mut alpha {
+            decoder.decode_alpha(samples[i], &mut frame)?;
+        }
+        frames.push(apply_crop(frame, tracks.crop));
+    }
+    let (width, height) = tracks
+        .crop
+        .map_or((width, height), |crop| (crop.width, crop.height));
+
+    Ok(RGBA8AnimatedImageData {
+        width,
+        height,
+        durations: tracks.durations,
+        frames,
+        loop_count: tracks.loop_count,
+        bg_color: Rgba([255, 255, 255, 0]),
+        metadata: ImageMetadata {
+            icc: tracks.icc,
+            ..ImageMetadata::default()
+        },
+        regions: None,
+    })
+}
+
+pub fn encode_static_avif(image_data: RGBA8StaticImageData, quality: f32) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+
+    AvifEncoder::new_with_speed_quality(&mut buf, AVIF_ENCODER_SPEED, avif_quality(quality))
+        .with_num_threads(Some(1))
+        .write_image(
+            image_data.data.as_bytes(),
+            image_data.width,
+            image_data.height,
+            ExtendedColorType::Rgba8,
+        )
+        .map_err(|e| TransformError::encode(ImageFormat::Avif, e))?;
+
+    Ok(buf)
+}
+
+/// Writes an AVIF sequence with rav1e, plus an alpha track when any frame
+/// is not opaque. The first frame doubles as the still for readers without
+/// sequence support.
+pub fn encode_animated_avif(
+    mut image_data: RGBA8AnimatedImageData,
+    quality: f32,
+) -> Result<Vec<u8>> {
+    image_data.composite();
+    if image_data.frames.is_empty() {
+        return Err(TransformError::encode(ImageFormat::Avif, "no frames"));
+    }
+    let (width, height) = (image_data.width, image_data.height);
+    let opaque = image_data
+        .frames
+        .iter()
+        .all(|frame| frame.pixels().all(|p| p.0[3] == 255));
+
+    let mut color = Av1Encoder::new(width, height, quality, AVIF_ENCODER_SPEED, false)?;
+    let mut alpha = (!opaque)
+        .then(|| Av1Encoder::new(width, height, quality, AVIF_ENCODER_SPEED, true))
+        .transpose()?;
+    for frame in &image_data.frames {
+        color.add_frame(frame)?;
+        if let Some(alpha) = &mut alpha {
+            alpha.add_frame(frame)?;
+        }
+    }
+
+    write_sequence(&SequenceMux {
+        width,
+        height,
+        durations: &image_data.durations,
+        loop_count: image_data.loop_count,
+        color: color.finish()?,
+        alpha: alpha.map(Av1Encoder::finish).transpose()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::{animation, still};
+
+    #[test]
+    fn test_encode_static_avif() {
+        let st_img = still(RgbaImage::from_pixel(16, 16, Rgba([12, 34, 56, 255])));
+
+        let bytes = encode_static_avif(st_img, 60.0).unwrap();
+
+        assert_eq!(&bytes[4..12], b"ftypavif");
+    }
+
+    fn close(a: Rgba<u8>, b: Rgba<u8>) -> bool {
+        a.0.iter().zip(b.0).all(|(&a, b)| a.abs_diff(b) <= 12)
+    }
+
+    #[test]
+    fn test_decode_static_avif() {
+        let data = RgbaImage::from_fn(16, 16, |x, _| {
+            if x < 8 {
+                Rgba([200, 40, 40, 255])
+            } else {
+                Rgba([40, 40, 200, 128])
+            }
+        });
+        let bytes = encode_static_avif(still(data.clone()), 90.0).unwrap();
+
+        let RGBA8ImageDataType::Static(decoded) =
+            decode_avif(&bytes, &DecodeLimits::default()).unwrap()
+        else {
+            panic!("expected static avif");
+        };
+        assert_eq!((decoded.width, decoded.height), (16, 16));
+        assert!(close(*decoded.data.get_pixel(2, 8), data[(2, 8)]));
+        assert!(close(*decoded.data.get_pixel(13, 8), data[(13, 8)]));
+    }
+
+    #[test]
+    fn test_animated_avif_round_trip() {
+        let colors = [
+            Rgba([220, 30, 30, 255]),
+            Rgba([30, 220, 30, 255]),
+            Rgba([30, 30, 220, 96]),
+        ];
+        let mut ani_img = animation(
+            colors
+                .iter()
+                .map(|&c| RgbaImage::from_pixel(24, 18, c))
+                .collect(),
+            vec![50, 120, 300],
+        );
+        ani_img.loop_count = 3;
+
+        let bytes = encode_animated_avif(ani_img, 90.0).unwrap();
+        let info = crate::format::ImageFormatInfo::sniff(&bytes).unwrap();
+        assert!(info.format == ImageFormat::Avif && info.animated);
+
+        let RGBA8ImageDataType::Animated(decoded) =
+            decode_avif(&bytes, &DecodeLimits::default()).unwrap()
+        else {
+            panic!("expected animated avif");
+        };
+        assert_eq!((decoded.width, decoded.height), (24, 18));
+        assert_eq!(decoded.durations, vec![50, 120, 300]);
+        assert_eq!(decoded.loop_count, 3);
+        for (frame, color) in decoded.frames.iter().zip(colors) {
+            assert!(
+                close(*frame.get_pixel(12, 9), color),
+                "{:?}",
+                frame.get_pixel(12, 9)
+            );
+        }
+
+        // the first frame is also the primary item
+        let still = decode_avif_still(&bytes, &DecodeLimits::default()).unwrap();
+        assert!(close(*still.data.get_pixel(0, 0), colors[0]));
+
+        let limits = DecodeLimits {
+            max_frames: Some(2),
+            ..DecodeLimits::default()
+        };
+        let err = decode_avif(&bytes, &limits).err().unwrap();
+        assert_eq!(err.kind(), "LimitExceeded");
+    }
+
+    #[test]
+    fn test_small_animated_avif_round_trip() {
+        let frames = vec![
+            RgbaImage::from_fn(8, 5, |x, _| {
+                if x < 4 {
+                    Rgba([220, 30, 30, 255])
+                } else {
+                    Rgba([30, 30, 220, 128])
+                }
+            }),
+            RgbaImage::from_pixel(8, 5, Rgba([30, 220, 30, 255])),
+        ];
+        let ani_img = crate::core::fixtures::animation(frames.clone(), vec![100, 100]);
+
+        let bytes = encode_animated_avif(ani_img, 90.0).unwrap();
+
+        // rav1e codes at least 16x16, cropped back by `clap`
+        let RGBA8ImageDataType::Animated(decoded) =
+            decode_avif(&bytes, &DecodeLimits::default()).unwrap()
+        else {
+            panic!("expected animated avif");
+        };
+        assert_eq!((decoded.width, decoded.height), (8, 5));
+        assert!(decoded.frames.iter().all(|f| f.dimensions() == (8, 5)));
+        for (x, y) in [(0, 0), (7, 4)] {
+            assert!(close(*decoded.frames[0].get_pixel(x, y), frames[0][(x, y)]));
+        }
+        let still = decode_avif_still(&bytes, &DecodeLimits::default()).unwrap();
+        assert_eq!((still.width, still.height), (8, 5));
+        assert!(close(*still.data.get_pixel(7, 4), frames[0][(7, 4)]));
+    }
+}
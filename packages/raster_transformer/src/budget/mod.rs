@@ -0,0 +1,248 @@
+use crate::core::RGBA8ImageDataType;
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::{EncodeOptions, TransformOptions};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SizeBudget {
+    pub max_bytes: u32,
+    /// Lowest quality the search may fall back to; the ceiling is the
+    /// requested `encode.quality`.
+    pub min_quality: f32,
+    /// Scales to try in order of preference; empty keeps `scale`.
+    pub scales: Vec<f32>,
+    /// Minimum frame delays to try in order of preference; empty keeps `min_delay`.
+    pub min_delays: Vec<u32>,
+    /// Bisection steps per scale/min_delay candidate.
+    pub max_iterations: u32,
+}
+
+impl Default for SizeBudget {
+    fn default() -> Self {
+        Self {
+            max_bytes: u32::MAX,
+            min_quality: 10.0,
+            scales: vec![],
+            min_delays: vec![],
+            max_iterations: 7,
+        }
+    }
+}
+
+/// The first candidate that fits, with the parameters that produced it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BudgetChoice {
+    #[serde(skip)]
+    pub data: Vec<u8>,
+    pub size: u32,
+    pub quality: f32,
+    pub scale: f32,
+    pub min_delay: u32,
+    pub attempts: u32,
+}
+
+struct BudgetSearch<'a> {
+    format: ImageFormat,
+    budget: &'a SizeBudget,
+    encode: EncodeOptions,
+    attempts: u32,
+    smallest: Option<u32>,
+}
+
+impl BudgetSearch<'_> {
+    fn try_quality(&mut self, image_data: &RGBA8ImageDataType, quality: f32) -> Result<Vec<u8>> {
+        self.encode.quality = quality;
+        self.attempts += 1;
+
+        let bytes = image_data.clone().encode(self.format, &self.encode)?;
+        let size = bytes.len() as u32;
+        self.smallest = Some(self.smallest.map_or(size, |smallest| smallest.min(size)));
+
+        Ok(bytes)
+    }
+
+    fn fits(&self, bytes: &[u8]) -> bool {
+        bytes.len() as u64 <= u64::from(self.budget.max_bytes)
+    }
+
+    /// Highest quality in `[min_quality, max_quality]` that fits, bisecting on
+    /// whole quality steps.
+    fn search_quality(
+        &mut self,
+        image_data: &RGBA8ImageDataType,
+        max_quality: f32,
+    ) -> Result<Option<(f32, Vec<u8>)>> {
+        let bytes = self.try_quality(image_data, max_quality)?;
+        if self.fits(&bytes) {
+            return Ok(Some((max_quality, bytes)));
+        }
+        if !quality_changes_size(self.format) {
+            return Ok(None);
+        }
+
+        let mut best = None;
+        let mut lo = self.budget.min_quality.min(max_quality).round() as i32;
+        let mut hi = max_quality.round() as i32 - 1;
+
+        for _ in 0..self.budget.max_iterations {
+            if lo > hi {
+                break;
+            }
+            let mid = lo + (hi - lo + 1) / 2;
+            let bytes = self.try_quality(image_data, mid as f32)?;
+            if self.fits(&bytes) {
+                best = Some((mid as f32, bytes));
+                lo = mid + 1;
+            } else {
+                hi = mid - 1;
+            }
+        }
+
+        Ok(best)
+    }
+}
+
+/// PNG is lossless, GIF's quality only changes how the palette is sampled,
+/// and the pure-Rust WebP backend only encodes lossless.
+fn quality_changes_size(format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Png | ImageFormat::Gif => false,
+        ImageFormat::WebP => cfg!(feature = "libwebp"),
+        ImageFormat::Avif => true,
+    }
+}
+
+/// Runs the transform pipeline over the budget's candidates and
+/// returns the least degraded output that fits in `budget.max_bytes`.
+pub fn transform_within_budget(
+    image_data: &RGBA8ImageDataType,
+    format: ImageFormat,
+    options: &TransformOptions,
+    budget: &SizeBudget,
+) -> Result<BudgetChoice> {
+    let scales = if budget.scales.is_empty() {
+        vec![options.scale]
+    } else {
+        budget.scales.clone()
+    };
+    let min_delays = if budget.min_delays.is_empty() {
+        vec![options.min_delay]
+    } else {
+        budget.min_delays.clone()
+    };
+
+    let mut search = BudgetSearch {
+        format,
+        budget,
+        encode: options.encode.clone(),
+        attempts: 0,
+        smallest: None,
+    };
+
+    for &scale in &scales {
+        for &min_delay in &min_delays {
+            let mut candidate = image_data.clone();
+            options.apply(&mut candidate, min_delay, scale)?;
+
+            if let Some((quality, data)) =
+                search.search_quality(&candidate, options.encode.quality)?
+            {
+                return Ok(BudgetChoice {
+                    size: data.len() as u32,
+                    data,
+                    quality,
+                    scale,
+                    min_delay,
+                    attempts: search.attempts,
+                });
+            }
+        }
+    }
+
+    Err(TransformError::LimitExceeded {
+        limit: "output bytes".into(),
+        value: u64::from(search.smallest.unwrap_or(u32::MAX)),
+        max: u64::from(budget.max_bytes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::core::fixtures::still;
+
+    fn noise_image() -> RGBA8ImageDataType {
+        RGBA8ImageDataType::Static(still(RgbaImage::from_fn(96, 96, |x, y| {
+            let v = ((x * 7919 + y * 104729) % 251) as u8;
+            Rgba([v, v.wrapping_mul(3), v.wrapping_mul(7), 255])
+        })))
+    }
+
+    // the pure-Rust backend only encodes lossless, where quality does nothing
+    #[cfg(feature = "libwebp")]
+    #[test]
+    fn test_budget_lowers_quality_then_scale() {
+        let options = TransformOptions {
+            encode: EncodeOptions::with_quality(90.0),
+            ..TransformOptions::default()
+        };
+        let full = noise_image()
+            .encode(ImageFormat::WebP, &options.encode)
+            .unwrap();
+
+        let budget = SizeBudget {
+            max_bytes: full.len() as u32 / 2,
+            scales: vec![1.0, 0.5],
+            ..SizeBudget::default()
+        };
+        let choice =
+            transform_within_budget(&noise_image(), ImageFormat::WebP, &options, &budget).unwrap();
+
+        assert!(choice.size <= budget.max_bytes);
+        assert!(choice.quality < 90.0 || choice.scale < 1.0);
+    }
+
+    #[test]
+    fn test_budget_skips_quality_for_png() {
+        let full = noise_image()
+            .encode(ImageFormat::Png, &EncodeOptions::default())
+            .unwrap();
+        let budget = SizeBudget {
+            max_bytes: full.len() as u32 - 1,
+            scales: vec![1.0, 0.1],
+            ..SizeBudget::default()
+        };
+        let choice = transform_within_budget(
+            &noise_image(),
+            ImageFormat::Png,
+            &TransformOptions::default(),
+            &budget,
+        )
+        .unwrap();
+
+        assert_eq!(choice.scale, 0.1);
+        assert_eq!(choice.attempts, 2);
+    }
+
+    #[test]
+    fn test_budget_reports_limit() {
+        let budget = SizeBudget {
+            max_bytes: 8,
+            ..SizeBudget::default()
+        };
+        let err = transform_within_budget(
+            &noise_image(),
+            ImageFormat::Png,
+            &TransformOptions::default(),
+            &budget,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.kind(), "LimitExceeded");
+    }
+}
@@ -0,0 +1,151 @@
+use crate::core::RGBA8ImageDataType;
+use crate::error::{Result, TransformError};
+use crate::metadata::ImageMetadata;
+use moxcms::{
+    Chromaticity, ColorPrimaries, ColorProfile, DataColorSpace, Layout, TransformOptions, XyY,
+    curve_from_gamma,
+};
+
+fn color_profile_error(e: impl ToString) -> TransformError {
+    TransformError::ColorProfile(e.to_string())
+}
+
+/// The colour space the pixels were stored in, following PNG's precedence:
+/// an ICC profile wins over `sRGB`, which wins over `gAMA`/`cHRM`. `None`
+/// means the pixels are already sRGB, or nothing says otherwise.
+pub fn source_profile(metadata: &ImageMetadata) -> Result<Option<ColorProfile>> {
+    if let Some(icc) = &metadata.icc {
+        return ColorProfile::new_from_slice(icc)
+            .map(Some)
+            .map_err(color_profile_error);
+    }
+    if metadata.srgb || (metadata.gamma.is_none() && metadata.chromaticities.is_none()) {
+        return Ok(None);
+    }
+
+    let mut profile = ColorProfile::new_srgb();
+    if let Some([white, red, green, blue]) = metadata.chromaticities {
+        let xy = |[x, y]: [f32; 2]| Chromaticity::new(x, y);
+        profile.update_rgb_colorimetry(
+            XyY::new(f64::from(white[0]), f64::from(white[1]), 1.0),
+            ColorPrimaries {
+                red: xy(red),
+                green: xy(green),
+                blue: xy(blue),
+            },
+        );
+    }
+    if let Some(gamma) = metadata.gamma {
+        if gamma <= 0.0 {
+            return Err(color_profile_error(format!("invalid gAMA {}", gamma)));
+        }
+        // gAMA stores the encoding exponent, e.g. 0.45455 for a 2.2 display
+        let curve = curve_from_gamma(1.0 / gamma);
+        profile.red_trc = Some(curve.clone());
+        profile.green_trc = Some(curve.clone());
+        profile.blue_trc = Some(curve);
+        profile.cicp = None;
+    }
+
+    Ok(Some(profile))
+}
+
+impl RGBA8ImageDataType {
+    /// Converts every frame from the captured colour space to sRGB and drops
+    /// the colour tags that no longer apply. Returns whether anything was
+    /// converted; profiles for non-RGB data are left alone.
+    pub fn convert_to_srgb(&mut self) -> Result<bool> {
+        let Some(profile) = source_profile(self.metadata())? else {
+            return Ok(false);
+        };
+        if profile.color_space != DataColorSpace::Rgb {
+            return Ok(false);
+        }
+
+        let transform = profile
+            .create_transform_8bit(
+                Layout::Rgba,
+                &ColorProfile::new_srgb(),
+                Layout::Rgba,
+                TransformOptions::default(),
+            )
+            .map_err(color_profile_error)?;
+
+        let frames = match self {
+            Self::Static(st_img) => std::slice::from_mut(&mut st_img.data),
+            Self::Animated(ani_img) => ani_img.frames.as_mut_slice(),
+        };
+        for frame in frames {
+            let src = frame.as_raw().clone();
+            transform
+                .transform(&src, frame.as_mut())
+                .map_err(color_profile_error)?;
+        }
+
+        let metadata = self.metadata_mut();
+        metadata.icc = None;
+        metadata.gamma = None;
+        metadata.chromaticities = None;
+        metadata.srgb = false;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::core::fixtures::still;
+
+    fn flat(color: Rgba<u8>, metadata: ImageMetadata) -> RGBA8ImageDataType {
+        let mut st_img = still(RgbaImage::from_pixel(2, 2, color));
+        st_img.metadata = metadata;
+        RGBA8ImageDataType::Static(st_img)
+    }
+
+    #[test]
+    fn test_display_p3_to_srgb() {
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let mut img = flat(
+            Rgba([0, 255, 0, 200]),
+            ImageMetadata {
+                icc: Some(p3),
+                ..ImageMetadata::default()
+            },
+        );
+
+        assert!(img.convert_to_srgb().unwrap());
+
+        let RGBA8ImageDataType::Static(st_img) = &img else {
+            unreachable!()
+        };
+        let p = st_img.data.get_pixel(0, 0);
+        // P3 green is outside sRGB: clipped to full green, alpha untouched
+        assert!(p.0[1] == 255 && p.0[0] == 0, "{:?}", p);
+        assert_eq!(p.0[3], 200);
+        assert!(img.metadata().icc.is_none());
+    }
+
+    #[test]
+    fn test_png_gamma_to_srgb() {
+        let mut untagged = flat(Rgba([128, 128, 128, 255]), ImageMetadata::default());
+        assert!(!untagged.convert_to_srgb().unwrap());
+
+        // linear-light data, gamma 1.0: mid grey gets brighter in sRGB
+        let mut linear = flat(
+            Rgba([128, 128, 128, 255]),
+            ImageMetadata {
+                gamma: Some(1.0),
+                ..ImageMetadata::default()
+            },
+        );
+        assert!(linear.convert_to_srgb().unwrap());
+
+        let RGBA8ImageDataType::Static(st_img) = &linear else {
+            unreachable!()
+        };
+        assert!(st_img.data.get_pixel(0, 0).0[0].abs_diff(188) <= 2);
+    }
+}
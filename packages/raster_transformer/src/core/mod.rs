@@ -1,17 +1,58 @@
-use anyhow::Result;
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::limits::DecodeLimits;
+use crate::metadata::ImageMetadata;
+use crate::resize::{ResizeFilter, ResizeSpec, resample};
 use image::{Rgba, RgbaImage};
 
+/// Never rounds down to 0, which the resamplers can't produce.
 pub fn length_scale(len: u32, scale: f32) -> u32 {
-    f32::round(len as f32 * scale) as u32
+    (f32::round(len as f32 * scale) as u32).max(1)
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlendOp {
+    /// Replace the covered pixels.
+    #[default]
+    Source,
+    /// Alpha-blend over the current canvas.
+    Over,
+}
+
+/// What happens to a frame's rectangle once it has been shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisposeOp {
+    #[default]
+    None,
+    /// Clear to transparent.
+    Background,
+    /// Restore what was there before the frame.
+    Previous,
+}
+
+/// Placement of a sub-rectangle frame; its size is the frame buffer's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameRegion {
+    pub x: u32,
+    pub y: u32,
+    pub blend: BlendOp,
+    pub dispose: DisposeOp,
+}
+
+#[derive(Clone)]
 pub struct RGBA8AnimatedImageData {
     pub width: u32,
     pub height: u32,
     pub durations: Vec<u32>,
+    /// Full canvases, unless `regions` is set.
     pub frames: Vec<RgbaImage>,
     pub loop_count: u32,
     pub bg_color: Rgba<u8>,
+    pub metadata: ImageMetadata,
+    /// When set, `frames[i]` is a sub-rectangle composited per `regions[i]`.
+    /// Only decoders and encoders deal in this form; every other operation
+    /// calls `composite` first.
+    pub regions: Option<Vec<FrameRegion>>,
 }
 
 impl RGBA8AnimatedImageData {
@@ -20,7 +61,7 @@ impl RGBA8AnimatedImageData {
         let mut height = 0;
         let mut frames = vec![];
         let mut durations = vec![];
-        for (_i, frame) in img_frames.into_iter().enumerate() {
+        for frame in img_frames {
             let duration: std::time::Duration = frame.delay().into();
             durations.push(duration.as_millis() as u32);
             let img = frame.into_buffer();
@@ -35,10 +76,13 @@ impl RGBA8AnimatedImageData {
             frames,
             loop_count: 0,
             bg_color: Rgba([255, 255, 255, 0]),
+            metadata: ImageMetadata::default(),
+            regions: None,
         })
     }
 
     pub fn ease_frames(&mut self, min_delay_ms: u32) {
+        self.composite();
         let mut next_durations = vec![];
         let mut next_frames = vec![];
 
@@ -77,29 +121,55 @@ impl RGBA8AnimatedImageData {
         self.frames = next_frames;
     }
 
-    pub fn resize(&mut self, scale: f32) {
+    pub fn resize(&mut self, scale: f32, filter: ResizeFilter) {
+        self.composite();
         let next_width = length_scale(self.width, scale);
         let next_height = length_scale(self.height, scale);
 
         self.frames = self
             .frames
             .iter()
-            .map(|f| image::imageops::resize(f, next_width, next_height, image::imageops::Lanczos3))
+            .map(|f| resample(f, next_width, next_height, filter))
             .collect();
         self.width = next_width;
         self.height = next_height;
     }
+
+    pub fn resize_to(&mut self, spec: &ResizeSpec, filter: ResizeFilter) -> Result<()> {
+        self.composite();
+        let plan = spec.plan(self.width, self.height)?;
+
+        self.frames = self.frames.iter().map(|f| plan.apply(f, filter)).collect();
+        self.width = plan.canvas_width;
+        self.height = plan.canvas_height;
+
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
 pub struct RGBA8StaticImageData {
     pub data: RgbaImage,
     pub width: u32,
     pub height: u32,
+    pub metadata: ImageMetadata,
 }
 
 impl RGBA8StaticImageData {
-    pub fn decode(data: &[u8]) -> Result<Self> {
-        let img = image::load_from_memory(data)?;
+    pub fn decode(data: &[u8], limits: &DecodeLimits) -> Result<Self> {
+        let decode_error = |e: image::ImageError| {
+            let codec = image::guess_format(data)
+                .ok()
+                .and_then(|f| ImageFormat::from_extname(f.extensions_str().first()?).ok())
+                .unwrap_or(ImageFormat::Png);
+            TransformError::decode(codec, e)
+        };
+
+        let mut reader = image::ImageReader::new(std::io::Cursor::new(data))
+            .with_guessed_format()
+            .map_err(|e| decode_error(e.into()))?;
+        reader.limits(limits.image_limits());
+        let img = reader.decode().map_err(decode_error)?;
         let width = img.width();
         let height = img.height();
 
@@ -107,27 +177,92 @@ impl RGBA8StaticImageData {
             data: img.into_rgba8(),
             width,
             height,
+            metadata: ImageMetadata::default(),
         })
     }
 
     pub fn ease_frames(&mut self, _min_delay_ms: u32) {}
 
-    pub fn resize(&mut self, scale: f32) {
+    pub fn resize(&mut self, scale: f32, filter: ResizeFilter) {
         let next_width = length_scale(self.width, scale);
         let next_height = length_scale(self.height, scale);
 
-        self.data = image::imageops::resize(
-            &self.data,
-            next_width, 
-            next_height, 
-            image::imageops::Lanczos3
-        );
+        self.data = resample(&self.data, next_width, next_height, filter);
         self.width = next_width;
         self.height = next_height;
     }
+
+    pub fn resize_to(&mut self, spec: &ResizeSpec, filter: ResizeFilter) -> Result<()> {
+        let plan = spec.plan(self.width, self.height)?;
+
+        self.data = plan.apply(&self.data, filter);
+        self.width = plan.canvas_width;
+        self.height = plan.canvas_height;
+
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
 pub enum RGBA8ImageDataType {
     Static(RGBA8StaticImageData),
     Animated(RGBA8AnimatedImageData),
 }
+
+/// Animations for tests that only care about frame order and timing.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    pub fn still(data: RgbaImage) -> RGBA8StaticImageData {
+        RGBA8StaticImageData {
+            width: data.width(),
+            height: data.height(),
+            data,
+            metadata: ImageMetadata::default(),
+        }
+    }
+
+    /// Sized by the first frame.
+    pub fn animation(frames: Vec<RgbaImage>, durations: Vec<u32>) -> RGBA8AnimatedImageData {
+        RGBA8AnimatedImageData {
+            width: frames[0].width(),
+            height: frames[0].height(),
+            durations,
+            frames,
+            loop_count: 0,
+            bg_color: Rgba([255, 255, 255, 0]),
+            metadata: ImageMetadata::default(),
+            regions: None,
+        }
+    }
+
+    /// 4x4 frames filled with `colors`, 100ms each.
+    pub fn solid_animation(colors: &[[u8; 4]]) -> RGBA8AnimatedImageData {
+        animation(
+            colors
+                .iter()
+                .map(|c| RgbaImage::from_pixel(4, 4, Rgba(*c)))
+                .collect(),
+            vec![100; colors.len()],
+        )
+    }
+
+    /// 1x1 frames that carry their index, see `frame_ids`.
+    pub fn numbered_animation(durations: &[u32]) -> RGBA8AnimatedImageData {
+        animation(
+            (0..durations.len())
+                .map(|i| RgbaImage::from_pixel(1, 1, Rgba([i as u8, 0, 0, 255])))
+                .collect(),
+            durations.to_vec(),
+        )
+    }
+
+    pub fn frame_ids(ani_img: &RGBA8AnimatedImageData) -> Vec<u8> {
+        ani_img
+            .frames
+            .iter()
+            .map(|f| f.get_pixel(0, 0).0[0])
+            .collect()
+    }
+}
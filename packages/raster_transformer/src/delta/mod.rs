@@ -0,0 +1,310 @@
+use crate::core::{BlendOp, DisposeOp, FrameRegion, RGBA8AnimatedImageData, RGBA8ImageDataType};
+use crate::geometry::Rect;
+use image::{Rgba, RgbaImage};
+
+/// Non-premultiplied "over", truncating like libwebp's reference blender.
+pub fn blend_over(dst: Rgba<u8>, src: Rgba<u8>) -> Rgba<u8> {
+    let src_alpha = f64::from(src.0[3]);
+    let dst_alpha = f64::from(dst.0[3]);
+
+    let blend_alpha = src_alpha + dst_alpha * (1.0 - src_alpha / 255.0);
+    if blend_alpha as u8 == 0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let [r, g, b] = [0, 1, 2].map(|c| {
+        let val = (f64::from(src.0[c]) * src_alpha
+            + f64::from(dst.0[c]) * dst_alpha * (1.0 - src_alpha / 255.0))
+            / blend_alpha;
+        val as u8
+    });
+    Rgba([r, g, b, blend_alpha as u8])
+}
+
+/// Fully transparent pixels look the same whatever their colour.
+fn same_pixel(a: &Rgba<u8>, b: &Rgba<u8>) -> bool {
+    a == b || (a.0[3] == 0 && b.0[3] == 0)
+}
+
+/// Draws `frame` at `(x, y)`, clipped to the canvas.
+fn draw(canvas: &mut RgbaImage, frame: &RgbaImage, x: u32, y: u32, blend: BlendOp) {
+    let (width, height) = canvas.dimensions();
+    for (fx, fy, src) in frame.enumerate_pixels() {
+        let (Some(cx), Some(cy)) = (x.checked_add(fx), y.checked_add(fy)) else {
+            continue;
+        };
+        if cx >= width || cy >= height {
+            continue;
+        }
+
+        let dst = canvas.get_pixel_mut(cx, cy);
+        *dst = match blend {
+            BlendOp::Source => *src,
+            BlendOp::Over => blend_over(*dst, *src),
+        };
+    }
+}
+
+fn clear(canvas: &mut RgbaImage, rect: Rect) {
+    let (width, height) = canvas.dimensions();
+    for y in rect.y..rect.y.saturating_add(rect.height).min(height) {
+        for x in rect.x..rect.x.saturating_add(rect.width).min(width) {
+            canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+        }
+    }
+}
+
+/// Bounding box of the pixels that differ between two canvases.
+pub fn diff_bounds(prev: &RgbaImage, next: &RgbaImage) -> Option<Rect> {
+    let mut bounds: Option<Rect> = None;
+    for (x, y, p) in next.enumerate_pixels() {
+        if !same_pixel(prev.get_pixel(x, y), p) {
+            let px = Rect::new(x, y, 1, 1);
+            bounds = Some(bounds.map_or(px, |b| b.union(px)));
+        }
+    }
+    bounds
+}
+
+/// Whether blending over `prev` can't reach `next`: some changed pixel is
+/// translucent and lands on something visible.
+fn needs_clear(prev: &RgbaImage, next: &RgbaImage) -> bool {
+    prev.pixels()
+        .zip(next.pixels())
+        .any(|(p, n)| !same_pixel(p, n) && n.0[3] < 255 && p.0[3] > 0)
+}
+
+fn contains(rect: Rect, x: u32, y: u32) -> bool {
+    x >= rect.x && y >= rect.y && x - rect.x < rect.width && y - rect.y < rect.height
+}
+
+/// Renders sub-rectangle frames one at a time onto a single canvas that
+/// starts out transparent, as browsers do.
+pub struct Compositor {
+    canvas: RgbaImage,
+}
+
+impl Compositor {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            canvas: RgbaImage::new(width, height),
+        }
+    }
+
+    /// The canvas as shown while `frame` is on screen; `region.dispose` is
+    /// applied before the next call.
+    pub fn render(&mut self, frame: &RgbaImage, region: &FrameRegion) -> RgbaImage {
+        let previous = (region.dispose == DisposeOp::Previous).then(|| self.canvas.clone());
+
+        draw(&mut self.canvas, frame, region.x, region.y, region.blend);
+        let shown = self.canvas.clone();
+
+        match region.dispose {
+            DisposeOp::None => {}
+            DisposeOp::Background => clear(
+                &mut self.canvas,
+                Rect::new(region.x, region.y, frame.width(), frame.height()),
+            ),
+            DisposeOp::Previous => {
+                if let Some(previous) = previous {
+                    self.canvas = previous;
+                }
+            }
+        }
+
+        shown
+    }
+}
+
+impl RGBA8AnimatedImageData {
+    /// Renders sub-rectangle frames onto full canvases. No-op for full frames.
+    pub fn composite(&mut self) {
+        let Some(regions) = self.regions.take() else {
+            return;
+        };
+
+        let mut compositor = Compositor::new(self.width, self.height);
+        self.frames = std::mem::take(&mut self.frames)
+            .iter()
+            .zip(&regions)
+            .map(|(frame, region)| compositor.render(frame, region))
+            .collect();
+    }
+
+    /// Turns full canvases into minimal delta frames that only blend over
+    /// what is already shown. Unchanged pixels inside a delta become
+    /// transparent; where a pixel must get more transparent, the frame before
+    /// clears its rectangle and the delta redraws it. Uses only `Over`,
+    /// `None` and `Background`, which GIF and APNG both express.
+    pub fn into_deltas(&mut self) {
+        self.composite();
+        let len = self.frames.len();
+        if len == 0 {
+            self.regions = Some(vec![]);
+            return;
+        }
+
+        let full = Rect::new(0, 0, self.width, self.height);
+        let diffs = self
+            .frames
+            .windows(2)
+            .map(|pair| {
+                (
+                    diff_bounds(&pair[0], &pair[1]),
+                    needs_clear(&pair[0], &pair[1]),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut rects = Vec::with_capacity(len);
+        let mut clears = vec![false; len];
+        for i in 0..len {
+            let mut rect = match i {
+                0 => Some(full),
+                _ => diffs[i - 1].0,
+            };
+            if clears[i] {
+                let prev = rects[i - 1];
+                rect = Some(rect.map_or(prev, |r: Rect| r.union(prev)));
+            }
+            // the next frame needs its changed pixels cleared by this one
+            if let Some((Some(next_diff), true)) = diffs.get(i) {
+                clears[i + 1] = true;
+                rect = Some(rect.map_or(*next_diff, |r| r.union(*next_diff)));
+            }
+            rects.push(rect.unwrap_or(Rect::new(0, 0, 1, 1)));
+        }
+
+        let frames = std::mem::take(&mut self.frames);
+        let mut regions = Vec::with_capacity(len);
+        for (i, rect) in rects.iter().enumerate() {
+            let cleared = clears[i].then(|| rects[i - 1]);
+            let next = &frames[i];
+            let delta = RgbaImage::from_fn(rect.width, rect.height, |dx, dy| {
+                let (x, y) = (rect.x + dx, rect.y + dy);
+                let p = *next.get_pixel(x, y);
+                let keep = i == 0
+                    || cleared.is_some_and(|c| contains(c, x, y))
+                    || !same_pixel(frames[i - 1].get_pixel(x, y), &p);
+                if keep { p } else { Rgba([0, 0, 0, 0]) }
+            });
+
+            self.frames.push(delta);
+            regions.push(FrameRegion {
+                x: rect.x,
+                y: rect.y,
+                blend: BlendOp::Over,
+                dispose: match clears.get(i + 1) {
+                    Some(true) => DisposeOp::Background,
+                    _ => DisposeOp::None,
+                },
+            });
+        }
+        self.regions = Some(regions);
+    }
+}
+
+impl RGBA8ImageDataType {
+    pub fn composite(&mut self) {
+        if let Self::Animated(ani_img) = self {
+            ani_img.composite();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::animation;
+
+    #[test]
+    fn test_composite_regions() {
+        let mut ani_img = animation(
+            vec![
+                RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])),
+                RgbaImage::from_pixel(2, 2, Rgba([0, 0, 255, 128])),
+                RgbaImage::from_pixel(1, 1, Rgba([0, 255, 0, 255])),
+            ],
+            vec![100; 3],
+        );
+        ani_img.regions = Some(vec![
+            FrameRegion::default(),
+            FrameRegion {
+                x: 1,
+                y: 1,
+                blend: BlendOp::Over,
+                dispose: DisposeOp::Previous,
+            },
+            // runs off the canvas, clipped rather than rejected
+            FrameRegion {
+                x: 3,
+                y: 3,
+                blend: BlendOp::Source,
+                dispose: DisposeOp::Background,
+            },
+        ]);
+
+        ani_img.composite();
+
+        assert!(ani_img.regions.is_none());
+        assert!(ani_img.frames.iter().all(|f| f.dimensions() == (4, 4)));
+        let blended = ani_img.frames[1].get_pixel(1, 1).0;
+        assert!(
+            blended[0].abs_diff(127) <= 1 && blended[3] == 255,
+            "{:?}",
+            blended
+        );
+        // the blue square was restored away before the third frame
+        assert_eq!(ani_img.frames[2].get_pixel(1, 1), &Rgba([255, 0, 0, 255]));
+        assert_eq!(ani_img.frames[2].get_pixel(3, 3), &Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn test_frame_operations_composite_first() {
+        let frames = vec![
+            RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])),
+            RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255])),
+            RgbaImage::from_pixel(4, 4, Rgba([0, 255, 0, 255])),
+        ];
+        let mut deltas = animation(frames.clone(), vec![100, 10, 10]);
+        deltas.into_deltas();
+
+        let mut ani_img = deltas.clone();
+        ani_img.reverse();
+        assert!(ani_img.regions.is_none());
+        assert!(ani_img.frames.iter().eq(frames.iter().rev()));
+
+        // the two short frames ease into one, keeping the later green
+        let mut ani_img = deltas;
+        ani_img.ease_frames(20);
+        assert!(ani_img.regions.is_none());
+        assert_eq!(ani_img.durations, vec![100, 20]);
+        assert_eq!(ani_img.frames, vec![frames[0].clone(), frames[2].clone()]);
+    }
+
+    #[test]
+    fn test_deltas_round_trip() {
+        let base = RgbaImage::from_fn(6, 5, |x, y| Rgba([x as u8 * 40, y as u8 * 50, 0, 255]));
+        let mut moved = base.clone();
+        moved.put_pixel(2, 3, Rgba([9, 9, 9, 255]));
+        // a pixel fades to translucent, which blending alone can't do
+        let mut faded = moved.clone();
+        faded.put_pixel(5, 0, Rgba([1, 2, 3, 100]));
+        let frames = vec![base.clone(), base, moved, faded];
+
+        let mut ani_img = animation(frames.clone(), vec![100; 4]);
+        ani_img.into_deltas();
+
+        let regions = ani_img.regions.clone().unwrap();
+        // an unchanged frame shrinks to a single transparent pixel
+        assert_eq!(ani_img.frames[1].dimensions(), (1, 1));
+        assert_eq!(ani_img.frames[1].get_pixel(0, 0).0[3], 0);
+        // the move grows to cover the fade, which it then clears
+        assert_eq!((regions[2].x, regions[2].y), (2, 0));
+        assert_eq!(ani_img.frames[2].dimensions(), (4, 4));
+        assert_eq!(regions[2].dispose, DisposeOp::Background);
+
+        ani_img.composite();
+        assert!(ani_img.frames == frames);
+    }
+}
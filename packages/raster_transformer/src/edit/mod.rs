@@ -0,0 +1,236 @@
+use crate::core::{RGBA8AnimatedImageData, RGBA8ImageDataType, RGBA8StaticImageData};
+use crate::error::{Result, TransformError};
+use serde::Deserialize;
+
+/// A span of an animation, `start` inclusive and `end` exclusive; a missing
+/// `end` runs to the last frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "unit", rename_all = "camelCase")]
+pub enum FrameRange {
+    Frames {
+        start: u32,
+        end: Option<u32>,
+    },
+    /// Milliseconds; frames straddling an edge are shortened to fit.
+    Time {
+        start: u32,
+        end: Option<u32>,
+    },
+}
+
+/// Timeline edits, applied to the decoded frames before anything else.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AnimationEdit {
+    pub range: Option<FrameRange>,
+    pub reverse: bool,
+    /// Play forward then backward, after `reverse`.
+    pub boomerang: bool,
+    /// 0 loops forever.
+    pub loop_count: Option<u32>,
+}
+
+fn empty_range(range: FrameRange) -> TransformError {
+    TransformError::InvalidInput(format!("{:?} selects no frames", range))
+}
+
+impl RGBA8AnimatedImageData {
+    pub fn trim_frames(&mut self, range: FrameRange) -> Result<()> {
+        self.composite();
+        let spans = match range {
+            FrameRange::Frames { start, end } => {
+                let end = end.map_or(self.frames.len(), |end| {
+                    (end as usize).min(self.frames.len())
+                });
+                (start as usize..end)
+                    .map(|i| (i, self.durations[i]))
+                    .collect::<Vec<_>>()
+            }
+            FrameRange::Time { start, end } => {
+                let (start, end) = (u64::from(start), end.map_or(u64::MAX, u64::from));
+                let mut frame_start = 0;
+                self.durations
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, d)| {
+                        let frame_end = frame_start + u64::from(*d);
+                        let overlap = frame_end.min(end).saturating_sub(frame_start.max(start));
+                        frame_start = frame_end;
+                        (overlap > 0).then_some((i, overlap as u32))
+                    })
+                    .collect()
+            }
+        };
+        if spans.is_empty() {
+            return Err(empty_range(range));
+        }
+
+        let mut frames = std::mem::take(&mut self.frames)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.frames = spans
+            .iter()
+            .filter_map(|(i, _)| frames[*i].take())
+            .collect();
+        self.durations = spans.into_iter().map(|(_, d)| d).collect();
+
+        Ok(())
+    }
+
+    pub fn reverse(&mut self) {
+        self.composite();
+        self.frames.reverse();
+        self.durations.reverse();
+    }
+
+    /// Appends the frames backwards, without repeating the first and last
+    /// frame at the turns.
+    pub fn boomerang(&mut self) {
+        self.composite();
+        let len = self.frames.len();
+        if len < 3 {
+            return;
+        }
+
+        let back = (1..len - 1).rev();
+        self.frames.extend(
+            back.clone()
+                .map(|i| self.frames[i].clone())
+                .collect::<Vec<_>>(),
+        );
+        self.durations
+            .extend(back.map(|i| self.durations[i]).collect::<Vec<_>>());
+    }
+
+    /// Index of the frame on screen `time_ms` into the first loop; later
+    /// times give the last frame.
+    pub fn frame_index_at(&self, time_ms: u32) -> usize {
+        let mut frame_end = 0;
+        self.durations
+            .iter()
+            .position(|d| {
+                frame_end += u64::from(*d);
+                frame_end > u64::from(time_ms)
+            })
+            .unwrap_or(self.frames.len().saturating_sub(1))
+    }
+
+    pub fn extract_frame(&self, index: usize) -> Result<RGBA8StaticImageData> {
+        let data = self.frames.get(index).ok_or_else(|| {
+            TransformError::InvalidInput(format!(
+                "frame {} out of range, {} frames",
+                index,
+                self.frames.len()
+            ))
+        })?;
+
+        Ok(RGBA8StaticImageData {
+            data: data.clone(),
+            width: self.width,
+            height: self.height,
+            metadata: self.metadata.clone(),
+        })
+    }
+
+    pub fn edit(&mut self, edit: &AnimationEdit) -> Result<()> {
+        if let Some(range) = edit.range {
+            self.trim_frames(range)?;
+        }
+        if edit.reverse {
+            self.reverse();
+        }
+        if edit.boomerang {
+            self.boomerang();
+        }
+        if let Some(loop_count) = edit.loop_count {
+            self.loop_count = loop_count;
+        }
+        Ok(())
+    }
+}
+
+impl RGBA8ImageDataType {
+    /// Static images have nothing to edit.
+    pub fn edit(&mut self, edit: &AnimationEdit) -> Result<()> {
+        match self {
+            Self::Animated(ani_img) => ani_img.edit(edit),
+            Self::Static(_) => Ok(()),
+        }
+    }
+
+    /// The frame at `index` as a static image; a static image is its own
+    /// frame 0.
+    pub fn into_frame(self, index: usize) -> Result<RGBA8StaticImageData> {
+        match self {
+            Self::Animated(mut ani_img) => {
+                ani_img.composite();
+                ani_img.extract_frame(index)
+            }
+            Self::Static(st_img) if index == 0 => Ok(st_img),
+            Self::Static(_) => Err(TransformError::InvalidInput(format!(
+                "frame {} out of range, 1 frame",
+                index
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::{frame_ids, numbered_animation};
+
+    #[test]
+    fn test_trim_frames() {
+        let mut by_index = numbered_animation(&[100; 5]);
+        by_index
+            .trim_frames(FrameRange::Frames {
+                start: 1,
+                end: Some(3),
+            })
+            .unwrap();
+        assert_eq!(frame_ids(&by_index), vec![1, 2]);
+
+        // cut the first 150 ms and stop at 420 ms
+        let mut by_time = numbered_animation(&[100; 5]);
+        by_time
+            .trim_frames(FrameRange::Time {
+                start: 150,
+                end: Some(420),
+            })
+            .unwrap();
+        assert_eq!(frame_ids(&by_time), vec![1, 2, 3, 4]);
+        assert_eq!(by_time.durations, vec![50, 100, 100, 20]);
+
+        let err = numbered_animation(&[100; 2])
+            .trim_frames(FrameRange::Frames {
+                start: 2,
+                end: None,
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), "InvalidInput");
+    }
+
+    #[test]
+    fn test_reverse_boomerang_and_extract() {
+        let mut ani_img = numbered_animation(&[10, 20, 30, 40]);
+        ani_img
+            .edit(&AnimationEdit {
+                reverse: true,
+                boomerang: true,
+                loop_count: Some(3),
+                ..AnimationEdit::default()
+            })
+            .unwrap();
+
+        assert_eq!(frame_ids(&ani_img), vec![3, 2, 1, 0, 1, 2]);
+        assert_eq!(ani_img.durations, vec![40, 30, 20, 10, 20, 30]);
+        assert_eq!(ani_img.loop_count, 3);
+
+        assert_eq!(ani_img.frame_index_at(75), 2);
+        let poster = ani_img.extract_frame(ani_img.frame_index_at(75)).unwrap();
+        assert_eq!(poster.data.get_pixel(0, 0).0[0], 1);
+        assert!(ani_img.extract_frame(6).is_err());
+    }
+}
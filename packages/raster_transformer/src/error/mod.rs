@@ -0,0 +1,101 @@
+use crate::format::ImageFormat;
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+pub type Result<T> = std::result::Result<T, TransformError>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TransformError {
+    #[error("unsupported image format: {0}")]
+    UnsupportedFormat(String),
+    #[error("{codec} decode error: {message}")]
+    Decode {
+        codec: ImageFormat,
+        code: Option<i32>,
+        message: String,
+    },
+    #[error("{codec} encode error: {message}")]
+    Encode {
+        codec: ImageFormat,
+        code: Option<i32>,
+        message: String,
+    },
+    #[error("mux error: {message}")]
+    Mux { code: Option<i32>, message: String },
+    #[error("invalid dimensions: {width}x{height}")]
+    InvalidDimensions { width: u32, height: u32 },
+    #[error("limit exceeded: {limit} is {value}, max {max}")]
+    LimitExceeded { limit: String, value: u64, max: u64 },
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("colour profile error: {0}")]
+    ColorProfile(String),
+}
+
+impl TransformError {
+    pub fn decode(codec: ImageFormat, message: impl ToString) -> Self {
+        Self::Decode {
+            codec,
+            code: None,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn encode(codec: ImageFormat, message: impl ToString) -> Self {
+        Self::Encode {
+            codec,
+            code: None,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::UnsupportedFormat(_) => "UnsupportedFormat",
+            Self::Decode { .. } => "Decode",
+            Self::Encode { .. } => "Encode",
+            Self::Mux { .. } => "Mux",
+            Self::InvalidDimensions { .. } => "InvalidDimensions",
+            Self::LimitExceeded { .. } => "LimitExceeded",
+            Self::InvalidInput(_) => "InvalidInput",
+            Self::ColorProfile(_) => "ColorProfile",
+        }
+    }
+}
+
+fn set_field(target: &JsValue, key: &str, value: impl Into<JsValue>) {
+    // setting a property on a fresh `Error` object cannot fail
+    let _ = js_sys::Reflect::set(target, &key.into(), &value.into());
+}
+
+/// Thrown to JS as an `Error` carrying a `kind` discriminant plus the
+/// variant's fields, so callers can branch without parsing messages.
+impl From<TransformError> for JsValue {
+    fn from(err: TransformError) -> Self {
+        let js_err: JsValue = js_sys::Error::new(&err.to_string()).into();
+        set_field(&js_err, "kind", err.kind());
+
+        match err {
+            TransformError::Decode { codec, code, .. }
+            | TransformError::Encode { codec, code, .. } => {
+                set_field(&js_err, "codec", codec.extname());
+                set_field(&js_err, "code", code);
+            }
+            TransformError::Mux { code, .. } => set_field(&js_err, "code", code),
+            TransformError::InvalidDimensions { width, height } => {
+                set_field(&js_err, "width", width);
+                set_field(&js_err, "height", height);
+            }
+            TransformError::LimitExceeded { limit, value, max } => {
+                set_field(&js_err, "limit", limit);
+                set_field(&js_err, "value", value as f64);
+                set_field(&js_err, "max", max as f64);
+            }
+            TransformError::UnsupportedFormat(_)
+            | TransformError::InvalidInput(_)
+            | TransformError::ColorProfile(_) => {}
+        }
+
+        js_err
+    }
+}
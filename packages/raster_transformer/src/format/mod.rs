@@ -0,0 +1,338 @@
+use crate::error::{Result, TransformError};
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    WebP,
+    Png,
+    Gif,
+    Avif,
+}
+
+impl ImageFormat {
+    /// Accepts a bare extension (`webp`), a dotted one (`.webp`) or a file name.
+    pub fn from_extname(extname: &str) -> Result<Self> {
+        let ext = extname.rsplit('.').next().unwrap_or(extname);
+
+        match ext.to_ascii_lowercase().as_str() {
+            "webp" => Ok(Self::WebP),
+            "png" | "apng" => Ok(Self::Png),
+            "gif" => Ok(Self::Gif),
+            "avif" => Ok(Self::Avif),
+            _ => Err(TransformError::UnsupportedFormat(extname.into())),
+        }
+    }
+
+    pub fn extname(&self) -> &'static str {
+        match self {
+            Self::WebP => ".webp",
+            Self::Png => ".png",
+            Self::Gif => ".gif",
+            Self::Avif => ".avif",
+        }
+    }
+
+    /// Whether this build can read the format. AVIF input needs rav1d, which
+    /// has no wasm32 build, so the wasm package only writes AVIF.
+    pub fn can_decode(&self) -> bool {
+        !matches!(self, Self::Avif) || cfg!(not(target_arch = "wasm32"))
+    }
+
+    /// Prefers the sniffed container format and only falls back to `hint`
+    /// (usually derived from the file extension) when the header is unknown.
+    pub fn detect(data: &[u8], hint: Option<ImageFormat>) -> Result<Self> {
+        ImageFormatInfo::sniff(data)
+            .map(|info| info.format)
+            .or(hint)
+            .ok_or_else(|| TransformError::UnsupportedFormat("unrecognized file header".into()))
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::WebP => "WebP",
+            Self::Png => "PNG",
+            Self::Gif => "GIF",
+            Self::Avif => "AVIF",
+        };
+        f.write_str(name)
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageFormatInfo {
+    pub format: ImageFormat,
+    pub animated: bool,
+}
+
+#[wasm_bindgen]
+impl ImageFormatInfo {
+    #[wasm_bindgen(getter)]
+    pub fn extname(&self) -> String {
+        self.format.extname().into()
+    }
+
+    /// See [`ImageFormat::can_decode`].
+    #[wasm_bindgen(getter)]
+    pub fn decodable(&self) -> bool {
+        self.format.can_decode()
+    }
+}
+
+impl ImageFormatInfo {
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        sniff_webp(data)
+            .or_else(|| sniff_png(data))
+            .or_else(|| sniff_gif(data))
+            .or_else(|| sniff_avif(data))
+    }
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// `RIFF....WEBP`, animated when the `VP8X` header sets the animation flag.
+fn sniff_webp(data: &[u8]) -> Option<ImageFormatInfo> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+
+    let animated =
+        data.get(12..16) == Some(b"VP8X") && data.get(20).is_some_and(|flags| flags & 0x02 != 0);
+
+    Some(ImageFormatInfo {
+        format: ImageFormat::WebP,
+        animated,
+    })
+}
+
+/// PNG signature, animated when an `acTL` chunk precedes the first `IDAT`.
+fn sniff_png(data: &[u8]) -> Option<ImageFormatInfo> {
+    if data.get(0..8)? != b"\x89PNG\r\n\x1a\n" {
+        return None;
+    }
+
+    let mut animated = false;
+    let mut offset = 8;
+    while let Some(len) = read_u32_be(data, offset) {
+        let Some(chunk_type) = data.get(offset + 4..offset + 8) else {
+            break;
+        };
+        match chunk_type {
+            b"acTL" => {
+                animated = true;
+                break;
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => match (len as usize)
+                .checked_add(12)
+                .and_then(|n| offset.checked_add(n))
+            {
+                Some(next) => offset = next,
+                None => break,
+            },
+        }
+    }
+
+    Some(ImageFormatInfo {
+        format: ImageFormat::Png,
+        animated,
+    })
+}
+
+/// `GIF87a`/`GIF89a`, animated when more than one image descriptor is present.
+fn sniff_gif(data: &[u8]) -> Option<ImageFormatInfo> {
+    if data.get(0..6)? != b"GIF87a" && data.get(0..6)? != b"GIF89a" {
+        return None;
+    }
+
+    fn skip_sub_blocks(data: &[u8], mut offset: usize) -> Option<usize> {
+        loop {
+            let len = *data.get(offset)? as usize;
+            offset += 1 + len;
+            if len == 0 {
+                return Some(offset);
+            }
+        }
+    }
+
+    fn color_table_len(flags: u8) -> usize {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 0x07) + 1)
+        } else {
+            0
+        }
+    }
+
+    let count_images = || -> usize {
+        let mut images = 0;
+        let Some(&flags) = data.get(10) else {
+            return images;
+        };
+        let mut offset = 13 + color_table_len(flags);
+        while images < 2 {
+            match data.get(offset) {
+                Some(0x21) => match skip_sub_blocks(data, offset + 2) {
+                    Some(next) => offset = next,
+                    None => break,
+                },
+                Some(0x2C) => {
+                    images += 1;
+                    let Some(&flags) = data.get(offset + 9) else {
+                        break;
+                    };
+                    // descriptor, local colour table, then the LZW minimum code size
+                    match skip_sub_blocks(data, offset + 10 + color_table_len(flags) + 1) {
+                        Some(next) => offset = next,
+                        None => break,
+                    }
+                }
+                _ => break,
+            }
+        }
+        images
+    };
+
+    Some(ImageFormatInfo {
+        format: ImageFormat::Gif,
+        animated: count_images() > 1,
+    })
+}
+
+/// ISOBMFF `ftyp` box listing `avif` or `avis`; the latter marks a sequence.
+fn sniff_avif(data: &[u8]) -> Option<ImageFormatInfo> {
+    if data.get(4..8)? != b"ftyp" {
+        return None;
+    }
+
+    let box_len = (read_u32_be(data, 0)? as usize).min(data.len());
+    // major brand, minor version, then compatible brands
+    let brands = data
+        .get(8..box_len)?
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(i, _)| *i != 1)
+        .map(|(_, brand)| brand)
+        .collect::<Vec<_>>();
+
+    let animated = brands.contains(&&b"avis"[..]);
+    if !animated && !brands.contains(&&b"avif"[..]) {
+        return None;
+    }
+
+    Some(ImageFormatInfo {
+        format: ImageFormat::Avif,
+        animated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::core::fixtures::{solid_animation, still};
+    use crate::core::{RGBA8AnimatedImageData, RGBA8StaticImageData};
+
+    fn animated_image() -> RGBA8AnimatedImageData {
+        solid_animation(&[[255, 0, 0, 255], [0, 255, 0, 255]])
+    }
+
+    fn static_image() -> RGBA8StaticImageData {
+        still(RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255])))
+    }
+
+    #[test]
+    fn test_sniff_encoded_formats() {
+        let cases = [
+            (
+                crate::png::encode_static_png(static_image()).unwrap(),
+                ImageFormat::Png,
+                false,
+            ),
+            (
+                crate::png::encode_animated_png(animated_image()).unwrap(),
+                ImageFormat::Png,
+                true,
+            ),
+            (
+                crate::gif::encode_static_gif(static_image(), 80.0).unwrap(),
+                ImageFormat::Gif,
+                false,
+            ),
+            (
+                crate::gif::encode_animated_gif(animated_image(), 80.0).unwrap(),
+                ImageFormat::Gif,
+                true,
+            ),
+            (
+                crate::webp::encode_static_webp(static_image(), 80.0, &Default::default()).unwrap(),
+                ImageFormat::WebP,
+                false,
+            ),
+            (
+                crate::webp::encode_animated_webp(animated_image(), 80.0, &Default::default())
+                    .unwrap(),
+                ImageFormat::WebP,
+                true,
+            ),
+            (
+                crate::avif::encode_static_avif(static_image(), 80.0).unwrap(),
+                ImageFormat::Avif,
+                false,
+            ),
+        ];
+
+        for (data, format, animated) in cases {
+            assert_eq!(
+                ImageFormatInfo::sniff(&data),
+                Some(ImageFormatInfo { format, animated })
+            );
+        }
+    }
+
+    #[test]
+    fn test_sniff_png_with_oversized_chunk() {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+        data.extend_from_slice(b"tEXt");
+
+        assert_eq!(
+            ImageFormatInfo::sniff(&data),
+            Some(ImageFormatInfo {
+                format: ImageFormat::Png,
+                animated: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_decodable_formats() {
+        for format in [ImageFormat::WebP, ImageFormat::Png, ImageFormat::Gif] {
+            assert!(format.can_decode());
+        }
+        // this test only runs natively, where rav1d is available
+        assert!(ImageFormat::Avif.can_decode());
+    }
+
+    #[test]
+    fn test_detect_prefers_header_over_hint() {
+        let data = crate::png::encode_static_png(static_image()).unwrap();
+
+        assert_eq!(
+            ImageFormat::detect(&data, Some(ImageFormat::WebP)).unwrap(),
+            ImageFormat::Png
+        );
+        assert_eq!(
+            ImageFormat::detect(b"garbage", Some(ImageFormat::Gif)).unwrap(),
+            ImageFormat::Gif
+        );
+        assert!(ImageFormat::detect(b"garbage", None).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::core::{RGBA8AnimatedImageData, RGBA8ImageDataType};
+use crate::error::Result;
+use crate::metrics::mse;
+use crate::timeline::check_rate;
+use image::RgbaImage;
+use serde::Deserialize;
+
+/// Content-aware frame reduction, applied after `min_delay` easing. All
+/// steps keep the total play time: a dropped frame's duration goes to the
+/// frame shown before it.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FrameReduction {
+    /// Merge a frame into the one before it when their RMS difference, in
+    /// premultiplied 0-255 units, is at most this.
+    pub merge_threshold: Option<f32>,
+    /// Keep at most this many frames.
+    pub max_frames: Option<u32>,
+    /// Keep at most this many frames per second of play time.
+    pub max_fps: Option<f32>,
+}
+
+/// RMS difference of the premultiplied RGBA channels, 0 to 255.
+pub fn frame_distance(a: &RgbaImage, b: &RgbaImage) -> f32 {
+    mse(a, b).sqrt() as f32
+}
+
+impl FrameReduction {
+    fn target_frames(&self, total_duration_ms: u64) -> Result<Option<usize>> {
+        let by_fps = match self.max_fps {
+            Some(fps) => {
+                let fps = check_rate("maxFps", fps)?;
+                Some((total_duration_ms as f64 * fps / 1000.0).ceil() as usize)
+            }
+            None => None,
+        };
+        let by_count = self.max_frames.map(|count| count as usize);
+
+        Ok(match (by_fps, by_count) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+        .map(|target| target.max(1)))
+    }
+}
+
+impl RGBA8AnimatedImageData {
+    /// Folds each frame into the last kept one while it stays within
+    /// `threshold`, comparing against the kept frame so slow fades don't
+    /// collapse step by step.
+    pub fn merge_similar_frames(&mut self, threshold: f32) {
+        self.composite();
+        let frames = std::mem::take(&mut self.frames);
+        let durations = std::mem::take(&mut self.durations);
+
+        for (frame, duration) in frames.into_iter().zip(durations) {
+            match (self.frames.last(), self.durations.last_mut()) {
+                (Some(kept), Some(kept_duration)) if frame_distance(kept, &frame) <= threshold => {
+                    *kept_duration = kept_duration.saturating_add(duration);
+                }
+                _ => {
+                    self.frames.push(frame);
+                    self.durations.push(duration);
+                }
+            }
+        }
+    }
+
+    /// Drops frames until at most `target` remain, always the one closest to
+    /// the frame before it, so the most distinct frames survive. The first
+    /// frame is always kept.
+    pub fn decimate_frames(&mut self, target: usize) {
+        self.composite();
+        let target = target.max(1);
+        if self.frames.len() <= target {
+            return;
+        }
+
+        // distance to the previous kept frame; the first frame can't go
+        let mut distances = std::iter::once(f32::INFINITY)
+            .chain(
+                self.frames
+                    .windows(2)
+                    .map(|pair| frame_distance(&pair[0], &pair[1])),
+            )
+            .collect::<Vec<_>>();
+
+        while self.frames.len() > target {
+            let (index, _) = distances
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .unwrap();
+
+            self.frames.remove(index);
+            let duration = self.durations.remove(index);
+            self.durations[index - 1] = self.durations[index - 1].saturating_add(duration);
+            distances.remove(index);
+
+            if let Some(next) = self.frames.get(index) {
+                distances[index] = frame_distance(&self.frames[index - 1], next);
+            }
+        }
+    }
+
+    pub fn reduce_frames(&mut self, reduction: &FrameReduction) -> Result<()> {
+        if let Some(threshold) = reduction.merge_threshold {
+            self.merge_similar_frames(threshold);
+        }
+
+        let total_duration_ms = self.durations.iter().map(|d| u64::from(*d)).sum();
+        if let Some(target) = reduction.target_frames(total_duration_ms)? {
+            self.decimate_frames(target);
+        }
+        Ok(())
+    }
+}
+
+impl RGBA8ImageDataType {
+    pub fn reduce_frames(&mut self, reduction: &FrameReduction) -> Result<()> {
+        match self {
+            Self::Animated(ani_img) => ani_img.reduce_frames(reduction),
+            Self::Static(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::core::fixtures::solid_animation;
+
+    #[test]
+    fn test_merge_similar_frames() {
+        let mut ani_img = solid_animation(&[
+            [200, 0, 0, 255],
+            [202, 0, 0, 255],
+            [204, 0, 0, 255],
+            [0, 0, 200, 255],
+        ]);
+
+        ani_img.merge_similar_frames(3.0);
+
+        assert_eq!(ani_img.durations, vec![300, 100]);
+        assert_eq!(ani_img.frames[1].get_pixel(0, 0), &Rgba([0, 0, 200, 255]));
+    }
+
+    #[test]
+    fn test_decimate_keeps_distinct_frames() {
+        let mut ani_img = solid_animation(&[
+            [255, 0, 0, 255],
+            [250, 0, 0, 255],
+            [0, 0, 255, 255],
+            [0, 0, 250, 255],
+            [0, 255, 0, 255],
+        ]);
+
+        // 500ms at 6fps leaves 3 frames
+        ani_img
+            .reduce_frames(&FrameReduction {
+                max_fps: Some(6.0),
+                ..FrameReduction::default()
+            })
+            .unwrap();
+
+        assert_eq!(ani_img.durations, vec![200, 200, 100]);
+        let firsts = ani_img
+            .frames
+            .iter()
+            .map(|f| f.get_pixel(0, 0).0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            firsts,
+            vec![[255, 0, 0, 255], [0, 0, 255, 255], [0, 255, 0, 255]]
+        );
+    }
+
+    #[test]
+    fn test_reduce_rejects_bad_fps() {
+        for max_fps in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let mut ani_img = solid_animation(&[[255, 0, 0, 255], [0, 0, 255, 255]]);
+            let err = ani_img
+                .reduce_frames(&FrameReduction {
+                    max_fps: Some(max_fps),
+                    ..FrameReduction::default()
+                })
+                .unwrap_err();
+            assert_eq!(err.kind(), "InvalidInput");
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use crate::core::{RGBA8ImageDataType, RGBA8StaticImageData};
+use crate::error::{Result, TransformError};
+use image::metadata::Orientation;
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Self::new(x, y, right - x, bottom - y)
+    }
+
+    fn check(&self, width: u32, height: u32) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(TransformError::InvalidDimensions {
+                width: self.width,
+                height: self.height,
+            });
+        }
+        if u64::from(self.x) + u64::from(self.width) > u64::from(width)
+            || u64::from(self.y) + u64::from(self.height) > u64::from(height)
+        {
+            return Err(TransformError::InvalidInput(format!(
+                "crop {}x{}+{}+{} is outside the {}x{} image",
+                self.width, self.height, self.x, self.y, width, height
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TrimOptions {
+    /// Border colour to trim as `[r, g, b, a]`; `None` takes the top-left
+    /// pixel of the first frame. Any colour with alpha 0 trims transparency
+    /// regardless of the hidden RGB.
+    pub color: Option<[u8; 4]>,
+    /// Largest per-channel difference still counted as border.
+    pub tolerance: u8,
+}
+
+impl TrimOptions {
+    fn is_border(&self, color: Rgba<u8>, p: &Rgba<u8>) -> bool {
+        if color.0[3] == 0 {
+            return p.0[3] <= self.tolerance;
+        }
+        color
+            .0
+            .iter()
+            .zip(p.0.iter())
+            .all(|(a, b)| a.abs_diff(*b) <= self.tolerance)
+    }
+
+    fn bounds(&self, img: &RgbaImage, color: Rgba<u8>) -> Option<Rect> {
+        let (width, height) = img.dimensions();
+        let mut bounds: Option<Rect> = None;
+
+        for y in 0..height {
+            let mut row = (0..width).filter(|x| !self.is_border(color, img.get_pixel(*x, y)));
+            let (Some(left), Some(right)) = (row.clone().next(), row.next_back()) else {
+                continue;
+            };
+            let rect = Rect::new(left, y, right - left + 1, 1);
+            bounds = Some(bounds.map_or(rect, |bounds| bounds.union(rect)));
+        }
+
+        bounds
+    }
+}
+
+/// Grow the canvas to `width`x`height`; sides that are already larger are
+/// left alone. The image is centred unless `x`/`y` are given.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PadSpec {
+    pub width: u32,
+    pub height: u32,
+    pub x: Option<u32>,
+    pub y: Option<u32>,
+    /// `[r, g, b, a]`, transparent by default.
+    pub color: [u8; 4],
+}
+
+/// Position of the orientation value in IFD0 of a raw EXIF block, with or
+/// without the `Exif\0\0` prefix JPEG and some WebP writers keep, and
+/// whether the block is little-endian.
+fn exif_orientation_offset(exif: &[u8]) -> Option<(usize, bool)> {
+    let prefix = if exif.starts_with(b"Exif\0\0") { 6 } else { 0 };
+    let tiff = &exif[prefix..];
+
+    let little_endian = if tiff.starts_with(b"II*\0") {
+        true
+    } else if tiff.starts_with(b"MM\0*") {
+        false
+    } else {
+        return None;
+    };
+
+    let read_u16 = |bytes: &[u8]| -> Option<u16> {
+        let bytes: [u8; 2] = bytes.get(..2)?.try_into().ok()?;
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let read_u32 = |bytes: &[u8]| -> Option<u32> {
+        let bytes: [u8; 4] = bytes.get(..4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    let ifd_offset = read_u32(tiff.get(4..)?)? as usize;
+    let entries = read_u16(tiff.get(ifd_offset..)?)?;
+
+    (0..usize::from(entries))
+        .map(|i| ifd_offset + 2 + i * 12)
+        .take_while(|offset| offset + 12 <= tiff.len())
+        // tag 0x112, type SHORT, count 1
+        .find(|&offset| {
+            read_u16(&tiff[offset..]) == Some(0x112) && read_u16(&tiff[offset + 2..]) == Some(3)
+        })
+        .map(|offset| (prefix + offset + 8, little_endian))
+}
+
+pub fn exif_orientation(exif: &[u8]) -> Option<Orientation> {
+    let (offset, little_endian) = exif_orientation_offset(exif)?;
+    let value = if little_endian {
+        exif[offset]
+    } else {
+        exif[offset + 1]
+    };
+    Orientation::from_exif(value)
+}
+
+/// Marks the EXIF block as upright, after the orientation has been applied
+/// to the pixels, so viewers don't rotate the output a second time.
+pub fn reset_exif_orientation(exif: &mut [u8]) {
+    if let Some((offset, little_endian)) = exif_orientation_offset(exif) {
+        let value = if little_endian { [1, 0] } else { [0, 1] };
+        exif[offset..offset + 2].copy_from_slice(&value);
+    }
+}
+
+fn crop_frame(img: &RgbaImage, rect: Rect) -> RgbaImage {
+    image::imageops::crop_imm(img, rect.x, rect.y, rect.width, rect.height).to_image()
+}
+
+fn pad_frame(
+    img: &RgbaImage,
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    color: Rgba<u8>,
+) -> RgbaImage {
+    let mut canvas = RgbaImage::from_pixel(width, height, color);
+    image::imageops::replace(&mut canvas, img, i64::from(x), i64::from(y));
+    canvas
+}
+
+impl RGBA8ImageDataType {
+    fn frames(&self) -> &[RgbaImage] {
+        match self {
+            Self::Static(st_img) => std::slice::from_ref(&st_img.data),
+            Self::Animated(ani_img) => &ani_img.frames,
+        }
+    }
+
+    fn map_frames(&mut self, width: u32, height: u32, f: impl Fn(&RgbaImage) -> RgbaImage) {
+        match self {
+            Self::Static(RGBA8StaticImageData {
+                data,
+                width: w,
+                height: h,
+                ..
+            }) => {
+                *data = f(data);
+                (*w, *h) = (width, height);
+            }
+            Self::Animated(ani_img) => {
+                ani_img.composite();
+                ani_img.frames = ani_img.frames.iter().map(f).collect();
+                (ani_img.width, ani_img.height) = (width, height);
+            }
+        }
+    }
+
+    /// Clockwise; `degrees` must be a multiple of 90.
+    pub fn rotate(&mut self, degrees: u32) -> Result<()> {
+        let orientation = match degrees % 360 {
+            0 => Orientation::NoTransforms,
+            90 => Orientation::Rotate90,
+            180 => Orientation::Rotate180,
+            270 => Orientation::Rotate270,
+            _ => {
+                return Err(TransformError::InvalidInput(format!(
+                    "rotation must be a multiple of 90 degrees, got {}",
+                    degrees
+                )));
+            }
+        };
+        self.apply_orientation(orientation);
+        Ok(())
+    }
+
+    pub fn flip_horizontal(&mut self) {
+        self.apply_orientation(Orientation::FlipHorizontal);
+    }
+
+    pub fn flip_vertical(&mut self) {
+        self.apply_orientation(Orientation::FlipVertical);
+    }
+
+    pub fn apply_orientation(&mut self, orientation: Orientation) {
+        use image::imageops::{flip_horizontal, flip_vertical, rotate90, rotate180, rotate270};
+
+        let (width, height) = self.dimensions();
+        let (width, height) = match orientation {
+            Orientation::NoTransforms => return,
+            Orientation::Rotate180 | Orientation::FlipHorizontal | Orientation::FlipVertical => {
+                (width, height)
+            }
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => (height, width),
+        };
+
+        self.map_frames(width, height, |f| match orientation {
+            Orientation::NoTransforms => f.clone(),
+            Orientation::Rotate90 => rotate90(f),
+            Orientation::Rotate180 => rotate180(f),
+            Orientation::Rotate270 => rotate270(f),
+            Orientation::FlipHorizontal => flip_horizontal(f),
+            Orientation::FlipVertical => flip_vertical(f),
+            Orientation::Rotate90FlipH => flip_horizontal(&rotate90(f)),
+            Orientation::Rotate270FlipH => flip_horizontal(&rotate270(f)),
+        });
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Self::Static(st_img) => (st_img.width, st_img.height),
+            Self::Animated(ani_img) => (ani_img.width, ani_img.height),
+        }
+    }
+
+    pub fn crop(&mut self, rect: Rect) -> Result<()> {
+        let (width, height) = self.dimensions();
+        rect.check(width, height)?;
+
+        self.map_frames(rect.width, rect.height, |f| crop_frame(f, rect));
+        Ok(())
+    }
+
+    /// Content bounds across all frames, so trimmed animations stay aligned.
+    /// `None` when every frame is entirely border.
+    pub fn trim_bounds(&self, options: &TrimOptions) -> Option<Rect> {
+        let frames = self.frames();
+        let color = options
+            .color
+            .map(Rgba)
+            .or_else(|| frames.first().map(|f| *f.get_pixel(0, 0)))?;
+
+        frames
+            .iter()
+            .filter_map(|f| options.bounds(f, color))
+            .reduce(Rect::union)
+    }
+
+    /// Crops to [`Self::trim_bounds`]; images with no content are kept as is.
+    pub fn trim(&mut self, options: &TrimOptions) -> Result<()> {
+        self.composite();
+        match self.trim_bounds(options) {
+            Some(rect) => self.crop(rect),
+            None => Ok(()),
+        }
+    }
+
+    pub fn pad(&mut self, spec: &PadSpec) -> Result<()> {
+        let (width, height) = self.dimensions();
+        let next_width = spec.width.max(width);
+        let next_height = spec.height.max(height);
+
+        let x = spec.x.unwrap_or((next_width - width) / 2);
+        let y = spec.y.unwrap_or((next_height - height) / 2);
+        if u64::from(x) + u64::from(width) > u64::from(next_width)
+            || u64::from(y) + u64::from(height) > u64::from(next_height)
+        {
+            return Err(TransformError::InvalidInput(format!(
+                "{}x{} image at +{}+{} does not fit the {}x{} padding",
+                width, height, x, y, next_width, next_height
+            )));
+        }
+        if (next_width, next_height) == (width, height) {
+            return Ok(());
+        }
+
+        let color = Rgba(spec.color);
+        self.map_frames(next_width, next_height, |f| {
+            pad_frame(f, next_width, next_height, x, y, color)
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::animation;
+
+    fn sticker(offsets: &[(u32, u32)]) -> RGBA8ImageDataType {
+        let frames = offsets
+            .iter()
+            .map(|&(ox, oy)| {
+                RgbaImage::from_fn(10, 10, |x, y| {
+                    if (ox..ox + 2).contains(&x) && (oy..oy + 2).contains(&y) {
+                        Rgba([255, 0, 0, 255])
+                    } else {
+                        Rgba([0, 0, 0, 0])
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let durations = vec![100; frames.len()];
+        RGBA8ImageDataType::Animated(animation(frames, durations))
+    }
+
+    #[test]
+    fn test_trim_uses_union_of_frames() {
+        let mut img = sticker(&[(2, 3), (5, 6)]);
+
+        assert_eq!(
+            img.trim_bounds(&TrimOptions::default()),
+            Some(Rect::new(2, 3, 5, 5))
+        );
+
+        img.trim(&TrimOptions::default()).unwrap();
+        assert_eq!(img.dimensions(), (5, 5));
+        assert_eq!(img.frames()[1].get_pixel(3, 3), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_crop_and_pad() {
+        let mut img = sticker(&[(0, 0)]);
+
+        assert_eq!(
+            img.crop(Rect::new(8, 8, 4, 4)).unwrap_err().kind(),
+            "InvalidInput"
+        );
+
+        img.crop(Rect::new(0, 0, 4, 2)).unwrap();
+        img.pad(&PadSpec {
+            width: 8,
+            height: 4,
+            color: [0, 0, 255, 255],
+            ..PadSpec::default()
+        })
+        .unwrap();
+
+        assert_eq!(img.dimensions(), (8, 4));
+        assert_eq!(img.frames()[0].get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+        assert_eq!(img.frames()[0].get_pixel(2, 1), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_rotate_and_exif_orientation() {
+        let mut img = sticker(&[(0, 0), (8, 0)]);
+        img.crop(Rect::new(0, 0, 10, 4)).unwrap();
+
+        img.rotate(90).unwrap();
+        assert_eq!(img.dimensions(), (4, 10));
+        assert!(img.frames().iter().all(|f| f.dimensions() == (4, 10)));
+        // top-left corner of the first frame ends up top-right
+        assert_eq!(img.frames()[0].get_pixel(3, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(img.rotate(45).unwrap_err().kind(), "InvalidInput");
+
+        let exif = [
+            b"Exif\0\0MM\0*".as_slice(),
+            &[0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0],
+        ]
+        .concat();
+        assert_eq!(exif_orientation(&exif), Some(Orientation::Rotate90));
+        let mut exif = exif;
+        reset_exif_orientation(&mut exif);
+        assert_eq!(exif_orientation(&exif), Some(Orientation::NoTransforms));
+        assert_eq!(exif_orientation(b"garbage"), None);
+    }
+}
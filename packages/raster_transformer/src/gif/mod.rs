@@ -0,0 +1,398 @@
+use crate::core::{
+    BlendOp, DisposeOp, FrameRegion, RGBA8AnimatedImageData, RGBA8ImageDataType,
+    RGBA8StaticImageData,
+};
+use crate::delta::Compositor;
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::limits::{DecodeLimits, LimitTracker};
+use crate::metadata::ImageMetadata;
+use crate::stream::{AnimationInfo, FrameSink};
+use color_quant::NeuQuant;
+use gif::{
+    ColorOutput, DecodeOptions, Decoder, DisposalMethod, Encoder, Frame, MemoryLimit, Repeat,
+};
+use image::{Rgba, RgbaImage};
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::num::NonZeroU64;
+
+/// Pixels below this alpha are written as the transparent palette entry.
+pub const GIF_ALPHA_THRESHOLD: u8 = 128;
+
+/// The NETSCAPE extension counts repetitions after the first play, while the
+/// shared model counts plays with `0` meaning infinite.
+pub fn gif_loop_count(repeat: Repeat) -> u32 {
+    match repeat {
+        Repeat::Infinite => 0,
+        Repeat::Finite(n) => u32::from(n) + 1,
+    }
+}
+
+pub fn gif_repeat(loop_count: u32) -> Option<Repeat> {
+    match loop_count {
+        0 => Some(Repeat::Infinite),
+        1 => None,
+        n => Some(Repeat::Finite(u16::try_from(n - 1).unwrap_or(u16::MAX))),
+    }
+}
+
+/// Maps `quality` (0-100) onto the NeuQuant sampling factor (30-1).
+pub fn gif_sample_factor(quality: f32) -> i32 {
+    (30.0 - quality.clamp(0.0, 100.0) * 29.0 / 100.0).round() as i32
+}
+
+type GifDecoder<'a> = Decoder<Cursor<&'a [u8]>>;
+
+fn gif_decoder<'a>(data: &'a [u8], limits: &DecodeLimits) -> Result<GifDecoder<'a>> {
+    let mut options = DecodeOptions::new();
+    options.set_color_output(ColorOutput::RGBA);
+    if let Some(bytes) = limits
+        .max_pixels
+        .and_then(|pixels| NonZeroU64::new(pixels.saturating_mul(4)))
+    {
+        options.set_memory_limit(MemoryLimit::Bytes(bytes));
+    }
+
+    options
+        .read_info(Cursor::new(data))
+        .map_err(gif_decode_error)
+}
+
+/// The next frame as stored, with its delay in ms. The frame header is
+/// checked against `tracker` before its pixel buffer is allocated.
+fn read_gif_frame(
+    decoder: &mut GifDecoder,
+    tracker: &mut LimitTracker,
+) -> Result<Option<(RgbaImage, FrameRegion, u32)>> {
+    let Some(frame) = decoder.next_frame_info().map_err(gif_decode_error)? else {
+        return Ok(None);
+    };
+
+    let width = u32::from(frame.width);
+    let height = u32::from(frame.height);
+    let duration = u32::from(frame.delay) * 10;
+    let region = FrameRegion {
+        x: u32::from(frame.left),
+        y: u32::from(frame.top),
+        blend: BlendOp::Over,
+        dispose: match frame.dispose {
+            DisposalMethod::Background => DisposeOp::Background,
+            DisposalMethod::Previous => DisposeOp::Previous,
+            DisposalMethod::Any | DisposalMethod::Keep => DisposeOp::None,
+        },
+    };
+
+    tracker.frame(width, height, duration)?;
+    // `buffer_size` panics where this overflows, e.g. on wasm32
+    let size = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(4))
+        .ok_or(TransformError::InvalidDimensions { width, height })?;
+    let mut buf = vec![0; size];
+    decoder
+        .read_into_buffer(&mut buf)
+        .map_err(gif_decode_error)?;
+
+    let data = RgbaImage::from_raw(width, height, buf)
+        .ok_or_else(|| TransformError::decode(ImageFormat::Gif, "truncated frame"))?;
+
+    Ok(Some((data, region, duration)))
+}
+
+/// Decodes and composites one frame at a time into `sink`, holding a single
+/// canvas.
+pub fn stream_gif_frames(
+    data: &[u8],
+    limits: &DecodeLimits,
+    sink: &mut dyn FrameSink,
+) -> Result<AnimationInfo> {
+    let mut decoder = gif_decoder(data, limits)?;
+    let width = u32::from(decoder.width());
+    let height = u32::from(decoder.height());
+    let mut tracker = limits.tracker(width, height)?;
+
+    let mut compositor = Compositor::new(width, height);
+    let mut frame_count = 0;
+
+    while let Some((data, region, duration)) = read_gif_frame(&mut decoder, &mut tracker)? {
+        sink.frame(compositor.render(&data, &region), duration)?;
+        frame_count += 1;
+    }
+    if frame_count == 0 {
+        return Err(TransformError::decode(ImageFormat::Gif, "no frames"));
+    }
+    sink.flush()?;
+
+    let bg_color = decoder
+        .bg_color()
+        .and_then(|i| decoder.global_palette()?.get(i * 3..i * 3 + 3))
+        .map(|c| Rgba([c[0], c[1], c[2], 255]))
+        .unwrap_or(Rgba([255, 255, 255, 0]));
+
+    Ok(AnimationInfo {
+        loop_count: gif_loop_count(decoder.repeat()),
+        bg_color,
+    })
+}
+
+pub fn decode_gif(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8ImageDataType> {
+    let mut image_data = decode_gif_regions(data, limits)?;
+    image_data.composite();
+
+    Ok(image_data)
+}
+
+/// Like `decode_gif`, but animation frames stay the sub-rectangles stored in
+/// the file. Transparent pixels leave the canvas alone, which is `Over` for
+/// GIF's all-or-nothing alpha.
+pub fn decode_gif_regions(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8ImageDataType> {
+    let mut decoder = gif_decoder(data, limits)?;
+    let width = u32::from(decoder.width());
+    let height = u32::from(decoder.height());
+    let mut tracker = limits.tracker(width, height)?;
+
+    let bg_color = decoder
+        .bg_color()
+        .and_then(|i| decoder.global_palette()?.get(i * 3..i * 3 + 3))
+        .map(|c| Rgba([c[0], c[1], c[2], 255]))
+        .unwrap_or(Rgba([255, 255, 255, 0]));
+
+    let mut frames = vec![];
+    let mut durations = vec![];
+    let mut regions = vec![];
+
+    while let Some((data, region, duration)) = read_gif_frame(&mut decoder, &mut tracker)? {
+        frames.push(data);
+        durations.push(duration);
+        regions.push(region);
+    }
+
+    let mut ani_img = RGBA8AnimatedImageData {
+        width,
+        height,
+        durations,
+        frames,
+        loop_count: gif_loop_count(decoder.repeat()),
+        bg_color,
+        metadata: ImageMetadata::default(),
+        regions: Some(regions),
+    };
+
+    match ani_img.frames.len() {
+        0 => Err(TransformError::decode(ImageFormat::Gif, "no frames")),
+        1 => {
+            ani_img.composite();
+            Ok(RGBA8ImageDataType::Static(RGBA8StaticImageData {
+                data: ani_img.frames.remove(0),
+                width,
+                height,
+                metadata: ImageMetadata::default(),
+            }))
+        }
+        _ => Ok(RGBA8ImageDataType::Animated(ani_img)),
+    }
+}
+
+pub struct GifPaletteFrame {
+    pub palette: Vec<u8>,
+    pub indices: Vec<u8>,
+    pub transparent: Option<u8>,
+}
+
+/// Quantizes an RGBA8 image to a local palette with NeuQuant and
+/// Floyd-Steinberg error diffusion. Transparency takes the last palette entry.
+pub fn quantize_rgba8(img: &RgbaImage, quality: f32) -> GifPaletteFrame {
+    let width = img.width() as usize;
+    let height = img.height() as usize;
+
+    let opaque = img
+        .pixels()
+        .filter(|p| p.0[3] >= GIF_ALPHA_THRESHOLD)
+        .flat_map(|p| [p.0[0], p.0[1], p.0[2], 255])
+        .collect::<Vec<_>>();
+    let has_transparent = opaque.len() / 4 < width * height;
+
+    let nq = (!opaque.is_empty()).then(|| {
+        let colors = if has_transparent { 255 } else { 256 };
+        NeuQuant::new(gif_sample_factor(quality), colors, &opaque)
+    });
+
+    let mut palette = nq.as_ref().map(|nq| nq.color_map_rgb()).unwrap_or_default();
+    let transparent = has_transparent.then(|| {
+        palette.extend_from_slice(&[0, 0, 0]);
+        (palette.len() / 3 - 1) as u8
+    });
+
+    let mut indices = vec![0u8; width * height];
+    let mut errors = vec![[0f32; 3]; width + 2];
+    let mut next_errors = vec![[0f32; 3]; width + 2];
+
+    for y in 0..height {
+        for x in 0..width {
+            let px = img.get_pixel(x as u32, y as u32).0;
+
+            let Some(nq) = nq.as_ref().filter(|_| px[3] >= GIF_ALPHA_THRESHOLD) else {
+                indices[y * width + x] = transparent.unwrap_or(0);
+                continue;
+            };
+
+            let err = errors[x + 1];
+            let wanted = [0, 1, 2].map(|c| (f32::from(px[c]) + err[c]).clamp(0.0, 255.0));
+            let idx = nq.index_of(&[wanted[0] as u8, wanted[1] as u8, wanted[2] as u8, 255]);
+            indices[y * width + x] = idx as u8;
+
+            let chosen = &palette[idx * 3..idx * 3 + 3];
+            for c in 0..3 {
+                let diff = wanted[c] - f32::from(chosen[c]);
+                errors[x + 2][c] += diff * 7.0 / 16.0;
+                next_errors[x][c] += diff * 3.0 / 16.0;
+                next_errors[x + 1][c] += diff * 5.0 / 16.0;
+                next_errors[x + 2][c] += diff / 16.0;
+            }
+        }
+        std::mem::swap(&mut errors, &mut next_errors);
+        next_errors.iter_mut().for_each(|e| *e = [0.0; 3]);
+    }
+
+    GifPaletteFrame {
+        palette,
+        indices,
+        transparent,
+    }
+}
+
+fn gif_decode_error(e: gif::DecodingError) -> TransformError {
+    TransformError::decode(ImageFormat::Gif, e)
+}
+
+fn gif_encode_error(e: gif::EncodingError) -> TransformError {
+    TransformError::encode(ImageFormat::Gif, e)
+}
+
+fn gif_dimensions(width: u32, height: u32) -> Result<(u16, u16)> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(TransformError::InvalidDimensions { width, height }),
+    }
+}
+
+fn new_gif_frame(
+    img: &RgbaImage,
+    region: &FrameRegion,
+    duration_ms: u32,
+    quality: f32,
+) -> Result<Frame<'static>> {
+    let quantized = quantize_rgba8(img, quality);
+
+    let (width, height) = gif_dimensions(img.width(), img.height())?;
+    let (left, top) = gif_dimensions(region.x, region.y)?;
+
+    Ok(Frame {
+        left,
+        top,
+        width,
+        height,
+        delay: u16::try_from(duration_ms.div_ceil(10)).unwrap_or(u16::MAX),
+        dispose: match region.dispose {
+            DisposeOp::None => DisposalMethod::Keep,
+            DisposeOp::Background => DisposalMethod::Background,
+            DisposeOp::Previous => DisposalMethod::Previous,
+        },
+        transparent: quantized.transparent,
+        palette: Some(quantized.palette),
+        buffer: Cow::Owned(quantized.indices),
+        ..Frame::default()
+    })
+}
+
+/// Writes the minimal delta frames from `RGBA8AnimatedImageData::into_deltas`.
+pub fn encode_animated_gif(
+    mut image_data: RGBA8AnimatedImageData,
+    quality: f32,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    image_data.into_deltas();
+    let regions = image_data.regions.take().unwrap_or_default();
+
+    {
+        let (width, height) = gif_dimensions(image_data.width, image_data.height)?;
+        let mut encoder = Encoder::new(&mut buf, width, height, &[]).map_err(gif_encode_error)?;
+
+        if let Some(repeat) = gif_repeat(image_data.loop_count) {
+            encoder.set_repeat(repeat).map_err(gif_encode_error)?;
+        }
+
+        for ((frame, duration), region) in image_data
+            .frames
+            .iter()
+            .zip(image_data.durations.iter())
+            .zip(regions.iter())
+        {
+            encoder
+                .write_frame(&new_gif_frame(frame, region, *duration, quality)?)
+                .map_err(gif_encode_error)?;
+        }
+    }
+
+    Ok(buf)
+}
+
+pub fn encode_static_gif(image_data: RGBA8StaticImageData, quality: f32) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+
+    {
+        let (width, height) = gif_dimensions(image_data.width, image_data.height)?;
+        let mut encoder = Encoder::new(&mut buf, width, height, &[]).map_err(gif_encode_error)?;
+
+        encoder
+            .write_frame(&new_gif_frame(
+                &image_data.data,
+                &FrameRegion::default(),
+                0,
+                quality,
+            )?)
+            .map_err(gif_encode_error)?;
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::animation;
+
+    #[test]
+    fn test_gif_round_trip() {
+        let mut first = RgbaImage::from_pixel(8, 6, Rgba([255, 0, 0, 255]));
+        first.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+        let second = RgbaImage::from_pixel(8, 6, Rgba([0, 0, 255, 255]));
+
+        let mut ani_img = animation(vec![first, second], vec![50, 120]);
+        ani_img.loop_count = 3;
+
+        let bytes = encode_animated_gif(ani_img, 80.0).unwrap();
+
+        match decode_gif(&bytes, &DecodeLimits::default()).unwrap() {
+            RGBA8ImageDataType::Animated(decoded) => {
+                assert_eq!(decoded.durations, vec![50, 120]);
+                assert_eq!(decoded.loop_count, 3);
+                assert_eq!(decoded.frames[0].get_pixel(0, 0).0[3], 0);
+                assert_eq!(decoded.frames[0].get_pixel(1, 0).0, [255, 0, 0, 255]);
+                assert_eq!(decoded.frames[1].get_pixel(0, 0).0, [0, 0, 255, 255]);
+            }
+            RGBA8ImageDataType::Static(_) => panic!("expected animated gif"),
+        }
+    }
+
+    #[test]
+    fn test_gif_loop_count() {
+        assert_eq!(gif_loop_count(Repeat::Infinite), 0);
+        assert_eq!(gif_loop_count(Repeat::Finite(0)), 1);
+        for loop_count in [0, 1, 5] {
+            let repeat = gif_repeat(loop_count).unwrap_or(Repeat::Finite(0));
+            assert_eq!(gif_loop_count(repeat), loop_count);
+        }
+    }
+}
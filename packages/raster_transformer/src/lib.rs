@@ -1,15 +1,49 @@
+pub mod avif;
+pub mod budget;
+pub mod color;
 pub mod core;
+pub mod delta;
+pub mod edit;
+pub mod error;
+pub mod format;
+pub mod frames;
+pub mod geometry;
+pub mod gif;
+pub mod limits;
+pub mod metadata;
+pub mod metrics;
+pub mod png;
+pub mod poster;
+pub mod resize;
+pub mod stream;
+pub mod timeline;
 mod utils;
 pub mod webp;
 
-use anyhow::Result;
 use base64::{Engine as _, engine::general_purpose};
-use image::AnimationDecoder;
 use wasm_bindgen::prelude::*;
-use webp::decode_webp;
+use webp::{decode_webp, decode_webp_regions};
 
-use crate::core::{RGBA8AnimatedImageData, RGBA8ImageDataType, RGBA8StaticImageData};
-use crate::webp::{encode_animated_webp, encode_static_webp};
+use crate::avif::{decode_avif, encode_animated_avif, encode_static_avif};
+use crate::budget::{BudgetChoice, SizeBudget, transform_within_budget};
+use crate::core::RGBA8ImageDataType;
+use crate::edit::AnimationEdit;
+use crate::error::{Result, TransformError};
+use crate::format::{ImageFormat, ImageFormatInfo};
+use crate::frames::FrameReduction;
+use crate::geometry::{PadSpec, Rect, TrimOptions};
+use crate::gif::{decode_gif, decode_gif_regions, encode_animated_gif, encode_static_gif};
+use crate::limits::DecodeLimits;
+use crate::metadata::MetadataPolicy;
+use crate::metrics::{QualityReport, compare};
+use crate::png::{decode_png, encode_animated_png, encode_static_png};
+use crate::poster::PosterOptions;
+use crate::resize::{ResizeFilter, ResizeSpec};
+use crate::timeline::TimelineOptions;
+use crate::webp::{
+    WebPEncodeOptions, encode_animated_webp, encode_static_webp, inspect_webp_frames,
+};
+use serde::Deserialize;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -17,22 +51,180 @@ use crate::webp::{encode_animated_webp, encode_static_webp};
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct EncodeOptions {
+    pub quality: f32,
+    pub webp: WebPEncodeOptions,
+    /// What to keep of the decoded ICC/EXIF/XMP; GIF and AVIF output carry
+    /// none of it.
+    pub metadata: MetadataPolicy,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            quality: 75.0,
+            webp: WebPEncodeOptions::default(),
+            metadata: MetadataPolicy::default(),
+        }
+    }
+}
+
+impl EncodeOptions {
+    pub fn with_quality(quality: f32) -> Self {
+        Self {
+            quality,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TransformOptions {
+    /// Uniform scale, applied after `resize`.
+    pub scale: f32,
+    pub min_delay: u32,
+    /// Size, frame and duration caps checked while decoding the input.
+    pub limits: DecodeLimits,
+    /// Trim, reverse, boomerang and loop edits, applied first on the decoded
+    /// timeline.
+    pub edit: AnimationEdit,
+    /// Content-aware merging and decimation, after `min_delay` easing.
+    pub frames: FrameReduction,
+    /// Speed, length and frame-rate changes, after frame reduction.
+    pub timeline: TimelineOptions,
+    /// Convert the pixels from the embedded ICC profile or PNG colour chunks
+    /// to sRGB, dropping the tags; runs before any resampling.
+    pub convert_to_srgb: bool,
+    /// Clockwise degrees, a multiple of 90. Rotation and flips run before
+    /// `crop`, so crop coordinates refer to the upright image.
+    pub rotate: u32,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    /// Source rectangle, applied before `trim`.
+    pub crop: Option<Rect>,
+    pub trim: Option<TrimOptions>,
+    /// Target box; `None` keeps the decoded size.
+    pub resize: Option<ResizeSpec>,
+    pub filter: ResizeFilter,
+    /// Applied last, after `scale`.
+    pub pad: Option<PadSpec>,
+    pub encode: EncodeOptions,
+}
+
+impl Default for TransformOptions {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            min_delay: 0,
+            limits: DecodeLimits::default(),
+            edit: AnimationEdit::default(),
+            frames: FrameReduction::default(),
+            timeline: TimelineOptions::default(),
+            convert_to_srgb: false,
+            rotate: 0,
+            flip_horizontal: false,
+            flip_vertical: false,
+            crop: None,
+            trim: None,
+            resize: None,
+            filter: ResizeFilter::default(),
+            pad: None,
+            encode: EncodeOptions::default(),
+        }
+    }
+}
+
+impl TransformOptions {
+    /// Colour conversion, then rotate, flip, crop, trim and fit into the
+    /// `resize` box: everything that does not depend on `scale`.
+    pub fn apply_layout(&self, image_data: &mut RGBA8ImageDataType) -> Result<()> {
+        if self.convert_to_srgb {
+            image_data.convert_to_srgb()?;
+        }
+        image_data.rotate(self.rotate)?;
+        if self.flip_horizontal {
+            image_data.flip_horizontal();
+        }
+        if self.flip_vertical {
+            image_data.flip_vertical();
+        }
+        if let Some(rect) = self.crop {
+            image_data.crop(rect)?;
+        }
+        if let Some(trim) = &self.trim {
+            image_data.trim(trim)?;
+        }
+        if let Some(spec) = &self.resize {
+            image_data.resize_to(spec, self.filter)?;
+        }
+        Ok(())
+    }
+
+    /// Whether every step works on one frame at a time, so
+    /// [`stream::transform_to_webp`] can run the transform without holding the
+    /// whole animation. `trim` needs the union of all frames' content.
+    pub fn is_streamable(&self) -> bool {
+        self.trim.is_none()
+            && self.edit == AnimationEdit::default()
+            && self.frames == FrameReduction::default()
+            && self.timeline.is_identity()
+    }
+
+    pub fn apply_scale(&self, image_data: &mut RGBA8ImageDataType, scale: f32) -> Result<()> {
+        image_data.resize(scale, self.filter);
+        if let Some(pad) = &self.pad {
+            image_data.pad(pad)?;
+        }
+        Ok(())
+    }
+
+    /// Every step between decoding and encoding, with `min_delay` and `scale`
+    /// passed in so [`budget`] can try other values.
+    pub fn apply(
+        &self,
+        image_data: &mut RGBA8ImageDataType,
+        min_delay: u32,
+        scale: f32,
+    ) -> Result<()> {
+        image_data.edit(&self.edit)?;
+        image_data.ease_frames(min_delay);
+        image_data.reduce_frames(&self.frames)?;
+        image_data.retime(&self.timeline)?;
+        self.apply_layout(image_data)?;
+        self.apply_scale(image_data, scale)
+    }
+}
+
 impl RGBA8ImageDataType {
-    pub fn decode(extname: &str, data: &[u8]) -> Result<Self> {
-        if extname.ends_with(".webp") {
-            decode_webp(data)
-        } else if extname.ends_with(".png") {
-            let cursor = std::io::Cursor::new(data);
-
-            let decoded_png_data = image::codecs::png::PngDecoder::new(cursor)?;
-            if decoded_png_data.is_apng()? {
-                let frames = decoded_png_data.apng()?.into_frames().collect_frames()?;
-                RGBA8AnimatedImageData::decode(frames).map(RGBA8ImageDataType::Animated)
-            } else {
-                RGBA8StaticImageData::decode(data).map(RGBA8ImageDataType::Static)
-            }
-        } else {
-            unimplemented!("unsupported image format now!")
+    /// Decodes within the default [`DecodeLimits`].
+    pub fn decode(format: ImageFormat, data: &[u8]) -> Result<Self> {
+        Self::decode_with_limits(format, data, &DecodeLimits::default())
+    }
+
+    pub fn decode_with_limits(
+        format: ImageFormat,
+        data: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<Self> {
+        match format {
+            ImageFormat::WebP => decode_webp(data, limits),
+            ImageFormat::Png => decode_png(data, limits),
+            ImageFormat::Gif => decode_gif(data, limits),
+            ImageFormat::Avif => decode_avif(data, limits),
+        }
+    }
+
+    /// Like `decode`, but WebP and GIF animations keep their frames as the
+    /// sub-rectangles stored in the file; see
+    /// [`core::RGBA8AnimatedImageData::regions`].
+    pub fn decode_regions(format: ImageFormat, data: &[u8]) -> Result<Self> {
+        match format {
+            ImageFormat::WebP => decode_webp_regions(data, &DecodeLimits::default()),
+            ImageFormat::Gif => decode_gif_regions(data, &DecodeLimits::default()),
+            _ => Self::decode(format, data),
         }
     }
 
@@ -43,40 +235,96 @@ impl RGBA8ImageDataType {
         }
     }
 
-    pub fn resize(&mut self, scale: f32) {
+    pub fn resize(&mut self, scale: f32, filter: ResizeFilter) {
         match self {
-            Self::Animated(a) => a.resize(scale),
-            Self::Static(a) => a.resize(scale),
+            Self::Animated(a) => a.resize(scale, filter),
+            Self::Static(a) => a.resize(scale, filter),
         }
     }
 
-    pub fn encode(self, extname: &str, quality: f32) -> Result<Vec<u8>> {
-        if extname.ends_with(".webp") {
-            match self {
-                RGBA8ImageDataType::Animated(ani_img) => encode_animated_webp(ani_img, quality),
-                RGBA8ImageDataType::Static(st_img) => encode_static_webp(st_img, quality),
+    pub fn resize_to(&mut self, spec: &ResizeSpec, filter: ResizeFilter) -> Result<()> {
+        match self {
+            Self::Animated(a) => a.resize_to(spec, filter),
+            Self::Static(a) => a.resize_to(spec, filter),
+        }
+    }
+
+    pub fn encode(mut self, format: ImageFormat, options: &EncodeOptions) -> Result<Vec<u8>> {
+        let quality = options.quality;
+        self.metadata_mut().apply_policy(options.metadata);
+
+        match (format, self) {
+            (ImageFormat::WebP, Self::Animated(ani_img)) => {
+                encode_animated_webp(ani_img, quality, &options.webp)
             }
-        } else if extname.ends_with(".png") {
-            todo!()
-        } else {
-            todo!()
+            (ImageFormat::WebP, Self::Static(st_img)) => {
+                encode_static_webp(st_img, quality, &options.webp)
+            }
+            (ImageFormat::Png, Self::Animated(ani_img)) => encode_animated_png(ani_img),
+            (ImageFormat::Png, Self::Static(st_img)) => encode_static_png(st_img),
+            (ImageFormat::Gif, Self::Animated(ani_img)) => encode_animated_gif(ani_img, quality),
+            (ImageFormat::Gif, Self::Static(st_img)) => encode_static_gif(st_img, quality),
+            (ImageFormat::Avif, Self::Animated(ani_img)) => encode_animated_avif(ani_img, quality),
+            (ImageFormat::Avif, Self::Static(st_img)) => encode_static_avif(st_img, quality),
         }
     }
 }
 
+fn decode_base64(base64_data: &str) -> Result<Vec<u8>> {
+    general_purpose::STANDARD_NO_PAD
+        .decode(base64_data)
+        .map_err(|e| TransformError::InvalidInput(format!("decode base64 error: {}", e)))
+}
+
+fn parse_options<T: Default + for<'de> Deserialize<'de>>(options: JsValue) -> Result<T> {
+    if options.is_undefined() || options.is_null() {
+        return Ok(T::default());
+    }
+
+    serde_wasm_bindgen::from_value(options)
+        .map_err(|e| TransformError::InvalidInput(format!("invalid options: {}", e)))
+}
+
 pub fn transform_one_image_impl(
-    extname: &str,
+    input_extname: &str,
+    output_extname: &str,
     data: &[u8],
     scale: f32,
     min_delay: u32,
     quality: f32,
 ) -> Result<Vec<u8>> {
-    let mut image_data = RGBA8ImageDataType::decode(extname, data)?;
+    let options = TransformOptions {
+        scale,
+        min_delay,
+        encode: EncodeOptions::with_quality(quality),
+        ..TransformOptions::default()
+    };
 
-    image_data.ease_frames(min_delay);
-    image_data.resize(scale);
+    transform_image_impl(input_extname, output_extname, data, &options)
+}
 
-    let bytes = image_data.encode(extname, quality)?;
+pub fn transform_image_impl(
+    input_extname: &str,
+    output_extname: &str,
+    data: &[u8],
+    options: &TransformOptions,
+) -> Result<Vec<u8>> {
+    let input_format = ImageFormat::detect(data, ImageFormat::from_extname(input_extname).ok())?;
+    let output_format = ImageFormat::from_extname(output_extname)?;
+
+    if output_format == ImageFormat::WebP
+        && stream::can_stream(input_format)
+        && options.is_streamable()
+    {
+        return stream::transform_to_webp(input_format, data, options);
+    }
+
+    let mut image_data =
+        RGBA8ImageDataType::decode_with_limits(input_format, data, &options.limits)?;
+
+    options.apply(&mut image_data, options.min_delay, options.scale)?;
+
+    let bytes = image_data.encode(output_format, &options.encode)?;
 
     Ok(bytes)
 }
@@ -88,15 +336,222 @@ pub fn transform_one_image(
     scale: f32,
     min_delay: u32,
     quality: f32,
-) -> String {
-    let data = general_purpose::STANDARD_NO_PAD
-        .decode(base64_data)
-        .expect_throw("decode base64 error");
+) -> std::result::Result<String, JsValue> {
+    convert_one_image(extname, extname, base64_data, scale, min_delay, quality)
+}
+
+#[wasm_bindgen]
+pub fn convert_one_image(
+    input_extname: &str,
+    output_extname: &str,
+    base64_data: &str,
+    scale: f32,
+    min_delay: u32,
+    quality: f32,
+) -> std::result::Result<String, JsValue> {
+    let data = decode_base64(base64_data)?;
+
+    let transformed = transform_one_image_impl(
+        input_extname,
+        output_extname,
+        &data,
+        scale,
+        min_delay,
+        quality,
+    )?;
+
+    Ok(general_purpose::STANDARD_NO_PAD.encode(transformed))
+}
+
+/// Decodes any supported image and encodes a still of it, e.g. a thumbnail
+/// for an animated asset.
+pub fn make_poster_impl(
+    input_extname: &str,
+    output_extname: &str,
+    data: &[u8],
+    options: &PosterOptions,
+) -> Result<Vec<u8>> {
+    let input_format = ImageFormat::detect(data, ImageFormat::from_extname(input_extname).ok())?;
+    let output_format = ImageFormat::from_extname(output_extname)?;
+
+    let poster = RGBA8ImageDataType::decode(input_format, data)?.poster(options)?;
+
+    RGBA8ImageDataType::Static(poster).encode(output_format, &options.encode)
+}
+
+/// `options` mirrors [`PosterOptions`], e.g.
+/// `{ frame: { mode: "at", time: 1000 }, resize: { width: 320, height: 180 } }`.
+#[wasm_bindgen]
+pub fn make_poster(
+    input_extname: &str,
+    output_extname: &str,
+    base64_data: &str,
+    options: JsValue,
+) -> std::result::Result<String, JsValue> {
+    let data = decode_base64(base64_data)?;
+    let options = parse_options::<PosterOptions>(options)?;
+
+    let poster = make_poster_impl(input_extname, output_extname, &data, &options)?;
+
+    Ok(general_purpose::STANDARD_NO_PAD.encode(poster))
+}
+
+pub fn transform_image_within_budget_impl(
+    input_extname: &str,
+    output_extname: &str,
+    data: &[u8],
+    options: &TransformOptions,
+    budget: &SizeBudget,
+) -> Result<BudgetChoice> {
+    let input_format = ImageFormat::detect(data, ImageFormat::from_extname(input_extname).ok())?;
+    let output_format = ImageFormat::from_extname(output_extname)?;
 
-    let transformed = transform_one_image_impl(extname, &data, scale, min_delay, quality)
-        .expect_throw("transform error");
+    let image_data = RGBA8ImageDataType::decode_with_limits(input_format, data, &options.limits)?;
 
-    general_purpose::STANDARD_NO_PAD.encode(transformed)
+    transform_within_budget(&image_data, output_format, options, budget)
+}
+
+/// Decodes an image and encodes frame `index` on its own, e.g. as a poster.
+pub fn extract_frame_impl(
+    input_extname: &str,
+    output_extname: &str,
+    data: &[u8],
+    index: u32,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>> {
+    let input_format = ImageFormat::detect(data, ImageFormat::from_extname(input_extname).ok())?;
+    let output_format = ImageFormat::from_extname(output_extname)?;
+
+    let frame = RGBA8ImageDataType::decode(input_format, data)?.into_frame(index as usize)?;
+
+    RGBA8ImageDataType::Static(frame).encode(output_format, options)
+}
+
+/// Decodes both images and scores `transformed` against `original`; either
+/// side may be in any supported format.
+pub fn compare_images_impl(
+    original_extname: &str,
+    original: &[u8],
+    transformed_extname: &str,
+    transformed: &[u8],
+) -> Result<QualityReport> {
+    let original_format =
+        ImageFormat::detect(original, ImageFormat::from_extname(original_extname).ok())?;
+    let transformed_format = ImageFormat::detect(
+        transformed,
+        ImageFormat::from_extname(transformed_extname).ok(),
+    )?;
+
+    let original = RGBA8ImageDataType::decode(original_format, original)?;
+    let transformed = RGBA8ImageDataType::decode(transformed_format, transformed)?;
+
+    compare(&original, &transformed)
+}
+
+/// `options` is a plain JS object mirroring [`TransformOptions`] in camelCase;
+/// omitted fields keep their defaults.
+#[wasm_bindgen]
+pub fn transform_image(
+    input_extname: &str,
+    output_extname: &str,
+    base64_data: &str,
+    options: JsValue,
+) -> std::result::Result<String, JsValue> {
+    let data = decode_base64(base64_data)?;
+    let options = parse_options::<TransformOptions>(options)?;
+
+    let transformed = transform_image_impl(input_extname, output_extname, &data, &options)?;
+
+    Ok(general_purpose::STANDARD_NO_PAD.encode(transformed))
+}
+
+/// `options` mirrors [`EncodeOptions`]; static inputs only have frame 0.
+#[wasm_bindgen]
+pub fn extract_frame(
+    input_extname: &str,
+    output_extname: &str,
+    base64_data: &str,
+    index: u32,
+    options: JsValue,
+) -> std::result::Result<String, JsValue> {
+    let data = decode_base64(base64_data)?;
+    let options = parse_options::<EncodeOptions>(options)?;
+
+    let frame = extract_frame_impl(input_extname, output_extname, &data, index, &options)?;
+
+    Ok(general_purpose::STANDARD_NO_PAD.encode(frame))
+}
+
+/// Returns `{ data, size, quality, scale, minDelay, attempts }` with `data`
+/// base64 encoded, or throws a `LimitExceeded` error when nothing fits.
+#[wasm_bindgen]
+pub fn transform_image_within_budget(
+    input_extname: &str,
+    output_extname: &str,
+    base64_data: &str,
+    options: JsValue,
+    budget: JsValue,
+) -> std::result::Result<JsValue, JsValue> {
+    let data = decode_base64(base64_data)?;
+    let options = parse_options::<TransformOptions>(options)?;
+    let budget = parse_options::<SizeBudget>(budget)?;
+
+    let choice = transform_image_within_budget_impl(
+        input_extname,
+        output_extname,
+        &data,
+        &options,
+        &budget,
+    )?;
+
+    let result = serde_wasm_bindgen::to_value(&choice)?;
+    js_sys::Reflect::set(
+        &result,
+        &"data".into(),
+        &general_purpose::STANDARD_NO_PAD.encode(&choice.data).into(),
+    )?;
+
+    Ok(result)
+}
+
+/// Returns `[{ mode: "lossy" | "lossless", duration, xOffset, yOffset, width, height }]`.
+#[wasm_bindgen]
+pub fn inspect_webp(base64_data: &str) -> std::result::Result<JsValue, JsValue> {
+    let data = decode_base64(base64_data)?;
+    let reports = inspect_webp_frames(&data)?;
+
+    Ok(serde_wasm_bindgen::to_value(&reports)?)
+}
+
+/// Returns `{ psnr, ssim, frames: [{ psnr, ssim, duration }] }`; `psnr` is
+/// `Infinity` for identical frames.
+#[wasm_bindgen]
+pub fn compare_images(
+    original_extname: &str,
+    original_base64_data: &str,
+    transformed_extname: &str,
+    transformed_base64_data: &str,
+) -> std::result::Result<JsValue, JsValue> {
+    let original = decode_base64(original_base64_data)?;
+    let transformed = decode_base64(transformed_base64_data)?;
+
+    let report = compare_images_impl(
+        original_extname,
+        &original,
+        transformed_extname,
+        &transformed,
+    )?;
+
+    Ok(serde_wasm_bindgen::to_value(&report)?)
+}
+
+#[wasm_bindgen]
+pub fn detect_image_format(
+    base64_data: &str,
+) -> std::result::Result<Option<ImageFormatInfo>, JsValue> {
+    let data = decode_base64(base64_data)?;
+
+    Ok(ImageFormatInfo::sniff(&data))
 }
 
 #[cfg(test)]
@@ -104,14 +559,354 @@ mod tests {
     use std::{fs, path::Path};
 
     use super::*;
+    use crate::core::fixtures::{animation, still};
 
     #[test]
     fn test_transform_one_image() {
         let path = Path::new("./examples/example_1/example_1.webp");
         let content = fs::read(path).unwrap();
 
-        let img = transform_one_image_impl(".webp", &content, 0.5f32, 80, 60f32).unwrap();
+        let img = transform_one_image_impl(".webp", ".webp", &content, 0.5f32, 80, 60f32).unwrap();
+
+        fs::write(std::env::temp_dir().join("example_1_test.webp"), img).unwrap();
+    }
+
+    #[test]
+    fn test_transform_misnamed_image() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let img = transform_one_image_impl(".png", ".png", &content, 0.25f32, 80, 60f32).unwrap();
+
+        assert_eq!(
+            ImageFormatInfo::sniff(&img).map(|info| info.format),
+            Some(ImageFormat::Png)
+        );
+    }
+
+    #[test]
+    fn test_encode_lossless_webp() {
+        let data = image::RgbaImage::from_fn(16, 16, |x, y| {
+            image::Rgba([(x * 16) as u8, (y * 16) as u8, 0, 255])
+        });
+        let st_img = RGBA8ImageDataType::Static(still(data.clone()));
+
+        let options = EncodeOptions {
+            webp: WebPEncodeOptions::lossless(),
+            ..EncodeOptions::default()
+        };
+        let bytes = st_img.encode(ImageFormat::WebP, &options).unwrap();
+
+        let RGBA8ImageDataType::Static(decoded) =
+            RGBA8ImageDataType::decode(ImageFormat::WebP, &bytes).unwrap()
+        else {
+            panic!("expected static webp");
+        };
+        assert_eq!(decoded.data, data);
+    }
+
+    #[test]
+    fn test_encode_mixed_animated_webp() {
+        let flat = image::RgbaImage::from_pixel(32, 32, image::Rgba([40, 80, 160, 255]));
+        let noisy = image::RgbaImage::from_fn(32, 32, |x, y| {
+            let v = ((x * 7919 + y * 104729) % 251) as u8;
+            image::Rgba([v, v.wrapping_mul(3), v.wrapping_mul(7), 255])
+        });
+        let ani_img = RGBA8ImageDataType::Animated(animation(vec![flat, noisy], vec![100, 100]));
+
+        let mut options = EncodeOptions::with_quality(50.0);
+        options.webp.anim.allow_mixed = true;
+        options.webp.anim.kmax = Some(1);
+        let bytes = ani_img.encode(ImageFormat::WebP, &options).unwrap();
+
+        let reports = inspect_webp_frames(&bytes).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].mode, webp::WebPFrameMode::Lossless);
+        assert!(reports.iter().all(|r| r.duration == 100));
+    }
+
+    #[test]
+    fn test_transform_unsupported_format() {
+        let err =
+            transform_one_image_impl(".bmp", ".webp", b"BM garbage", 1.0, 0, 60f32).unwrap_err();
+
+        assert_eq!(err.kind(), "UnsupportedFormat");
+    }
+
+    #[test]
+    fn test_convert_webp_to_gif() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let RGBA8ImageDataType::Animated(source) =
+            RGBA8ImageDataType::decode(ImageFormat::WebP, &content).unwrap()
+        else {
+            panic!("expected animated webp");
+        };
+
+        let img = transform_one_image_impl(".webp", ".gif", &content, 0.25f32, 0, 60f32).unwrap();
+
+        let RGBA8ImageDataType::Animated(converted) =
+            RGBA8ImageDataType::decode(ImageFormat::Gif, &img).unwrap()
+        else {
+            panic!("expected animated gif");
+        };
+        assert_eq!(converted.frames.len(), source.frames.len());
+        assert_eq!(converted.loop_count, source.loop_count);
+    }
+
+    #[test]
+    fn test_compare_transformed_image() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let img = transform_one_image_impl(".webp", ".webp", &content, 0.5f32, 80, 60f32).unwrap();
+        let report = compare_images_impl(".webp", &content, ".webp", &img).unwrap();
+
+        assert!(!report.frames.is_empty());
+        assert!(report.psnr > 20.0 && report.psnr.is_finite());
+        assert!(report.ssim > 0.5 && report.ssim <= 1.0);
+    }
+
+    #[test]
+    fn test_transform_into_box() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let options = TransformOptions {
+            resize: Some(ResizeSpec::fit(160, 90, resize::ResizeFit::Cover)),
+            ..TransformOptions::default()
+        };
+        let img = transform_image_impl(".webp", ".png", &content, &options).unwrap();
+
+        let RGBA8ImageDataType::Animated(resized) =
+            RGBA8ImageDataType::decode(ImageFormat::Png, &img).unwrap()
+        else {
+            panic!("expected animated png");
+        };
+        assert_eq!((resized.width, resized.height), (160, 90));
+        assert!(resized.frames.iter().all(|f| f.dimensions() == (160, 90)));
+    }
+
+    #[test]
+    fn test_transform_with_trim_and_pad() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let options = TransformOptions {
+            trim: Some(TrimOptions::default()),
+            resize: Some(ResizeSpec::fit(64, 64, resize::ResizeFit::Inside)),
+            pad: Some(PadSpec {
+                width: 80,
+                height: 80,
+                ..PadSpec::default()
+            }),
+            ..TransformOptions::default()
+        };
+        let img = transform_image_impl(".webp", ".png", &content, &options).unwrap();
+
+        let decoded = RGBA8ImageDataType::decode(ImageFormat::Png, &img).unwrap();
+        assert_eq!(decoded.dimensions(), (80, 80));
+    }
+
+    #[test]
+    fn test_edit_and_extract_frame() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let RGBA8ImageDataType::Animated(source) =
+            RGBA8ImageDataType::decode(ImageFormat::WebP, &content).unwrap()
+        else {
+            panic!("expected animated webp");
+        };
+
+        let options = TransformOptions {
+            edit: edit::AnimationEdit {
+                range: Some(edit::FrameRange::Frames {
+                    start: 1,
+                    end: Some(4),
+                }),
+                boomerang: true,
+                loop_count: Some(2),
+                ..edit::AnimationEdit::default()
+            },
+            ..TransformOptions::default()
+        };
+        let img = transform_image_impl(".webp", ".png", &content, &options).unwrap();
+        let RGBA8ImageDataType::Animated(edited) =
+            RGBA8ImageDataType::decode(ImageFormat::Png, &img).unwrap()
+        else {
+            panic!("expected animated png");
+        };
+        assert_eq!(edited.frames.len(), 4);
+        assert_eq!(edited.loop_count, 2);
+
+        let poster =
+            extract_frame_impl(".webp", ".png", &content, 2, &EncodeOptions::default()).unwrap();
+        let RGBA8ImageDataType::Static(poster) =
+            RGBA8ImageDataType::decode(ImageFormat::Png, &poster).unwrap()
+        else {
+            panic!("expected static png");
+        };
+        assert_eq!(poster.data, source.frames[2]);
+    }
+
+    #[test]
+    fn test_make_poster() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let options = PosterOptions {
+            frame: poster::PosterFrame::Representative,
+            resize: Some(ResizeSpec::fit(64, 64, resize::ResizeFit::Contain)),
+            ..PosterOptions::default()
+        };
+        let img = make_poster_impl(".webp", ".gif", &content, &options).unwrap();
+
+        let RGBA8ImageDataType::Static(poster) =
+            RGBA8ImageDataType::decode(ImageFormat::Gif, &img).unwrap()
+        else {
+            panic!("expected static gif");
+        };
+        assert_eq!((poster.width, poster.height), (64, 64));
+    }
+
+    #[test]
+    fn test_region_frames_to_apng() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+
+        let RGBA8ImageDataType::Animated(composited) =
+            RGBA8ImageDataType::decode(ImageFormat::WebP, &content).unwrap()
+        else {
+            panic!("expected animated webp");
+        };
+        let regions = RGBA8ImageDataType::decode_regions(ImageFormat::WebP, &content).unwrap();
+
+        let bytes = regions
+            .encode(ImageFormat::Png, &EncodeOptions::default())
+            .unwrap();
+        let RGBA8ImageDataType::Animated(apng) =
+            RGBA8ImageDataType::decode(ImageFormat::Png, &bytes).unwrap()
+        else {
+            panic!("expected animated png");
+        };
+        assert!(apng.frames == composited.frames);
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let path = Path::new("./examples/example_1/example_1.webp");
+        let content = fs::read(path).unwrap();
+        let gif = RGBA8ImageDataType::decode(ImageFormat::WebP, &content)
+            .unwrap()
+            .encode(ImageFormat::Gif, &EncodeOptions::default())
+            .unwrap();
+
+        let options = TransformOptions {
+            limits: DecodeLimits {
+                max_frames: Some(2),
+                ..DecodeLimits::default()
+            },
+            ..TransformOptions::default()
+        };
+        for (ext, data) in [("webp", &content), ("gif", &gif)] {
+            for out in ["webp", "png"] {
+                let err = transform_image_impl(ext, out, data, &options).unwrap_err();
+                assert!(
+                    matches!(err, TransformError::LimitExceeded { ref limit, .. } if limit == "frames"),
+                    "{ext} -> {out}: {err}"
+                );
+            }
+        }
+
+        // the same frames on a 65535x65535 screen: rejected from the header
+        let mut bomb = gif.clone();
+        bomb[6..10].fill(0xff);
+        let err = transform_image_impl("gif", "gif", &bomb, &TransformOptions::default());
+        assert_eq!(err.unwrap_err().kind(), "LimitExceeded");
+    }
+
+    fn corpus(name: &str) -> Vec<u8> {
+        fs::read(Path::new("./fuzz/corpus").join(name)).unwrap()
+    }
+
+    /// Every malformed sample in the fuzz corpus has to come back as a
+    /// result, never a panic.
+    #[test]
+    fn test_malformed_corpus() {
+        for target in ["decode_webp", "decode_png", "decode_gif", "decode_avif"] {
+            for entry in fs::read_dir(Path::new("./fuzz/corpus").join(target)).unwrap() {
+                let data = fs::read(entry.unwrap().path()).unwrap();
+                for format in [
+                    ImageFormat::WebP,
+                    ImageFormat::Png,
+                    ImageFormat::Gif,
+                    ImageFormat::Avif,
+                ] {
+                    let _ = RGBA8ImageDataType::decode(format, &data);
+                    let _ = RGBA8ImageDataType::decode_regions(format, &data);
+                }
+                for output in ["webp", "png", "gif"] {
+                    let _ = transform_one_image_impl("webp", output, &data, 0.5, 20, 50.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_regressions() {
+        // a zero-height screen used to reach the resampler and divide by zero
+        let gif = corpus("decode_gif/zero-height-screen.gif");
+        let err = transform_one_image_impl("gif", "webp", &gif, 0.5, 0, 50.0).unwrap_err();
+        assert_eq!(err.kind(), "InvalidDimensions");
+
+        let webp = corpus("decode_webp/vp8x-wide-canvas.webp");
+        let err = RGBA8ImageDataType::decode(ImageFormat::WebP, &webp)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "LimitExceeded");
+
+        // a fragment flush with the right edge, where the old full-canvas
+        // copy in `get_ani_frame` indexed past the row
+        let webp = corpus("decode_webp/frame-at-right-edge.webp");
+        let RGBA8ImageDataType::Animated(ani_img) =
+            RGBA8ImageDataType::decode(ImageFormat::WebP, &webp).unwrap()
+        else {
+            panic!("expected animated webp");
+        };
+        let Ok(RGBA8ImageDataType::Animated(regions)) =
+            RGBA8ImageDataType::decode_regions(ImageFormat::WebP, &webp)
+        else {
+            panic!("expected animated webp");
+        };
+        assert!(ani_img.frames.iter().all(|f| f.dimensions() == (12, 8)));
+        let fragment = regions.frames[1].get_pixel(0, 0);
+        assert_eq!(fragment.0[3], 255);
+        assert_eq!(ani_img.frames[1].get_pixel(10, 4), fragment);
 
-        fs::write(Path::new("./examples/example_1/example_1_test.webp"), img).unwrap();
+        let webp = corpus("decode_webp/frame-past-canvas.webp");
+        let err = RGBA8ImageDataType::decode(ImageFormat::WebP, &webp)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "Decode");
+
+        // a RIFF size below the WEBP tag used to slice backwards
+        let webp = corpus("decode_webp/riff-size-zero.webp");
+        assert!(webp::webp_metadata(&webp).is_err());
+        let err = RGBA8ImageDataType::decode(ImageFormat::WebP, &webp)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), "Decode");
+        assert!(transform_one_image_impl("webp", "webp", &webp, 0.5, 20, 50.0).is_err());
+
+        // a sample count that sized the table before anything checked it
+        for name in ["stsz-huge-count.avif", "stts-huge-count.avif"] {
+            let avif = corpus(&format!("decode_avif/{name}"));
+            let err = RGBA8ImageDataType::decode(ImageFormat::Avif, &avif)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), "Decode");
+        }
     }
 }
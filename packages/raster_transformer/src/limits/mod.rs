@@ -0,0 +1,182 @@
+use crate::error::{Result, TransformError};
+use serde::Deserialize;
+
+/// Caps on what a decoder may produce, checked against header values before
+/// the pixel buffers are allocated. `None` lifts a limit.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DecodeLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Canvas pixels, and pixels of any single frame.
+    pub max_pixels: Option<u64>,
+    pub max_frames: Option<u32>,
+    /// RGBA bytes of every frame composited onto a full canvas, which is
+    /// what the in-memory pipeline holds at its peak.
+    pub max_decoded_bytes: Option<u64>,
+    /// Milliseconds of one loop.
+    pub max_duration: Option<u64>,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: Some(16_384),
+            max_height: Some(16_384),
+            max_pixels: Some(64 * 1024 * 1024),
+            max_frames: Some(10_000),
+            max_decoded_bytes: Some(1024 * 1024 * 1024),
+            max_duration: None,
+        }
+    }
+}
+
+fn check(limit: &str, value: u64, max: Option<u64>) -> Result<()> {
+    match max {
+        Some(max) if value > max => Err(TransformError::LimitExceeded {
+            limit: limit.into(),
+            value,
+            max,
+        }),
+        _ => Ok(()),
+    }
+}
+
+impl DecodeLimits {
+    pub fn unlimited() -> Self {
+        Self {
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            max_frames: None,
+            max_decoded_bytes: None,
+            max_duration: None,
+        }
+    }
+
+    pub fn check_size(&self, width: u32, height: u32) -> Result<()> {
+        check("width", u64::from(width), self.max_width.map(u64::from))?;
+        check("height", u64::from(height), self.max_height.map(u64::from))?;
+        check(
+            "pixels",
+            u64::from(width) * u64::from(height),
+            self.max_pixels,
+        )
+    }
+
+    /// For decoders that know the frame count up front; `frames` full
+    /// canvases of `width` x `height`.
+    pub fn check_frames(&self, width: u32, height: u32, frames: u32) -> Result<()> {
+        check("frames", u64::from(frames), self.max_frames.map(u64::from))?;
+        check(
+            "decodedBytes",
+            u64::from(width)
+                .saturating_mul(u64::from(height))
+                .saturating_mul(4)
+                .saturating_mul(u64::from(frames)),
+            self.max_decoded_bytes,
+        )
+    }
+
+    /// Checks the canvas and starts counting frames against it. An empty
+    /// canvas is rejected here too, as nothing downstream can draw on it.
+    pub fn tracker(&self, width: u32, height: u32) -> Result<LimitTracker<'_>> {
+        if width == 0 || height == 0 {
+            return Err(TransformError::InvalidDimensions { width, height });
+        }
+        self.check_size(width, height)?;
+
+        Ok(LimitTracker {
+            limits: self,
+            width,
+            height,
+            frames: 0,
+            duration: 0,
+        })
+    }
+
+    /// The same caps for decoders run through `image`, which enforce them
+    /// on their own allocations.
+    pub fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::no_limits();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        limits.max_alloc = self.max_pixels.map(|pixels| pixels.saturating_mul(4));
+        limits
+    }
+}
+
+/// Running totals for decoders that only learn the frame count as they go.
+pub struct LimitTracker<'a> {
+    limits: &'a DecodeLimits,
+    width: u32,
+    height: u32,
+    frames: u32,
+    duration: u64,
+}
+
+impl LimitTracker<'_> {
+    /// Call with a frame's header values before decoding its pixels.
+    pub fn frame(&mut self, width: u32, height: u32, duration: u32) -> Result<()> {
+        self.limits.check_size(width, height)?;
+
+        self.frames += 1;
+        self.limits
+            .check_frames(self.width, self.height, self.frames)?;
+
+        self.duration += u64::from(duration);
+        check("duration", self.duration, self.limits.max_duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_tracker() {
+        let limits = DecodeLimits {
+            max_frames: Some(3),
+            max_duration: Some(250),
+            ..DecodeLimits::default()
+        };
+
+        let err = limits.check_size(20_000, 10).unwrap_err();
+        assert_eq!(
+            err,
+            TransformError::LimitExceeded {
+                limit: "width".into(),
+                value: 20_000,
+                max: 16_384,
+            }
+        );
+        assert!(limits.check_size(8192, 8192).is_ok());
+        let err = limits.check_size(8192, 8193).unwrap_err();
+        assert!(
+            matches!(err, TransformError::LimitExceeded { ref limit, .. } if limit == "pixels")
+        );
+
+        let mut tracker = limits.tracker(10, 10).unwrap();
+        tracker.frame(10, 10, 100).unwrap();
+        tracker.frame(4, 4, 100).unwrap();
+        let err = tracker.frame(4, 4, 100).unwrap_err();
+        assert!(
+            matches!(err, TransformError::LimitExceeded { ref limit, .. } if limit == "duration")
+        );
+
+        let bytes = DecodeLimits {
+            max_decoded_bytes: Some(1000),
+            ..DecodeLimits::unlimited()
+        };
+        let err = bytes.check_frames(10, 10, 3).unwrap_err();
+        assert_eq!(err.kind(), "LimitExceeded");
+
+        // the byte count saturates instead of overflowing
+        let err = bytes
+            .check_frames(u32::MAX, u32::MAX, u32::MAX)
+            .unwrap_err();
+        assert!(
+            matches!(err, TransformError::LimitExceeded { ref limit, value, .. } if limit == "decodedBytes" && value == u64::MAX)
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use crate::core::RGBA8ImageDataType;
+use crate::geometry::{exif_orientation, reset_exif_orientation};
+use serde::Deserialize;
+
+/// iTXt keyword Adobe uses for XMP packets in PNG.
+pub const PNG_XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+
+/// Raw metadata blocks carried from the decoder to the muxer: the ICC
+/// profile, a TIFF-structured EXIF block and the XMP packet, plus PNG's
+/// lightweight colour chunks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImageMetadata {
+    pub icc: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    /// PNG `gAMA`, the encoding exponent (0.45455 for a 2.2 display gamma).
+    pub gamma: Option<f32>,
+    /// PNG `cHRM` white point, red, green and blue as CIE xy.
+    pub chromaticities: Option<[[f32; 2]; 4]>,
+    /// PNG `sRGB`.
+    pub srgb: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MetadataPolicy {
+    #[default]
+    KeepAll,
+    StripAll,
+    IccOnly,
+}
+
+impl ImageMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply_policy(&mut self, policy: MetadataPolicy) {
+        match policy {
+            MetadataPolicy::KeepAll => {}
+            MetadataPolicy::StripAll => *self = Self::default(),
+            // the PNG colour chunks describe the pixels just like a profile
+            MetadataPolicy::IccOnly => {
+                self.exif = None;
+                self.xmp = None;
+            }
+        }
+    }
+}
+
+impl RGBA8ImageDataType {
+    pub fn metadata(&self) -> &ImageMetadata {
+        match self {
+            Self::Static(st_img) => &st_img.metadata,
+            Self::Animated(ani_img) => &ani_img.metadata,
+        }
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut ImageMetadata {
+        match self {
+            Self::Static(st_img) => &mut st_img.metadata,
+            Self::Animated(ani_img) => &mut ani_img.metadata,
+        }
+    }
+
+    /// Rotates the pixels upright according to the captured EXIF block and
+    /// resets its orientation tag to match.
+    pub fn apply_exif_orientation(&mut self) {
+        let Some(exif) = self.metadata_mut().exif.as_mut() else {
+            return;
+        };
+        let Some(orientation) = exif_orientation(exif) else {
+            return;
+        };
+        reset_exif_orientation(exif);
+
+        self.apply_orientation(orientation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::EncodeOptions;
+    use crate::core::fixtures::{animation, still};
+    use crate::format::ImageFormat;
+
+    #[test]
+    fn test_metadata_policy() {
+        let metadata = ImageMetadata {
+            icc: Some(b"icc".to_vec()),
+            exif: Some(b"exif".to_vec()),
+            xmp: Some(b"xmp".to_vec()),
+            ..ImageMetadata::default()
+        };
+
+        let mut icc_only = metadata.clone();
+        icc_only.apply_policy(MetadataPolicy::IccOnly);
+        assert_eq!(icc_only.icc.as_deref(), Some(b"icc".as_slice()));
+        assert!(icc_only.exif.is_none() && icc_only.xmp.is_none());
+
+        let mut stripped = metadata;
+        stripped.apply_policy(MetadataPolicy::StripAll);
+        assert!(stripped.is_empty());
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let exif = [
+            b"MM\0*".as_slice(),
+            &[0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 1, 0, 0],
+        ]
+        .concat();
+        let metadata = ImageMetadata {
+            icc: Some(vec![0; 128]),
+            exif: Some(exif),
+            xmp: Some(b"<x:xmpmeta xmlns:x='adobe:ns:meta/'/>".to_vec()),
+            ..ImageMetadata::default()
+        };
+
+        let frame = RgbaImage::from_pixel(8, 8, Rgba([10, 20, 30, 255]));
+        let mut st_img = still(frame.clone());
+        st_img.metadata = metadata.clone();
+        let mut ani_img = animation(vec![frame.clone(), frame], vec![100, 100]);
+        ani_img.metadata = metadata.clone();
+
+        for image_data in [
+            RGBA8ImageDataType::Static(st_img),
+            RGBA8ImageDataType::Animated(ani_img),
+        ] {
+            for format in [ImageFormat::WebP, ImageFormat::Png] {
+                let bytes = image_data
+                    .clone()
+                    .encode(format, &EncodeOptions::default())
+                    .unwrap();
+                let decoded = RGBA8ImageDataType::decode(format, &bytes).unwrap();
+                assert_eq!(decoded.metadata(), &metadata, "{format}");
+
+                let options = EncodeOptions {
+                    metadata: MetadataPolicy::IccOnly,
+                    ..EncodeOptions::default()
+                };
+                let bytes = image_data.clone().encode(format, &options).unwrap();
+                let decoded = RGBA8ImageDataType::decode(format, &bytes).unwrap();
+                assert_eq!(decoded.metadata().icc, metadata.icc, "{format}");
+                assert!(decoded.metadata().exif.is_none(), "{format}");
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::core::RGBA8ImageDataType;
+use crate::error::{Result, TransformError};
+use crate::resize::{ResizeFilter, resample};
+use image::RgbaImage;
+use serde::Serialize;
+
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STEP: u32 = 4;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameMetrics {
+    pub psnr: f64,
+    pub ssim: f64,
+    pub duration: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QualityReport {
+    pub frames: Vec<FrameMetrics>,
+    /// PSNR of the duration-weighted mean squared error over all frames.
+    pub psnr: f64,
+    /// Duration-weighted mean SSIM over all frames.
+    pub ssim: f64,
+}
+
+struct Timeline<'a> {
+    frames: Vec<(&'a RgbaImage, u32)>,
+}
+
+impl<'a> Timeline<'a> {
+    fn new(image_data: &'a RGBA8ImageDataType) -> Self {
+        let frames = match image_data {
+            RGBA8ImageDataType::Static(st_img) => vec![(&st_img.data, 0)],
+            RGBA8ImageDataType::Animated(ani_img) => ani_img
+                .frames
+                .iter()
+                .zip(ani_img.durations.iter().copied())
+                .collect(),
+        };
+        Self { frames }
+    }
+
+    /// Reference frames visible during `[start, start + duration)` with how
+    /// long each of them was on screen. Zero-length spans and spans past the
+    /// end of the reference pick the single frame showing at `start`.
+    fn overlapping(&self, start: u64, duration: u32) -> Vec<(usize, f64)> {
+        let end = start + u64::from(duration);
+        let mut overlaps = vec![];
+        let mut frame_start = 0u64;
+
+        for (i, (_, frame_duration)) in self.frames.iter().enumerate() {
+            let frame_end = frame_start + u64::from(*frame_duration);
+            let overlap = end.min(frame_end).saturating_sub(start.max(frame_start));
+            if overlap > 0 {
+                overlaps.push((i, overlap as f64));
+            }
+            frame_start = frame_end;
+        }
+
+        if overlaps.is_empty() {
+            let mut frame_start = 0u64;
+            let index = self
+                .frames
+                .iter()
+                .position(|(_, frame_duration)| {
+                    frame_start += u64::from(*frame_duration);
+                    start < frame_start
+                })
+                .unwrap_or(self.frames.len().saturating_sub(1));
+            overlaps.push((index, 1.0));
+        }
+
+        overlaps
+    }
+}
+
+/// Premultiplied RGB plus alpha, so colour hidden under transparent pixels
+/// does not count as a difference.
+fn premultiplied(img: &RgbaImage) -> Vec<[f64; 4]> {
+    img.pixels()
+        .map(|p| {
+            let a = f64::from(p.0[3]);
+            [
+                f64::from(p.0[0]) * a / 255.0,
+                f64::from(p.0[1]) * a / 255.0,
+                f64::from(p.0[2]) * a / 255.0,
+                a,
+            ]
+        })
+        .collect()
+}
+
+pub fn mse(reference: &RgbaImage, distorted: &RgbaImage) -> f64 {
+    let reference = premultiplied(reference);
+    let distorted = premultiplied(distorted);
+    let len = (reference.len() * 4).max(1) as f64;
+
+    reference
+        .iter()
+        .zip(distorted.iter())
+        .flat_map(|(r, d)| (0..4).map(move |c| (r[c] - d[c]) * (r[c] - d[c])))
+        .sum::<f64>()
+        / len
+}
+
+pub fn psnr_from_mse(mse: f64) -> f64 {
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+/// Mean SSIM over 8x8 windows with a stride of 4, averaged across the four
+/// premultiplied channels.
+pub fn ssim(reference: &RgbaImage, distorted: &RgbaImage) -> f64 {
+    let (width, height) = distorted.dimensions();
+    let reference = premultiplied(reference);
+    let distorted = premultiplied(distorted);
+
+    let window_w = SSIM_WINDOW.min(width);
+    let window_h = SSIM_WINDOW.min(height);
+    if window_w == 0 || window_h == 0 {
+        return 1.0;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0usize;
+
+    let mut y = 0;
+    while y + window_h <= height {
+        let mut x = 0;
+        while x + window_w <= width {
+            for c in 0..4 {
+                let n = f64::from(window_w * window_h);
+                let (mut sum_r, mut sum_d) = (0.0, 0.0);
+                let (mut sum_rr, mut sum_dd, mut sum_rd) = (0.0, 0.0, 0.0);
+
+                for wy in y..y + window_h {
+                    for wx in x..x + window_w {
+                        let i = (wy * width + wx) as usize;
+                        let r = reference[i][c];
+                        let d = distorted[i][c];
+                        sum_r += r;
+                        sum_d += d;
+                        sum_rr += r * r;
+                        sum_dd += d * d;
+                        sum_rd += r * d;
+                    }
+                }
+
+                let mean_r = sum_r / n;
+                let mean_d = sum_d / n;
+                let var_r = sum_rr / n - mean_r * mean_r;
+                let var_d = sum_dd / n - mean_d * mean_d;
+                let cov = sum_rd / n - mean_r * mean_d;
+
+                total += ((2.0 * mean_r * mean_d + SSIM_C1) * (2.0 * cov + SSIM_C2))
+                    / ((mean_r * mean_r + mean_d * mean_d + SSIM_C1) * (var_r + var_d + SSIM_C2));
+                windows += 1;
+            }
+            x += SSIM_STEP.min(window_w);
+        }
+        y += SSIM_STEP.min(window_h);
+    }
+
+    total / windows as f64
+}
+
+/// Whether `distorted` could be `reference` scaled by the same factor on both
+/// axes, allowing each side to have been rounded to whole pixels.
+fn is_uniform_scale((ref_w, ref_h): (u32, u32), (dist_w, dist_h): (u32, u32)) -> bool {
+    let skew =
+        (u64::from(dist_w) * u64::from(ref_h)).abs_diff(u64::from(dist_h) * u64::from(ref_w));
+    skew * 2 <= u64::from(ref_w) + u64::from(ref_h)
+}
+
+/// Compares the decoded original with the decoded transform output. The
+/// original is resized to the output's canvas and matched by play time, so
+/// frames merged by `ease_frames` are scored against every original frame
+/// they replaced, weighted by how long each one was shown. Outputs that were
+/// cropped, rotated or padded can't be lined up this way and are rejected.
+pub fn compare(
+    reference: &RGBA8ImageDataType,
+    distorted: &RGBA8ImageDataType,
+) -> Result<QualityReport> {
+    if !is_uniform_scale(reference.dimensions(), distorted.dimensions()) {
+        let (ref_w, ref_h) = reference.dimensions();
+        let (dist_w, dist_h) = distorted.dimensions();
+        return Err(TransformError::InvalidInput(format!(
+            "{dist_w}x{dist_h} is not a uniform scale of {ref_w}x{ref_h}"
+        )));
+    }
+
+    let reference = Timeline::new(reference);
+    let distorted = Timeline::new(distorted);
+
+    let mut frames = vec![];
+    let mut total_weight = 0.0;
+    let mut total_mse = 0.0;
+    let mut total_ssim = 0.0;
+    let mut start = 0u64;
+
+    for (frame, duration) in &distorted.frames {
+        let (width, height) = frame.dimensions();
+        let mut frame_mse = 0.0;
+        let mut frame_ssim = 0.0;
+        let mut frame_weight = 0.0;
+
+        for (index, weight) in reference.overlapping(start, *duration) {
+            let aligned = resample(
+                reference.frames[index].0,
+                width,
+                height,
+                ResizeFilter::default(),
+            );
+            frame_mse += mse(&aligned, frame) * weight;
+            frame_ssim += ssim(&aligned, frame) * weight;
+            frame_weight += weight;
+        }
+
+        frame_mse /= frame_weight;
+        frame_ssim /= frame_weight;
+
+        let weight = f64::from((*duration).max(1));
+        total_mse += frame_mse * weight;
+        total_ssim += frame_ssim * weight;
+        total_weight += weight;
+
+        frames.push(FrameMetrics {
+            psnr: psnr_from_mse(frame_mse),
+            ssim: frame_ssim,
+            duration: *duration,
+        });
+        start += u64::from(*duration);
+    }
+
+    Ok(QualityReport {
+        frames,
+        psnr: psnr_from_mse(total_mse / total_weight),
+        ssim: total_ssim / total_weight,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::core::fixtures::animation;
+
+    fn animated(frames: Vec<RgbaImage>, durations: Vec<u32>) -> RGBA8ImageDataType {
+        RGBA8ImageDataType::Animated(animation(frames, durations))
+    }
+
+    #[test]
+    fn test_identical_images() {
+        let img = RgbaImage::from_fn(16, 16, |x, y| Rgba([x as u8 * 16, y as u8 * 16, 0, 255]));
+        let report = compare(
+            &animated(vec![img.clone()], vec![100]),
+            &animated(vec![img], vec![100]),
+        )
+        .unwrap();
+
+        assert_eq!(report.psnr, f64::INFINITY);
+        assert!((report.ssim - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_merged_frames_are_weighted_by_time() {
+        let black = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 255]));
+        let white = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255]));
+
+        // the output kept the white frame for the whole 40ms span
+        let report = compare(
+            &animated(vec![black, white.clone()], vec![20, 20]),
+            &animated(vec![white], vec![40]),
+        )
+        .unwrap();
+
+        let expected_mse = 255.0 * 255.0 * 3.0 / 4.0 / 2.0;
+        assert!((report.psnr - psnr_from_mse(expected_mse)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_cropped_output_is_rejected() {
+        let img = RgbaImage::from_fn(16, 16, |x, y| Rgba([x as u8 * 16, y as u8 * 16, 0, 255]));
+        let cropped = image::imageops::crop_imm(&img, 0, 0, 16, 10).to_image();
+        let halved = resample(&img, 8, 8, ResizeFilter::default());
+
+        let err = compare(
+            &animated(vec![img.clone()], vec![100]),
+            &animated(vec![cropped], vec![100]),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), "InvalidInput");
+
+        assert!(
+            compare(
+                &animated(vec![img], vec![100]),
+                &animated(vec![halved], vec![100]),
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_hidden_colour_is_ignored() {
+        let a = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 0]));
+        let b = RgbaImage::from_pixel(8, 8, Rgba([0, 255, 0, 0]));
+
+        assert_eq!(mse(&a, &b), 0.0);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::core::{
+    BlendOp, DisposeOp, FrameRegion, RGBA8AnimatedImageData, RGBA8ImageDataType,
+    RGBA8StaticImageData,
+};
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::limits::DecodeLimits;
+use crate::metadata::{ImageMetadata, PNG_XMP_KEYWORD};
+use crate::stream::{AnimationInfo, FrameSink};
+use image::{AnimationDecoder, EncodableLayout, ImageDecoder, Rgba, RgbaImage};
+use png::{BitDepth, ColorType, Compression, Encoder};
+use std::borrow::Cow;
+
+/// APNG stores frame delays as a `u16` fraction of a second, so delays above
+/// 65535ms fall back to coarser denominators.
+pub fn png_frame_delay(duration_ms: u32) -> (u16, u16) {
+    for den in [1000u64, 100, 10, 1] {
+        let num = u64::from(duration_ms) * den / 1000;
+        if let Ok(num) = u16::try_from(num) {
+            return (num, den as u16);
+        }
+    }
+    (u16::MAX, 1)
+}
+
+pub fn png_loop_count(data: &[u8]) -> Result<u32> {
+    let reader = png::Decoder::new(std::io::Cursor::new(data))
+        .read_info()
+        .map_err(|e| TransformError::decode(ImageFormat::Png, e))?;
+
+    Ok(reader
+        .info()
+        .animation_control
+        .map(|actl| actl.num_plays)
+        .unwrap_or(0))
+}
+
+/// Contents of the first chunk of type `kind`, wherever it sits relative to
+/// `IDAT`.
+pub fn png_chunk<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut rest = data.strip_prefix(b"\x89PNG\r\n\x1a\n")?;
+
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let body = rest.get(8..8usize.checked_add(len)?)?;
+        if &rest[4..8] == kind {
+            return Some(body);
+        }
+        rest = rest.get(len.checked_add(12)?..)?;
+    }
+
+    None
+}
+
+/// `iCCP`, `eXIf` and the XMP `iTXt` chunk. `png` only parses chunks up to
+/// the first `IDAT`, so `eXIf` is looked up in the raw stream instead.
+pub fn png_metadata(data: &[u8]) -> Result<ImageMetadata> {
+    let reader = png::Decoder::new(std::io::Cursor::new(data))
+        .read_info()
+        .map_err(|e| TransformError::decode(ImageFormat::Png, e))?;
+    let info = reader.info();
+
+    let xmp = info
+        .utf8_text
+        .iter()
+        .find(|text| text.keyword == PNG_XMP_KEYWORD)
+        .map(|text| text.get_text())
+        .transpose()
+        .map_err(|e| TransformError::decode(ImageFormat::Png, e))?;
+
+    let xy = |(x, y): (png::ScaledFloat, png::ScaledFloat)| [x.into_value(), y.into_value()];
+
+    Ok(ImageMetadata {
+        icc: info.icc_profile.as_deref().map(<[u8]>::to_vec),
+        exif: png_chunk(data, b"eXIf").map(<[u8]>::to_vec),
+        xmp: xmp.map(String::into_bytes),
+        gamma: info.source_gamma.map(png::ScaledFloat::into_value),
+        chromaticities: info
+            .source_chromaticities
+            .map(|c| [xy(c.white), xy(c.red), xy(c.green), xy(c.blue)]),
+        srgb: info.srgb.is_some(),
+    })
+}
+
+/// Checks the `IHDR` size and the `acTL` frame count; `png` stops reading at
+/// the first `IDAT`, so nothing has been decoded yet.
+pub fn png_check_limits(data: &[u8], limits: &DecodeLimits) -> Result<()> {
+    let reader = png::Decoder::new(std::io::Cursor::new(data))
+        .read_info()
+        .map_err(|e| TransformError::decode(ImageFormat::Png, e))?;
+    let info = reader.info();
+
+    limits.check_size(info.width, info.height)?;
+    let frame_count = info
+        .animation_control
+        .map(|actl| actl.num_frames)
+        .unwrap_or(1);
+    limits.check_frames(info.width, info.height, frame_count)
+}
+
+pub fn decode_png(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8ImageDataType> {
+    let mut image_data = decode_png_pixels(data, limits)?;
+
+    *image_data.metadata_mut() = png_metadata(data)?;
+    image_data.apply_exif_orientation();
+
+    Ok(image_data)
+}
+
+/// Decodes one frame at a time into `sink`; the APNG reader composites onto
+/// a single canvas. A still image is one frame of 0 ms.
+pub fn stream_png_frames(
+    data: &[u8],
+    limits: &DecodeLimits,
+    sink: &mut dyn FrameSink,
+) -> Result<AnimationInfo> {
+    png_check_limits(data, limits)?;
+    let cursor = std::io::Cursor::new(data);
+
+    let png_decode_error = |e| TransformError::decode(ImageFormat::Png, e);
+
+    let decoder = image::codecs::png::PngDecoder::with_limits(cursor, limits.image_limits())
+        .map_err(png_decode_error)?;
+    let loop_count = if decoder.is_apng().map_err(png_decode_error)? {
+        let (width, height) = decoder.dimensions();
+        let mut tracker = limits.tracker(width, height)?;
+        for frame in decoder.apng().map_err(png_decode_error)?.into_frames() {
+            let frame = frame.map_err(png_decode_error)?;
+            let duration: std::time::Duration = frame.delay().into();
+            let duration = duration.as_millis() as u32;
+            tracker.frame(width, height, duration)?;
+            sink.frame(frame.into_buffer(), duration)?;
+        }
+        png_loop_count(data)?
+    } else {
+        sink.frame(RGBA8StaticImageData::decode(data, limits)?.data, 0)?;
+        0
+    };
+    sink.flush()?;
+
+    Ok(AnimationInfo {
+        loop_count,
+        bg_color: Rgba([255, 255, 255, 0]),
+    })
+}
+
+fn decode_png_pixels(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8ImageDataType> {
+    png_check_limits(data, limits)?;
+    let cursor = std::io::Cursor::new(data);
+
+    let png_decode_error = |e| TransformError::decode(ImageFormat::Png, e);
+
+    let decoded_png_data =
+        image::codecs::png::PngDecoder::with_limits(cursor, limits.image_limits())
+            .map_err(png_decode_error)?;
+    if decoded_png_data.is_apng().map_err(png_decode_error)? {
+        let (width, height) = decoded_png_data.dimensions();
+        let mut tracker = limits.tracker(width, height)?;
+        // `acTL` may understate the frame count, so keep counting
+        let frames = decoded_png_data
+            .apng()
+            .map_err(png_decode_error)?
+            .into_frames()
+            .map(|frame| {
+                let frame = frame.map_err(png_decode_error)?;
+                let duration: std::time::Duration = frame.delay().into();
+                tracker.frame(width, height, duration.as_millis() as u32)?;
+                Ok(frame)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut ani_img = RGBA8AnimatedImageData::decode(frames)?;
+        ani_img.loop_count = png_loop_count(data)?;
+        Ok(RGBA8ImageDataType::Animated(ani_img))
+    } else {
+        RGBA8StaticImageData::decode(data, limits).map(RGBA8ImageDataType::Static)
+    }
+}
+
+fn png_encode_error(e: png::EncodingError) -> TransformError {
+    TransformError::encode(ImageFormat::Png, e)
+}
+
+fn new_rgba8_encoder<'a>(
+    buf: &'a mut Vec<u8>,
+    width: u32,
+    height: u32,
+    metadata: &'a ImageMetadata,
+) -> Result<Encoder<'a, &'a mut Vec<u8>>> {
+    let mut info = png::Info::with_size(width, height);
+    info.color_type = ColorType::Rgba;
+    info.bit_depth = BitDepth::Eight;
+    info.icc_profile = metadata.icc.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = metadata.exif.as_deref().map(Cow::Borrowed);
+    info.source_gamma = metadata.gamma.map(png::ScaledFloat::new);
+    info.source_chromaticities = metadata.chromaticities.map(|[w, r, g, b]| {
+        png::SourceChromaticities::new((w[0], w[1]), (r[0], r[1]), (g[0], g[1]), (b[0], b[1]))
+    });
+    info.srgb = metadata
+        .srgb
+        .then_some(png::SrgbRenderingIntent::Perceptual);
+
+    let mut encoder = Encoder::with_info(buf, info).map_err(png_encode_error)?;
+    encoder.set_compression(Compression::Best);
+    if let Some(xmp) = &metadata.xmp {
+        encoder
+            .add_itxt_chunk(
+                PNG_XMP_KEYWORD.into(),
+                String::from_utf8_lossy(xmp).into_owned(),
+            )
+            .map_err(png_encode_error)?;
+    }
+
+    Ok(encoder)
+}
+
+/// APNG's default image must cover the canvas and every frame must fit in
+/// it; anything else is rebuilt with `into_deltas`.
+fn apng_regions(image_data: &RGBA8AnimatedImageData) -> bool {
+    let Some(regions) = &image_data.regions else {
+        return false;
+    };
+    let fits = |(frame, region): (&RgbaImage, &FrameRegion)| {
+        u64::from(region.x) + u64::from(frame.width()) <= u64::from(image_data.width)
+            && u64::from(region.y) + u64::from(frame.height()) <= u64::from(image_data.height)
+    };
+
+    image_data.frames.first().map(|f| f.dimensions()) == Some((image_data.width, image_data.height))
+        && regions.first().is_some_and(|r| (r.x, r.y) == (0, 0))
+        && image_data.frames.iter().zip(regions).all(fits)
+}
+
+/// Writes sub-rectangle frames as they are when APNG can express them, and
+/// minimal delta frames otherwise.
+pub fn encode_animated_png(mut image_data: RGBA8AnimatedImageData) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    if !apng_regions(&image_data) {
+        image_data.into_deltas();
+    }
+    let regions = image_data.regions.take().unwrap_or_default();
+
+    {
+        let mut encoder = new_rgba8_encoder(
+            &mut buf,
+            image_data.width,
+            image_data.height,
+            &image_data.metadata,
+        )?;
+
+        encoder
+            .set_animated(image_data.frames.len() as u32, image_data.loop_count)
+            .map_err(png_encode_error)?;
+
+        let mut writer = encoder.write_header().map_err(png_encode_error)?;
+
+        for ((frame, duration), region) in image_data
+            .frames
+            .iter()
+            .zip(image_data.durations.iter())
+            .zip(regions.iter())
+        {
+            let (delay_num, delay_den) = png_frame_delay(*duration);
+
+            // position last: both setters check the frame against the canvas
+            writer.reset_frame_position().map_err(png_encode_error)?;
+            writer
+                .set_frame_dimension(frame.width(), frame.height())
+                .map_err(png_encode_error)?;
+            writer
+                .set_frame_position(region.x, region.y)
+                .map_err(png_encode_error)?;
+            writer
+                .set_blend_op(match region.blend {
+                    BlendOp::Source => png::BlendOp::Source,
+                    BlendOp::Over => png::BlendOp::Over,
+                })
+                .map_err(png_encode_error)?;
+            writer
+                .set_dispose_op(match region.dispose {
+                    DisposeOp::None => png::DisposeOp::None,
+                    DisposeOp::Background => png::DisposeOp::Background,
+                    DisposeOp::Previous => png::DisposeOp::Previous,
+                })
+                .map_err(png_encode_error)?;
+            writer
+                .set_frame_delay(delay_num, delay_den)
+                .map_err(png_encode_error)?;
+            writer
+                .write_image_data(frame.as_bytes())
+                .map_err(png_encode_error)?;
+        }
+
+        writer.finish().map_err(png_encode_error)?;
+    }
+
+    Ok(buf)
+}
+
+pub fn encode_static_png(image_data: RGBA8StaticImageData) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+
+    {
+        let encoder = new_rgba8_encoder(
+            &mut buf,
+            image_data.width,
+            image_data.height,
+            &image_data.metadata,
+        )?;
+
+        let mut writer = encoder.write_header().map_err(png_encode_error)?;
+
+        writer
+            .write_image_data(image_data.data.as_bytes())
+            .map_err(png_encode_error)?;
+
+        writer.finish().map_err(png_encode_error)?;
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::core::fixtures::animation;
+
+    #[test]
+    fn test_apng_round_trip() {
+        let frames = [Rgba([255, 0, 0, 255]), Rgba([0, 255, 0, 128])]
+            .into_iter()
+            .map(|c| RgbaImage::from_pixel(4, 3, c))
+            .collect::<Vec<_>>();
+
+        let mut ani_img = animation(frames.clone(), vec![40, 120]);
+        ani_img.loop_count = 3;
+
+        let bytes = encode_animated_png(ani_img).unwrap();
+
+        match decode_png(&bytes, &DecodeLimits::default()).unwrap() {
+            RGBA8ImageDataType::Animated(decoded) => {
+                assert_eq!(decoded.durations, vec![40, 120]);
+                assert_eq!(decoded.loop_count, 3);
+                assert_eq!(decoded.frames, frames);
+            }
+            RGBA8ImageDataType::Static(_) => panic!("expected animated png"),
+        }
+    }
+
+    #[test]
+    fn test_png_frame_delay() {
+        assert_eq!(png_frame_delay(80), (80, 1000));
+        assert_eq!(png_frame_delay(70_000), (7000, 100));
+        assert_eq!(png_frame_delay(5_000_000), (50_000, 10));
+        assert_eq!(png_frame_delay(u32::MAX), (u16::MAX, 1));
+    }
+
+    #[test]
+    fn test_png_exif_orientation() {
+        let data = RgbaImage::from_fn(4, 2, |x, _| Rgba([x as u8 * 60, 0, 0, 255]));
+
+        // orientation 8, rotate 270
+        let exif = [
+            b"MM\0*".as_slice(),
+            &[0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 8, 0, 0],
+        ]
+        .concat();
+        let mut info = png::Info::with_size(4, 2);
+        info.color_type = ColorType::Rgba;
+        info.bit_depth = BitDepth::Eight;
+        info.exif_metadata = Some(exif.into());
+
+        let mut bytes = vec![];
+        {
+            let encoder = Encoder::with_info(&mut bytes, info).unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(data.as_bytes()).unwrap();
+        }
+
+        let RGBA8ImageDataType::Static(decoded) =
+            decode_png(&bytes, &DecodeLimits::default()).unwrap()
+        else {
+            panic!("expected static png");
+        };
+        assert_eq!((decoded.width, decoded.height), (2, 4));
+        // the right-most column is now on top
+        assert_eq!(decoded.data.get_pixel(0, 0), &Rgba([180, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::EncodeOptions;
+use crate::core::{RGBA8AnimatedImageData, RGBA8ImageDataType, RGBA8StaticImageData};
+use crate::error::Result;
+use crate::resize::{ResizeFilter, ResizeSpec};
+use image::RgbaImage;
+use serde::Deserialize;
+
+/// Which frame of an animation becomes the poster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum PosterFrame {
+    #[default]
+    First,
+    /// The frame on screen `time` ms into the first loop.
+    At { time: u32 },
+    /// The frame closest to the per-pixel mean of all frames.
+    Representative,
+    /// The frame with the highest luma entropy, i.e. the most detail.
+    Detailed,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PosterOptions {
+    pub frame: PosterFrame,
+    /// Target box; `None` keeps the decoded size.
+    pub resize: Option<ResizeSpec>,
+    pub filter: ResizeFilter,
+    pub encode: EncodeOptions,
+}
+
+fn luma_entropy(frame: &RgbaImage) -> f64 {
+    let mut histogram = [0u32; 256];
+    for p in frame.pixels() {
+        let [r, g, b, a] = p.0.map(u32::from);
+        // BT.601 weights, premultiplied so transparent areas count as black
+        let luma = (r * 299 + g * 587 + b * 114) * a / (1000 * 255);
+        histogram[luma as usize] += 1;
+    }
+
+    let total = f64::from(frame.width() * frame.height());
+    histogram
+        .iter()
+        .filter(|n| **n > 0)
+        .map(|n| {
+            let p = f64::from(*n) / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+impl RGBA8AnimatedImageData {
+    pub fn representative_frame_index(&self) -> usize {
+        let count = self.frames.len() as f64;
+        let mut mean = vec![0f64; (self.width * self.height * 4) as usize];
+        for frame in &self.frames {
+            for (m, v) in mean.iter_mut().zip(frame.as_raw()) {
+                *m += f64::from(*v) / count;
+            }
+        }
+
+        let distance = |frame: &RgbaImage| -> f64 {
+            mean.iter()
+                .zip(frame.as_raw())
+                .map(|(m, v)| (m - f64::from(*v)).powi(2))
+                .sum()
+        };
+        self.frames
+            .iter()
+            .map(distance)
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(i, _)| i)
+    }
+
+    pub fn detailed_frame_index(&self) -> usize {
+        self.frames
+            .iter()
+            .map(luma_entropy)
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(i, _)| i)
+    }
+
+    pub fn poster_frame_index(&self, frame: PosterFrame) -> usize {
+        match frame {
+            PosterFrame::First => 0,
+            PosterFrame::At { time } => self.frame_index_at(time),
+            PosterFrame::Representative => self.representative_frame_index(),
+            PosterFrame::Detailed => self.detailed_frame_index(),
+        }
+    }
+}
+
+impl RGBA8ImageDataType {
+    /// A still of the chosen frame, fitted into `options.resize`. Static
+    /// images are their own poster.
+    pub fn poster(self, options: &PosterOptions) -> Result<RGBA8StaticImageData> {
+        let mut poster = match self {
+            Self::Animated(mut ani_img) => {
+                ani_img.composite();
+                ani_img.extract_frame(ani_img.poster_frame_index(options.frame))?
+            }
+            Self::Static(st_img) => st_img,
+        };
+
+        if let Some(spec) = &options.resize {
+            poster.resize_to(spec, options.filter)?;
+        }
+
+        Ok(poster)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::core::fixtures::animation;
+    use crate::resize::ResizeFit;
+
+    #[test]
+    fn test_poster_frame_choice() {
+        let black = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 255]));
+        let grey = RgbaImage::from_pixel(8, 8, Rgba([110, 110, 110, 255]));
+        let noise = RgbaImage::from_fn(8, 8, |x, y| {
+            let v = ((x * 31 + y * 17) * 7 % 256) as u8;
+            Rgba([v, v, v, 255])
+        });
+        let white = RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255]));
+        let ani_img = RGBA8ImageDataType::Animated(animation(
+            vec![black, grey.clone(), noise.clone(), white],
+            vec![100; 4],
+        ));
+
+        let RGBA8ImageDataType::Animated(frames) = &ani_img else {
+            unreachable!()
+        };
+        assert_eq!(frames.poster_frame_index(PosterFrame::At { time: 250 }), 2);
+        assert_eq!(frames.poster_frame_index(PosterFrame::Representative), 1);
+        assert_eq!(frames.poster_frame_index(PosterFrame::Detailed), 2);
+
+        let poster = ani_img
+            .poster(&PosterOptions {
+                frame: PosterFrame::Detailed,
+                resize: Some(ResizeSpec::fit(4, 2, ResizeFit::Cover)),
+                ..PosterOptions::default()
+            })
+            .unwrap();
+        assert_eq!((poster.width, poster.height), (4, 2));
+        assert_eq!(poster.data.dimensions(), (4, 2));
+    }
+}
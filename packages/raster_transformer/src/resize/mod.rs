@@ -0,0 +1,387 @@
+use crate::error::{Result, TransformError};
+use image::imageops::FilterType;
+use image::{ImageBuffer, Rgba, Rgba32FImage, RgbaImage};
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    /// Copies source pixels as they are, for pixel art.
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub fn to_filter_type(self) -> FilterType {
+        match self {
+            Self::Nearest => FilterType::Nearest,
+            Self::Triangle => FilterType::Triangle,
+            Self::CatmullRom => FilterType::CatmullRom,
+            Self::Gaussian => FilterType::Gaussian,
+            Self::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+fn srgb_to_linear_lut() -> &'static [f32; 256] {
+    static LUT: OnceLock<[f32; 256]> = OnceLock::new();
+    LUT.get_or_init(|| {
+        std::array::from_fn(|i| {
+            let v = i as f32 / 255.0;
+            if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    })
+}
+
+fn linear_to_srgb(v: f32) -> u8 {
+    let v = if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (v.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn to_linear_premultiplied(img: &RgbaImage) -> Rgba32FImage {
+    let lut = srgb_to_linear_lut();
+    let mut data = Vec::with_capacity(img.as_raw().len());
+
+    for p in img.pixels() {
+        let a = f32::from(p.0[3]) / 255.0;
+        data.extend_from_slice(&[
+            lut[p.0[0] as usize] * a,
+            lut[p.0[1] as usize] * a,
+            lut[p.0[2] as usize] * a,
+            a,
+        ]);
+    }
+
+    ImageBuffer::from_raw(img.width(), img.height(), data).unwrap()
+}
+
+fn from_linear_premultiplied(img: &Rgba32FImage) -> RgbaImage {
+    let mut data = Vec::with_capacity(img.as_raw().len());
+
+    for p in img.pixels() {
+        // ringing filters can overshoot on both sides
+        let a = p.0[3].clamp(0.0, 1.0);
+        if a <= 0.0 {
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            continue;
+        }
+        data.extend_from_slice(&[
+            linear_to_srgb(p.0[0] / a),
+            linear_to_srgb(p.0[1] / a),
+            linear_to_srgb(p.0[2] / a),
+            (a * 255.0).round() as u8,
+        ]);
+    }
+
+    ImageBuffer::from_raw(img.width(), img.height(), data).unwrap()
+}
+
+/// Averages `factor`x`factor` blocks; edge blocks average what they cover.
+fn box_downsample(img: &Rgba32FImage, factor: u32) -> Rgba32FImage {
+    let (width, height) = img.dimensions();
+
+    ImageBuffer::from_fn(width.div_ceil(factor), height.div_ceil(factor), |x, y| {
+        let mut sum = [0.0f32; 4];
+        let mut count = 0.0;
+        for sy in y * factor..((y + 1) * factor).min(height) {
+            for sx in x * factor..((x + 1) * factor).min(width) {
+                for (sum, v) in sum.iter_mut().zip(img.get_pixel(sx, sy).0) {
+                    *sum += v;
+                }
+                count += 1.0;
+            }
+        }
+        Rgba(sum.map(|v| v / count))
+    })
+}
+
+/// Resamples in premultiplied linear light so transparent edges don't bleed
+/// dark fringes. Downscales by 4x or more box-filter by an integer factor
+/// first and leave the last 2-4x to `filter`.
+pub fn resample(img: &RgbaImage, width: u32, height: u32, filter: ResizeFilter) -> RgbaImage {
+    if img.dimensions() == (width, height) {
+        return img.clone();
+    }
+    if filter == ResizeFilter::Nearest {
+        return image::imageops::resize(img, width, height, FilterType::Nearest);
+    }
+
+    let mut linear = to_linear_premultiplied(img);
+
+    let factor = (img.width() / width).min(img.height() / height) / 2;
+    if factor >= 2 {
+        linear = box_downsample(&linear, factor);
+    }
+
+    let resized = image::imageops::resize(&linear, width, height, filter.to_filter_type());
+
+    from_linear_premultiplied(&resized)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFit {
+    /// Keep the aspect ratio and letterbox into the box with transparent
+    /// padding.
+    #[default]
+    Contain,
+    /// Keep the aspect ratio, cover the box and crop the overflow around the
+    /// centre.
+    Cover,
+    /// Stretch to the box exactly.
+    Fill,
+    /// Keep the aspect ratio and shrink to fit; the output may be smaller
+    /// than the box on one side.
+    Inside,
+}
+
+/// Target box for a resize. With only one of `width`/`height` the other side
+/// follows the aspect ratio and `fit` has no effect. `max_width`/`max_height`
+/// shrink the box, keeping its aspect ratio, and never enlarge it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResizeSpec {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub fit: ResizeFit,
+}
+
+/// Resize to `width`x`height`, then place the result at `x`/`y` on a
+/// `canvas_width`x`canvas_height` canvas; negative offsets crop, positive ones
+/// pad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResizePlan {
+    pub width: u32,
+    pub height: u32,
+    pub canvas_width: u32,
+    pub canvas_height: u32,
+    pub x: i64,
+    pub y: i64,
+}
+
+fn proportional(len: u32, to: u32, from: u32) -> u32 {
+    let len = (u64::from(len) * u64::from(to) + u64::from(from) / 2) / u64::from(from);
+    (len as u32).max(1)
+}
+
+/// Largest size with the aspect ratio of `width`x`height` inside the box; the
+/// limiting side matches the box exactly.
+fn fit_inside(width: u32, height: u32, box_width: u32, box_height: u32) -> (u32, u32) {
+    if u64::from(width) * u64::from(box_height) >= u64::from(height) * u64::from(box_width) {
+        (box_width, proportional(height, box_width, width))
+    } else {
+        (proportional(width, box_height, height), box_height)
+    }
+}
+
+/// Smallest size with the aspect ratio of `width`x`height` covering the box.
+fn fit_outside(width: u32, height: u32, box_width: u32, box_height: u32) -> (u32, u32) {
+    if u64::from(width) * u64::from(box_height) >= u64::from(height) * u64::from(box_width) {
+        (proportional(width, box_height, height), box_height)
+    } else {
+        (box_width, proportional(height, box_width, width))
+    }
+}
+
+impl ResizeSpec {
+    pub fn fit(width: u32, height: u32, fit: ResizeFit) -> Self {
+        Self {
+            width: Some(width),
+            height: Some(height),
+            fit,
+            ..Self::default()
+        }
+    }
+
+    pub fn plan(&self, width: u32, height: u32) -> Result<ResizePlan> {
+        let invalid = |w: u32, h: u32| TransformError::InvalidDimensions {
+            width: w,
+            height: h,
+        };
+
+        if width == 0 || height == 0 {
+            return Err(invalid(width, height));
+        }
+        for (w, h) in [(self.width, self.height), (self.max_width, self.max_height)] {
+            if w == Some(0) || h == Some(0) {
+                return Err(invalid(w.unwrap_or(width), h.unwrap_or(height)));
+            }
+        }
+
+        let (fit, (mut box_width, mut box_height)) = match (self.width, self.height) {
+            (Some(w), Some(h)) => (self.fit, (w, h)),
+            (Some(w), None) => (ResizeFit::Fill, (w, proportional(height, w, width))),
+            (None, Some(h)) => (ResizeFit::Fill, (proportional(width, h, height), h)),
+            (None, None) => (ResizeFit::Fill, (width, height)),
+        };
+
+        let max_width = self.max_width.unwrap_or(u32::MAX);
+        let max_height = self.max_height.unwrap_or(u32::MAX);
+        if box_width > max_width || box_height > max_height {
+            (box_width, box_height) = fit_inside(
+                box_width,
+                box_height,
+                box_width.min(max_width),
+                box_height.min(max_height),
+            );
+        }
+
+        let (resize_width, resize_height) = match fit {
+            ResizeFit::Fill => (box_width, box_height),
+            ResizeFit::Contain | ResizeFit::Inside => {
+                fit_inside(width, height, box_width, box_height)
+            }
+            ResizeFit::Cover => fit_outside(width, height, box_width, box_height),
+        };
+
+        let (canvas_width, canvas_height) = match fit {
+            ResizeFit::Inside => (resize_width, resize_height),
+            _ => (box_width, box_height),
+        };
+
+        Ok(ResizePlan {
+            width: resize_width,
+            height: resize_height,
+            canvas_width,
+            canvas_height,
+            x: (i64::from(canvas_width) - i64::from(resize_width)) / 2,
+            y: (i64::from(canvas_height) - i64::from(resize_height)) / 2,
+        })
+    }
+}
+
+impl ResizePlan {
+    pub fn apply(&self, img: &RgbaImage, filter: ResizeFilter) -> RgbaImage {
+        let resized = resample(img, self.width, self.height, filter);
+
+        if (self.canvas_width, self.canvas_height) == (self.width, self.height) {
+            return resized;
+        }
+
+        let mut canvas =
+            RgbaImage::from_pixel(self.canvas_width, self.canvas_height, Rgba([0, 0, 0, 0]));
+        image::imageops::replace(&mut canvas, &resized, self.x, self.y);
+        canvas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canvas(spec: &ResizeSpec, width: u32, height: u32) -> (u32, u32) {
+        let plan = spec.plan(width, height).unwrap();
+        (plan.canvas_width, plan.canvas_height)
+    }
+
+    #[test]
+    fn test_fit_modes() {
+        assert_eq!(
+            canvas(&ResizeSpec::fit(320, 180, ResizeFit::Contain), 1000, 1000),
+            (320, 180)
+        );
+        assert_eq!(
+            canvas(&ResizeSpec::fit(320, 180, ResizeFit::Cover), 1000, 1000),
+            (320, 180)
+        );
+        assert_eq!(
+            canvas(&ResizeSpec::fit(320, 180, ResizeFit::Fill), 1000, 1000),
+            (320, 180)
+        );
+        assert_eq!(
+            canvas(&ResizeSpec::fit(320, 180, ResizeFit::Inside), 1000, 1000),
+            (180, 180)
+        );
+
+        let plan = ResizeSpec::fit(320, 180, ResizeFit::Cover)
+            .plan(1000, 1000)
+            .unwrap();
+        assert_eq!(
+            (plan.width, plan.height, plan.x, plan.y),
+            (320, 320, 0, -70)
+        );
+
+        // 333 * 180 / 1000 = 59.94 must not truncate to 59
+        assert_eq!(
+            canvas(&ResizeSpec::fit(180, 180, ResizeFit::Inside), 1000, 333),
+            (180, 60)
+        );
+    }
+
+    #[test]
+    fn test_single_side_and_max() {
+        let spec = ResizeSpec {
+            width: Some(320),
+            ..ResizeSpec::default()
+        };
+        assert_eq!(canvas(&spec, 1920, 1080), (320, 180));
+
+        let spec = ResizeSpec {
+            max_width: Some(640),
+            max_height: Some(640),
+            ..ResizeSpec::default()
+        };
+        assert_eq!(canvas(&spec, 1920, 1080), (640, 360));
+        assert_eq!(canvas(&spec, 100, 50), (100, 50));
+
+        let spec = ResizeSpec {
+            height: Some(0),
+            ..ResizeSpec::default()
+        };
+        assert_eq!(spec.plan(10, 10).unwrap_err().kind(), "InvalidDimensions");
+    }
+
+    #[test]
+    fn test_resample_transparent_edge() {
+        let img = RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+
+        for filter in [
+            ResizeFilter::Triangle,
+            ResizeFilter::CatmullRom,
+            ResizeFilter::Lanczos3,
+        ] {
+            let resized = resample(&img, 3, 3, filter);
+            assert!(
+                resized
+                    .pixels()
+                    .filter(|p| p.0[3] > 8)
+                    .all(|p| p.0[0] >= 250 && p.0[1] <= 4 && p.0[2] <= 4),
+                "{filter:?} darkened the edge"
+            );
+        }
+    }
+
+    #[test]
+    fn test_resample_linear_light() {
+        let checker = RgbaImage::from_fn(64, 64, |x, y| {
+            let v = if (x + y) % 2 == 0 { 255 } else { 0 };
+            Rgba([v, v, v, 255])
+        });
+
+        // goes through the box prepass; a 50% mix of black and white is
+        // about 188 in sRGB, not 128
+        let resized = resample(&checker, 4, 4, ResizeFilter::Gaussian);
+        assert!(resized.pixels().all(|p| p.0[0].abs_diff(188) <= 2));
+    }
+}
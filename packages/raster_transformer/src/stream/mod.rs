@@ -0,0 +1,308 @@
+use crate::TransformOptions;
+use crate::core::{RGBA8ImageDataType, RGBA8StaticImageData};
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::geometry::{exif_orientation, reset_exif_orientation};
+use crate::gif::stream_gif_frames;
+use crate::limits::DecodeLimits;
+use crate::metadata::ImageMetadata;
+use crate::png::{png_metadata, stream_png_frames};
+use crate::webp::{
+    WebPAnimSink, WebPEncodeOptions, encode_static_webp, stream_webp_frames, webp_metadata,
+};
+use image::metadata::Orientation;
+use image::{Rgba, RgbaImage};
+
+/// What is only known once the last frame has been read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnimationInfo {
+    pub loop_count: u32,
+    pub bg_color: Rgba<u8>,
+}
+
+/// Takes full-canvas frames one at a time.
+pub trait FrameSink {
+    fn frame(&mut self, frame: RgbaImage, duration: u32) -> Result<()>;
+
+    /// Called once after the last frame.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn can_stream(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::WebP | ImageFormat::Png | ImageFormat::Gif
+    )
+}
+
+pub fn read_metadata(format: ImageFormat, data: &[u8]) -> Result<ImageMetadata> {
+    match format {
+        ImageFormat::WebP => webp_metadata(data),
+        ImageFormat::Png => png_metadata(data),
+        ImageFormat::Gif => Ok(ImageMetadata::default()),
+        ImageFormat::Avif => Err(TransformError::UnsupportedFormat(
+            "AVIF can't be streamed".into(),
+        )),
+    }
+}
+
+/// Feeds every frame, composited onto the canvas, to `sink` and flushes it.
+pub fn stream_frames(
+    format: ImageFormat,
+    data: &[u8],
+    limits: &DecodeLimits,
+    sink: &mut dyn FrameSink,
+) -> Result<AnimationInfo> {
+    match format {
+        ImageFormat::WebP => stream_webp_frames(data, limits, sink),
+        ImageFormat::Png => stream_png_frames(data, limits, sink),
+        ImageFormat::Gif => stream_gif_frames(data, limits, sink),
+        ImageFormat::Avif => Err(TransformError::UnsupportedFormat(
+            "AVIF can't be streamed".into(),
+        )),
+    }
+}
+
+/// `RGBA8AnimatedImageData::ease_frames`, one frame at a time.
+pub struct EaseStage<S> {
+    min_delay_ms: u32,
+    pending: Option<(RgbaImage, u32)>,
+    next: S,
+}
+
+impl<S> EaseStage<S> {
+    pub fn new(min_delay_ms: u32, next: S) -> Self {
+        Self {
+            min_delay_ms,
+            pending: None,
+            next,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.next
+    }
+}
+
+impl<S: FrameSink> FrameSink for EaseStage<S> {
+    fn frame(&mut self, frame: RgbaImage, duration: u32) -> Result<()> {
+        if duration >= self.min_delay_ms {
+            if let Some((pending, pending_duration)) = self.pending.take() {
+                self.next.frame(pending, pending_duration)?;
+            }
+            return self.next.frame(frame, duration);
+        }
+
+        match self.pending.take() {
+            Some((_, pending_duration)) => self.next.frame(frame, pending_duration + duration),
+            None => {
+                self.pending = Some((frame, duration));
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some((pending, pending_duration)) = self.pending.take() {
+            self.next.frame(pending, pending_duration)?;
+        }
+        self.next.flush()
+    }
+}
+
+/// The frame-by-frame part of [`TransformOptions`]: EXIF orientation, colour
+/// conversion, rotate, flip, crop, resize, scale and pad.
+pub struct LayoutStage<'a, S> {
+    options: &'a TransformOptions,
+    orientation: Option<Orientation>,
+    source_metadata: ImageMetadata,
+    metadata: ImageMetadata,
+    next: S,
+}
+
+impl<'a, S> LayoutStage<'a, S> {
+    pub fn new(options: &'a TransformOptions, mut metadata: ImageMetadata, next: S) -> Self {
+        let orientation = metadata.exif.as_mut().and_then(|exif| {
+            let orientation = exif_orientation(exif)?;
+            reset_exif_orientation(exif);
+            Some(orientation)
+        });
+
+        Self {
+            options,
+            orientation,
+            source_metadata: metadata.clone(),
+            metadata,
+            next,
+        }
+    }
+
+    /// The output metadata, e.g. without colour tags after `convert_to_srgb`,
+    /// and the next stage.
+    pub fn into_parts(self) -> (ImageMetadata, S) {
+        (self.metadata, self.next)
+    }
+}
+
+impl<S: FrameSink> FrameSink for LayoutStage<'_, S> {
+    fn frame(&mut self, frame: RgbaImage, duration: u32) -> Result<()> {
+        let (width, height) = frame.dimensions();
+        let mut image_data = RGBA8ImageDataType::Static(RGBA8StaticImageData {
+            data: frame,
+            width,
+            height,
+            // every frame converts from the source profile
+            metadata: self.source_metadata.clone(),
+        });
+
+        if let Some(orientation) = self.orientation {
+            image_data.apply_orientation(orientation);
+        }
+        self.options.apply_layout(&mut image_data)?;
+        self.options
+            .apply_scale(&mut image_data, self.options.scale)?;
+
+        let RGBA8ImageDataType::Static(st_img) = image_data else {
+            unreachable!("layout keeps a still a still")
+        };
+        self.metadata = st_img.metadata;
+        self.next.frame(st_img.data, duration)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.next.flush()
+    }
+}
+
+/// Holds the first frame back so that a single frame still comes out as a
+/// still WebP.
+pub struct WebPOutput {
+    quality: f32,
+    options: WebPEncodeOptions,
+    sink: WebPAnimSink,
+    first: Option<(RgbaImage, u32)>,
+    animated: bool,
+}
+
+impl WebPOutput {
+    pub fn new(quality: f32, options: &WebPEncodeOptions) -> Result<Self> {
+        Ok(Self {
+            quality,
+            options: options.clone(),
+            sink: WebPAnimSink::new(quality, options)?,
+            first: None,
+            animated: false,
+        })
+    }
+
+    pub fn finish(self, info: &AnimationInfo, metadata: ImageMetadata) -> Result<Vec<u8>> {
+        if self.animated {
+            return self
+                .sink
+                .assemble(info.loop_count, info.bg_color, &metadata);
+        }
+
+        let (data, _) = self
+            .first
+            .ok_or_else(|| TransformError::encode(ImageFormat::WebP, "no frames"))?;
+        let (width, height) = data.dimensions();
+        encode_static_webp(
+            RGBA8StaticImageData {
+                data,
+                width,
+                height,
+                metadata,
+            },
+            self.quality,
+            &self.options,
+        )
+    }
+}
+
+impl FrameSink for WebPOutput {
+    fn frame(&mut self, frame: RgbaImage, duration: u32) -> Result<()> {
+        if !self.animated && self.first.is_none() {
+            self.first = Some((frame, duration));
+            return Ok(());
+        }
+
+        if let Some((first, first_duration)) = self.first.take() {
+            self.sink.add_frame(&first, first_duration)?;
+            self.animated = true;
+        }
+        self.sink.add_frame(&frame, duration)
+    }
+}
+
+/// Runs decode, `min_delay` easing, the per-frame layout and the WebP encoder
+/// one frame at a time, so peak memory is about one canvas plus the encoder
+/// state. Only for options where [`TransformOptions::is_streamable`] holds.
+pub fn transform_to_webp(
+    input_format: ImageFormat,
+    data: &[u8],
+    options: &TransformOptions,
+) -> Result<Vec<u8>> {
+    let metadata = read_metadata(input_format, data)?;
+
+    let output = WebPOutput::new(options.encode.quality, &options.encode.webp)?;
+    let layout = LayoutStage::new(options, metadata, output);
+    let mut ease = EaseStage::new(options.min_delay, layout);
+
+    let info = stream_frames(input_format, data, &options.limits, &mut ease)?;
+
+    let (mut metadata, output) = ease.into_inner().into_parts();
+    metadata.apply_policy(options.encode.metadata);
+    output.finish(&info, metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::EncodeOptions;
+    use crate::resize::{ResizeFit, ResizeSpec};
+
+    fn transform_in_memory(
+        input_format: ImageFormat,
+        data: &[u8],
+        options: &TransformOptions,
+    ) -> Vec<u8> {
+        let mut image_data = RGBA8ImageDataType::decode(input_format, data).unwrap();
+        image_data.ease_frames(options.min_delay);
+        options.apply_layout(&mut image_data).unwrap();
+        options.apply_scale(&mut image_data, options.scale).unwrap();
+        image_data
+            .encode(ImageFormat::WebP, &options.encode)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_stream_matches_in_memory() {
+        let webp = fs::read(Path::new("./examples/example_1/example_1.webp")).unwrap();
+        let gif = RGBA8ImageDataType::decode(ImageFormat::WebP, &webp)
+            .unwrap()
+            .encode(ImageFormat::Gif, &EncodeOptions::default())
+            .unwrap();
+
+        let options = TransformOptions {
+            min_delay: 80,
+            rotate: 90,
+            resize: Some(ResizeSpec::fit(96, 96, ResizeFit::Cover)),
+            scale: 0.5,
+            ..TransformOptions::default()
+        };
+        assert!(options.is_streamable());
+
+        for (format, data) in [(ImageFormat::WebP, webp), (ImageFormat::Gif, gif)] {
+            let streamed = transform_to_webp(format, &data, &options).unwrap();
+            assert_eq!(
+                streamed,
+                transform_in_memory(format, &data, &options),
+                "{format}"
+            );
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::core::{RGBA8AnimatedImageData, RGBA8ImageDataType};
+use crate::error::{Result, TransformError};
+use serde::Deserialize;
+
+/// Chrome and Firefox play delays of 10 ms or less as 100 ms, and GIF only
+/// stores centiseconds, so 20 ms is the shortest delay that plays as written
+/// everywhere.
+pub const BROWSER_MIN_DELAY_MS: u32 = 20;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TimelineOptions {
+    /// Playback speed; 1.5 plays half again as fast.
+    pub speed: f32,
+    /// Stretch or squeeze to this total length in ms, after `speed`.
+    pub total_duration: Option<u32>,
+    /// Resample to a fixed frame rate, dropping or repeating frames.
+    pub fps: Option<f32>,
+    /// Shortest delay left after retiming, see `clamp_delays`. Only enforced
+    /// when the timeline actually changes.
+    pub min_delay: u32,
+}
+
+impl Default for TimelineOptions {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            total_duration: None,
+            fps: None,
+            min_delay: BROWSER_MIN_DELAY_MS,
+        }
+    }
+}
+
+impl TimelineOptions {
+    pub fn is_identity(&self) -> bool {
+        self.speed == 1.0 && self.total_duration.is_none() && self.fps.is_none()
+    }
+}
+
+pub(crate) fn check_rate(name: &str, value: f32) -> Result<f64> {
+    if value.is_finite() && value > 0.0 {
+        Ok(f64::from(value))
+    } else {
+        Err(TransformError::InvalidInput(format!(
+            "{} must be positive, got {}",
+            name, value
+        )))
+    }
+}
+
+impl RGBA8AnimatedImageData {
+    pub fn total_duration(&self) -> u64 {
+        self.durations.iter().map(|d| u64::from(*d)).sum()
+    }
+
+    fn end_times(&self) -> Vec<f64> {
+        self.durations
+            .iter()
+            .scan(0.0, |end, d| {
+                *end += f64::from(*d);
+                Some(*end)
+            })
+            .collect()
+    }
+
+    /// Rounds the cumulative end times rather than each delay, so rounding
+    /// error doesn't build up over long animations.
+    fn set_end_times(&mut self, end_times: impl IntoIterator<Item = f64>) {
+        let mut prev = 0;
+        self.durations = end_times
+            .into_iter()
+            .map(|end| {
+                let end = end.round() as u64;
+                let duration = end.saturating_sub(prev);
+                prev = prev.max(end);
+                duration.min(u64::from(u32::MAX)) as u32
+            })
+            .collect();
+    }
+
+    pub fn set_speed(&mut self, speed: f32) -> Result<()> {
+        let speed = check_rate("speed", speed)?;
+        let end_times = self.end_times();
+        self.set_end_times(end_times.into_iter().map(|end| end / speed));
+        Ok(())
+    }
+
+    pub fn stretch_to(&mut self, total_ms: u32) -> Result<()> {
+        let current = self.total_duration();
+        let total = f64::from(total_ms);
+        if current == 0 {
+            // nothing to scale, spread the frames evenly
+            let step = total / self.durations.len() as f64;
+            self.set_end_times((1..=self.durations.len()).map(|i| i as f64 * step));
+            return Ok(());
+        }
+
+        let factor = total / current as f64;
+        let end_times = self.end_times();
+        self.set_end_times(end_times.into_iter().map(|end| end * factor));
+        Ok(())
+    }
+
+    /// Samples the frame on screen every `1000 / fps` ms. Lower rates drop
+    /// frames, higher rates repeat them; the total length is kept.
+    pub fn resample_fps(&mut self, fps: f32) -> Result<()> {
+        self.composite();
+        let interval = 1000.0 / check_rate("fps", fps)?;
+        let total = self.total_duration() as f64;
+        if total == 0.0 {
+            return Ok(());
+        }
+
+        let end_times = self.end_times();
+        let count = (total / interval).ceil().max(1.0) as usize;
+        let frames = (0..count)
+            .map(|k| {
+                let start = k as f64 * interval;
+                let index = end_times.partition_point(|end| *end <= start);
+                self.frames[index.min(self.frames.len() - 1)].clone()
+            })
+            .collect();
+
+        self.frames = frames;
+        self.set_end_times((1..=count).map(|k| (k as f64 * interval).min(total)));
+        Ok(())
+    }
+
+    /// Lets a frame shorter than `min_delay_ms` absorb the frames after it
+    /// until it is long enough, keeping the total length. A short tail goes
+    /// to the frame before it.
+    pub fn clamp_delays(&mut self, min_delay_ms: u32) {
+        self.composite();
+        let frames = std::mem::take(&mut self.frames);
+        let durations = std::mem::take(&mut self.durations);
+
+        for (frame, duration) in frames.into_iter().zip(durations) {
+            match self.durations.last_mut() {
+                Some(last) if *last < min_delay_ms => {
+                    *last += duration;
+                }
+                _ => {
+                    self.frames.push(frame);
+                    self.durations.push(duration);
+                }
+            }
+        }
+
+        if self.durations.len() > 1 && self.durations.last() < Some(&min_delay_ms) {
+            self.frames.pop();
+            let duration = self.durations.pop().unwrap_or_default();
+            if let Some(last) = self.durations.last_mut() {
+                *last += duration;
+            }
+        }
+    }
+
+    /// Speed, then total length, then frame rate, then the delay clamp.
+    pub fn retime(&mut self, options: &TimelineOptions) -> Result<()> {
+        if options.is_identity() {
+            return Ok(());
+        }
+
+        if options.speed != 1.0 {
+            self.set_speed(options.speed)?;
+        }
+        if let Some(total_ms) = options.total_duration {
+            self.stretch_to(total_ms)?;
+        }
+        if let Some(fps) = options.fps {
+            // samples closer than the minimum delay get folded right back below
+            let max_fps = 1000.0 / options.min_delay.max(1) as f32;
+            self.resample_fps(if fps > max_fps { max_fps } else { fps })?;
+        }
+        // rounding can leave zero-length frames even without a clamp
+        self.clamp_delays(options.min_delay.max(1));
+
+        Ok(())
+    }
+}
+
+impl RGBA8ImageDataType {
+    pub fn retime(&mut self, options: &TimelineOptions) -> Result<()> {
+        match self {
+            Self::Animated(ani_img) => ani_img.retime(options),
+            Self::Static(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fixtures::{frame_ids, numbered_animation};
+
+    #[test]
+    fn test_speed_and_stretch() {
+        let mut ani_img = numbered_animation(&[100, 100, 100]);
+        ani_img.set_speed(1.5).unwrap();
+        // 66.7, 133.3, 200 rounded as end times
+        assert_eq!(ani_img.durations, vec![67, 66, 67]);
+
+        ani_img.stretch_to(600).unwrap();
+        assert_eq!(ani_img.durations, vec![201, 198, 201]);
+        assert_eq!(ani_img.total_duration(), 600);
+
+        assert!(ani_img.set_speed(0.0).is_err());
+    }
+
+    #[test]
+    fn test_resample_fps() {
+        // 25 fps drops to 10 fps: every 100 ms, take the frame on screen
+        let mut ani_img = numbered_animation(&[40; 10]);
+        ani_img.resample_fps(10.0).unwrap();
+        assert_eq!(frame_ids(&ani_img), vec![0, 2, 5, 7]);
+        assert_eq!(ani_img.durations, vec![100, 100, 100, 100]);
+
+        // 5 fps doubles to 10 fps by repeating frames
+        let mut ani_img = numbered_animation(&[200, 200]);
+        ani_img.resample_fps(10.0).unwrap();
+        assert_eq!(frame_ids(&ani_img), vec![0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_retime_clamps_to_browser_delay() {
+        let mut ani_img = numbered_animation(&[30, 30, 30, 30]);
+        ani_img
+            .retime(&TimelineOptions {
+                speed: 3.0,
+                ..TimelineOptions::default()
+            })
+            .unwrap();
+
+        // 10 ms frames would play at 100 ms, fold them pairwise instead
+        assert_eq!(frame_ids(&ani_img), vec![0, 2]);
+        assert_eq!(ani_img.durations, vec![20, 20]);
+    }
+
+    #[test]
+    fn test_retime_caps_fps_at_min_delay() {
+        let mut ani_img = numbered_animation(&[60_000]);
+        ani_img
+            .retime(&TimelineOptions {
+                fps: Some(1000.0),
+                ..TimelineOptions::default()
+            })
+            .unwrap();
+
+        assert_eq!(ani_img.frames.len(), 3000);
+        assert!(ani_img.durations.iter().all(|&duration| duration == 20));
+
+        let mut ani_img = numbered_animation(&[100]);
+        let options = TimelineOptions {
+            fps: Some(f32::NAN),
+            ..TimelineOptions::default()
+        };
+        assert!(ani_img.retime(&options).is_err());
+    }
+}
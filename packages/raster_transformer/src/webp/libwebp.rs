@@ -0,0 +1,1283 @@
+//! The libwebp backend, through `libwebp-sys2`.
+
+use super::{
+    WebPAnimEncodeOptions, WebPEncodeOptions, WebPEncodePreset, WebPFrameMode, WebPFrameReport,
+};
+use crate::core::{
+    BlendOp, DisposeOp, FrameRegion, RGBA8AnimatedImageData, RGBA8ImageDataType,
+    RGBA8StaticImageData,
+};
+use crate::delta::Compositor;
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::limits::{DecodeLimits, LimitTracker};
+use crate::metadata::ImageMetadata;
+use crate::stream::{AnimationInfo, FrameSink};
+use image::{EncodableLayout, Rgba, RgbaImage};
+use libwebp_sys::{
+    MODE_RGBA, VP8_ENC_ERROR_BAD_DIMENSION, VP8_ENC_ERROR_BAD_WRITE,
+    VP8_ENC_ERROR_BITSTREAM_OUT_OF_MEMORY, VP8_ENC_ERROR_FILE_TOO_BIG,
+    VP8_ENC_ERROR_INVALID_CONFIGURATION, VP8_ENC_ERROR_LAST, VP8_ENC_ERROR_NULL_PARAMETER,
+    VP8_ENC_ERROR_OUT_OF_MEMORY, VP8_ENC_ERROR_PARTITION_OVERFLOW,
+    VP8_ENC_ERROR_PARTITION0_OVERFLOW, VP8_ENC_ERROR_USER_ABORT, VP8_ENC_OK,
+    VP8_STATUS_BITSTREAM_ERROR, VP8_STATUS_INVALID_PARAM, VP8_STATUS_NOT_ENOUGH_DATA,
+    VP8_STATUS_OK, VP8_STATUS_OUT_OF_MEMORY, VP8_STATUS_SUSPENDED, VP8_STATUS_UNSUPPORTED_FEATURE,
+    VP8_STATUS_USER_ABORT, VP8StatusCode, WEBP_FF_BACKGROUND_COLOR, WEBP_FF_CANVAS_HEIGHT,
+    WEBP_FF_CANVAS_WIDTH, WEBP_FF_FORMAT_FLAGS, WEBP_FF_FRAME_COUNT, WEBP_FF_LOOP_COUNT,
+    WEBP_MUX_BAD_DATA, WEBP_MUX_BLEND, WEBP_MUX_DISPOSE_BACKGROUND, WEBP_MUX_INVALID_ARGUMENT,
+    WEBP_MUX_MEMORY_ERROR, WEBP_MUX_NOT_ENOUGH_DATA, WEBP_MUX_NOT_FOUND, WEBP_MUX_OK,
+    WebPAnimEncoder, WebPAnimEncoderAdd, WebPAnimEncoderAssemble, WebPAnimEncoderDelete,
+    WebPAnimEncoderGetError, WebPAnimEncoderNew, WebPAnimEncoderOptions, WebPBitstreamFeatures,
+    WebPChunkIterator, WebPConfig, WebPData, WebPDataClear, WebPDataInit, WebPDecode,
+    WebPDecoderConfig, WebPDemux, WebPDemuxDelete, WebPDemuxGetChunk, WebPDemuxGetFrame,
+    WebPDemuxGetI, WebPDemuxNextFrame, WebPDemuxReleaseChunkIterator, WebPDemuxReleaseIterator,
+    WebPDemuxer, WebPEncode, WebPEncodingError, WebPFormatFeature, WebPFreeDecBuffer,
+    WebPGetFeatures, WebPInitDecoderConfig, WebPIterator, WebPMemoryWrite, WebPMemoryWriter,
+    WebPMemoryWriterClear, WebPMemoryWriterInit, WebPMux, WebPMuxAnimBlend, WebPMuxAnimDispose,
+    WebPMuxAnimParams, WebPMuxAssemble, WebPMuxCreate, WebPMuxDelete, WebPMuxError,
+    WebPMuxSetAnimationParams, WebPMuxSetChunk, WebPPicture, WebPPictureFree,
+    WebPPictureImportRGBA, WebPPictureInit,
+};
+use libwebp_sys::{
+    WEBP_PRESET_DEFAULT, WEBP_PRESET_DRAWING, WEBP_PRESET_ICON, WEBP_PRESET_PHOTO,
+    WEBP_PRESET_PICTURE, WEBP_PRESET_TEXT, WebPAnimEncoderOptionsInit, WebPConfigPreset,
+    WebPValidateConfig,
+};
+use std::{
+    ffi::{CStr, c_int, c_void},
+    mem::MaybeUninit,
+    ptr,
+};
+
+pub fn webp_encoding_errcode_to_string(error_code: WebPEncodingError) -> &'static str {
+    match error_code {
+        error_code if error_code == VP8_ENC_ERROR_OUT_OF_MEMORY => "out of memory",
+        error_code if error_code == VP8_ENC_ERROR_BITSTREAM_OUT_OF_MEMORY => {
+            "not enough memory to flush bits"
+        }
+        error_code if error_code == VP8_ENC_ERROR_NULL_PARAMETER => "NULL parameter",
+        error_code if error_code == VP8_ENC_ERROR_INVALID_CONFIGURATION => "invalid configuration",
+        error_code if error_code == VP8_ENC_ERROR_BAD_DIMENSION => "bad image dimensions",
+        error_code if error_code == VP8_ENC_ERROR_PARTITION0_OVERFLOW => {
+            "partition is bigger than 512K"
+        }
+        error_code if error_code == VP8_ENC_ERROR_PARTITION_OVERFLOW => {
+            "partition is bigger than 16M"
+        }
+        error_code if error_code == VP8_ENC_ERROR_BAD_WRITE => "unable to flush bytes",
+        error_code if error_code == VP8_ENC_ERROR_FILE_TOO_BIG => "file is larger than 4GiB",
+        error_code if error_code == VP8_ENC_ERROR_USER_ABORT => "user aborted encoding",
+        error_code if error_code == VP8_ENC_ERROR_LAST => "list terminator",
+        _ => "unknown error",
+    }
+}
+
+pub fn webp_decoding_errcode_to_string(error_code: VP8StatusCode) -> &'static str {
+    match error_code {
+        error_code if error_code == VP8_STATUS_OUT_OF_MEMORY => "out of memory",
+        error_code if error_code == VP8_STATUS_INVALID_PARAM => "invalid param",
+        error_code if error_code == VP8_STATUS_BITSTREAM_ERROR => "bitstream error",
+        error_code if error_code == VP8_STATUS_UNSUPPORTED_FEATURE => "unsupported feature",
+        error_code if error_code == VP8_STATUS_SUSPENDED => "suspended",
+        error_code if error_code == VP8_STATUS_USER_ABORT => "user abort",
+        error_code if error_code == VP8_STATUS_NOT_ENOUGH_DATA => "not enough data",
+        _ => "unknown error",
+    }
+}
+
+pub fn webp_mux_errcode_to_string(error_code: WebPMuxError) -> &'static str {
+    match error_code {
+        error_code if error_code == WEBP_MUX_NOT_FOUND => "mux not found",
+        error_code if error_code == WEBP_MUX_INVALID_ARGUMENT => "mux invalid argument",
+        error_code if error_code == WEBP_MUX_BAD_DATA => "mux bad data",
+        error_code if error_code == WEBP_MUX_MEMORY_ERROR => "mux memory error",
+        error_code if error_code == WEBP_MUX_NOT_ENOUGH_DATA => "mux not enoungh data",
+        _ => "unknown error",
+    }
+}
+
+pub fn webp_decoding_error(prefix: &str, error_code: VP8StatusCode) -> TransformError {
+    TransformError::Decode {
+        codec: ImageFormat::WebP,
+        code: Some(error_code as i32),
+        message: format!(
+            "{}: {}",
+            prefix,
+            webp_decoding_errcode_to_string(error_code)
+        ),
+    }
+}
+
+pub fn webp_encoding_error(prefix: &str, error_code: WebPEncodingError) -> TransformError {
+    TransformError::Encode {
+        codec: ImageFormat::WebP,
+        code: Some(error_code as i32),
+        message: format!(
+            "{}: {}",
+            prefix,
+            webp_encoding_errcode_to_string(error_code)
+        ),
+    }
+}
+
+pub fn webp_muxing_error(prefix: &str, error_code: WebPMuxError) -> TransformError {
+    TransformError::Mux {
+        code: Some(error_code),
+        message: format!("{}: {}", prefix, webp_mux_errcode_to_string(error_code)),
+    }
+}
+
+pub fn webp_check_decoding(prefix: &str, error_code: VP8StatusCode) -> Result<()> {
+    if error_code != VP8_STATUS_OK {
+        return Err(webp_decoding_error(prefix, error_code));
+    }
+    Ok(())
+}
+
+pub fn webp_check_encoding(prefix: &str, error_code: WebPEncodingError) -> Result<()> {
+    if error_code != VP8_ENC_OK {
+        return Err(webp_encoding_error(prefix, error_code));
+    }
+    Ok(())
+}
+
+pub fn webp_check_muxing(prefix: &str, error_code: WebPMuxError) -> Result<()> {
+    if error_code != WEBP_MUX_OK {
+        return Err(webp_muxing_error(prefix, error_code));
+    }
+    Ok(())
+}
+
+pub struct WebPDataAdapter {
+    pub webp_data: MaybeUninit<WebPData>,
+    _data: Option<Vec<u8>>,
+}
+
+impl WebPDataAdapter {
+    pub fn from_empty() -> Self {
+        Self::from_slice(&[])
+    }
+
+    pub fn from_slice(data: &[u8]) -> Self {
+        let cloned = Vec::from(data);
+        let mut webp_data = MaybeUninit::<WebPData>::uninit();
+        unsafe {
+            WebPDataInit(webp_data.as_mut_ptr());
+
+            {
+                let webp_data = webp_data.assume_init_mut();
+                webp_data.bytes = cloned.as_ptr();
+                webp_data.size = cloned.len();
+            }
+
+            Self {
+                _data: Some(cloned),
+                webp_data,
+            }
+        }
+    }
+
+    pub fn new(webp_data: MaybeUninit<WebPData>) -> Self {
+        Self {
+            webp_data,
+            _data: None,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The returned pointer borrows `self` and must not outlive it.
+    pub unsafe fn as_ptr(&self) -> *const WebPData {
+        self.webp_data.as_ptr()
+    }
+
+    /// # Safety
+    ///
+    /// The returned pointer borrows `self` and must not outlive it.
+    pub unsafe fn as_mut_ptr(&mut self) -> *mut WebPData {
+        self.webp_data.as_mut_ptr()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        unsafe {
+            let webp_data = self.webp_data.assume_init_ref();
+            // `WebPDataInit` leaves a null pointer behind
+            if webp_data.bytes.is_null() {
+                return vec![];
+            }
+
+            std::slice::from_raw_parts(webp_data.bytes, webp_data.size).to_vec()
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `self.webp_data` must have been initialised by `WebPDataInit` or a libwebp writer.
+    pub unsafe fn mut_bytes(&mut self) -> *mut u8 {
+        unsafe { self.webp_data.assume_init_mut().bytes as *mut _ }
+    }
+
+    /// # Safety
+    ///
+    /// `self.webp_data` must have been initialised by `WebPDataInit` or a libwebp writer.
+    pub unsafe fn bytes(&self) -> *const u8 {
+        unsafe { self.webp_data.assume_init_ref().bytes }
+    }
+
+    /// # Safety
+    ///
+    /// `self.webp_data` must have been initialised by `WebPDataInit` or a libwebp writer.
+    pub unsafe fn size(&self) -> usize {
+        unsafe { self.webp_data.assume_init_ref().size }
+    }
+}
+
+impl Drop for WebPDataAdapter {
+    fn drop(&mut self) {
+        if self._data.is_none() {
+            unsafe { WebPDataClear(self.webp_data.as_mut_ptr()) }
+        };
+    }
+}
+
+pub struct WebPMemoryWriterAdapter {
+    wrt: MaybeUninit<WebPMemoryWriter>,
+}
+
+impl WebPMemoryWriterAdapter {
+    pub fn new() -> Self {
+        let mut wrt = MaybeUninit::<WebPMemoryWriter>::uninit();
+
+        unsafe {
+            WebPMemoryWriterInit(wrt.as_mut_ptr());
+        }
+        Self { wrt }
+    }
+
+    pub(crate) extern "C" fn memory_writer(
+        data: *const u8,
+        data_size: usize,
+        picture: *const WebPPicture,
+    ) -> c_int {
+        unsafe { WebPMemoryWrite(data, data_size, picture) }
+    }
+
+    /// # Safety
+    ///
+    /// The returned pointer borrows `self` and must not outlive it.
+    pub unsafe fn as_custom_ptr(&mut self) -> *mut c_void {
+        self.wrt.as_mut_ptr() as *mut _
+    }
+}
+
+impl Default for WebPMemoryWriterAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<WebPMemoryWriterAdapter> for Vec<u8> {
+    fn from(val: WebPMemoryWriterAdapter) -> Self {
+        unsafe {
+            let wrt = val.wrt.assume_init_ref();
+            let mut dst = Vec::<u8>::with_capacity(wrt.max_size);
+            std::ptr::copy(wrt.mem, dst.as_mut_ptr(), wrt.size);
+
+            dst.set_len(wrt.size);
+
+            dst
+        }
+    }
+}
+
+impl Drop for WebPMemoryWriterAdapter {
+    fn drop(&mut self) {
+        unsafe { WebPMemoryWriterClear(self.wrt.as_mut_ptr()) }
+    }
+}
+
+pub struct WebPPictureAdapter {
+    pub pic: MaybeUninit<WebPPicture>,
+    pub wrt: WebPMemoryWriterAdapter,
+}
+
+impl WebPPictureAdapter {
+    pub fn from_rgba8(data: &[u8], width: u32, height: u32) -> Result<Self> {
+        let stride = width * 4;
+        let mut pic = MaybeUninit::<WebPPicture>::uninit();
+
+        unsafe {
+            if WebPPictureInit(pic.as_mut_ptr()) == 0 {
+                return Err(webp_encoding_error(
+                    "WebPPictureInit error",
+                    pic.assume_init_ref().error_code,
+                ));
+            }
+        }
+
+        let wrt = WebPMemoryWriterAdapter::new();
+
+        unsafe {
+            let pic = pic.assume_init_mut();
+
+            pic.use_argb = 1;
+
+            pic.width = width as i32;
+            pic.height = height as i32;
+            pic.writer = Some(WebPMemoryWriterAdapter::memory_writer);
+
+            let len = WebPPictureImportRGBA(pic as *mut _, data.as_ptr(), stride as i32);
+
+            if len == 0 {
+                WebPPictureFree(pic as *mut _);
+                return Err(webp_encoding_error(
+                    "WebPPictureImportRGBA error",
+                    pic.error_code,
+                ));
+            }
+        }
+
+        Ok(Self { pic, wrt })
+    }
+
+    pub fn encode(&mut self, config: &WebPConfig) -> Result<()> {
+        unsafe {
+            // the writer moves with `self`, so its address is only bound right before encoding
+            self.pic.assume_init_mut().custom_ptr = self.wrt.as_custom_ptr();
+
+            if WebPEncode(config as *const _, self.as_mut_ptr()) == 0 {
+                return Err(webp_encoding_error(
+                    "WebPEncode error",
+                    self.pic.assume_init_ref().error_code,
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The returned pointer borrows `self` and must not outlive it.
+    pub unsafe fn as_mut_ptr(&mut self) -> *mut WebPPicture {
+        self.pic.as_mut_ptr()
+    }
+
+    /// # Safety
+    ///
+    /// The returned pointer borrows `self` and must not outlive it.
+    pub unsafe fn as_ptr(&self) -> *const WebPPicture {
+        self.pic.as_ptr()
+    }
+
+    pub fn get_error(&self) -> String {
+        let error_code = unsafe { self.pic.assume_init_ref().error_code };
+        webp_encoding_errcode_to_string(error_code).into()
+    }
+}
+
+impl From<WebPPictureAdapter> for Vec<u8> {
+    fn from(mut val: WebPPictureAdapter) -> Self {
+        std::mem::take(&mut val.wrt).into()
+    }
+}
+
+impl Drop for WebPPictureAdapter {
+    fn drop(&mut self) {
+        unsafe { WebPPictureFree(self.as_mut_ptr()) }
+    }
+}
+
+pub struct WebPAnimEncoderAdapter(pub *mut WebPAnimEncoder);
+
+impl WebPAnimEncoderAdapter {
+    /// Fails on dimensions libwebp rejects or when it runs out of memory.
+    pub fn new(width: u32, height: u32, enc_options: &WebPAnimEncoderOptions) -> Result<Self> {
+        let (Ok(w), Ok(h)) = (i32::try_from(width), i32::try_from(height)) else {
+            return Err(TransformError::InvalidDimensions { width, height });
+        };
+
+        let enc = unsafe { WebPAnimEncoderNew(w, h, enc_options as *const WebPAnimEncoderOptions) };
+        if enc.is_null() {
+            return Err(TransformError::encode(
+                ImageFormat::WebP,
+                "WebPAnimEncoderNew error",
+            ));
+        }
+
+        Ok(Self(enc))
+    }
+
+    pub fn assemble(&mut self) -> Result<WebPDataAdapter> {
+        unsafe {
+            let mut webp_data = MaybeUninit::<WebPData>::uninit();
+
+            if WebPAnimEncoderAssemble(self.0, webp_data.as_mut_ptr()) == 0 {
+                return Err(TransformError::encode(
+                    ImageFormat::WebP,
+                    format!("WebPAnimEncoderAssemble error: {}", self.get_error()),
+                ));
+            }
+
+            Ok(WebPDataAdapter::new(webp_data))
+        }
+    }
+
+    pub fn add_rgba8_frame(
+        &mut self,
+        frame: &[u8],
+        width: u32,
+        height: u32,
+        timestamp_ms: i32,
+        config: &WebPConfig,
+    ) -> Result<()> {
+        unsafe {
+            let mut frame_pic = WebPPictureAdapter::from_rgba8(frame, width, height)?;
+
+            if WebPAnimEncoderAdd(self.0, frame_pic.as_mut_ptr(), timestamp_ms, config) == 0 {
+                return Err(TransformError::encode(
+                    ImageFormat::WebP,
+                    format!("WebPAnimEncoderAdd error: {}", self.get_error()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_end(&mut self, timestamp_ms: i32) -> Result<()> {
+        unsafe {
+            if WebPAnimEncoderAdd(self.0, ptr::null_mut(), timestamp_ms, ptr::null()) == 0 {
+                return Err(TransformError::encode(
+                    ImageFormat::WebP,
+                    format!("WebPAnimEncoderAdd error: {}", self.get_error()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_error(&self) -> String {
+        unsafe {
+            CStr::from_ptr(WebPAnimEncoderGetError(self.0))
+                .to_str()
+                .unwrap_or("unknown error")
+                .into()
+        }
+    }
+}
+
+impl Drop for WebPAnimEncoderAdapter {
+    fn drop(&mut self) {
+        unsafe { WebPAnimEncoderDelete(self.0) }
+    }
+}
+
+pub struct WebPMuxAdapter<'a> {
+    mux: *mut WebPMux,
+    webp_data: &'a mut WebPDataAdapter,
+}
+
+impl<'a> WebPMuxAdapter<'a> {
+    pub fn new(webp_data: &'a mut WebPDataAdapter) -> Self {
+        unsafe {
+            Self {
+                mux: WebPMuxCreate(webp_data.webp_data.as_ptr(), 1),
+                webp_data,
+            }
+        }
+    }
+
+    pub fn set_animation_params(&mut self, anim_params: &WebPMuxAnimParams) -> Result<()> {
+        unsafe {
+            webp_check_muxing(
+                "WebPMuxSetAnimationParams error",
+                WebPMuxSetAnimationParams(self.mux, anim_params as *const _),
+            )
+        }
+    }
+
+    pub fn set_chunk(&mut self, fourcc: &[u8; 4], data: &[u8]) -> Result<()> {
+        let fourcc = [fourcc[0], fourcc[1], fourcc[2], fourcc[3], 0];
+        let chunk = WebPDataAdapter::from_slice(data);
+
+        unsafe {
+            webp_check_muxing(
+                "WebPMuxSetChunk error",
+                WebPMuxSetChunk(self.mux, fourcc.as_ptr().cast(), chunk.as_ptr(), 1),
+            )
+        }
+    }
+
+    pub fn set_metadata(&mut self, metadata: &ImageMetadata) -> Result<()> {
+        for (fourcc, data) in [
+            (b"ICCP", &metadata.icc),
+            (b"EXIF", &metadata.exif),
+            (b"XMP ", &metadata.xmp),
+        ] {
+            if let Some(data) = data {
+                self.set_chunk(fourcc, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Assembles into a fresh libwebp buffer and copies it out, leaving the
+    /// input untouched.
+    pub fn assemble_to_vec(&mut self) -> Result<Vec<u8>> {
+        let mut webp_data = MaybeUninit::<WebPData>::uninit();
+
+        unsafe {
+            WebPDataInit(webp_data.as_mut_ptr());
+            let mut output = WebPDataAdapter::new(webp_data);
+
+            webp_check_muxing(
+                "WebPMuxAssemble error",
+                WebPMuxAssemble(self.mux, output.as_mut_ptr()),
+            )?;
+
+            Ok(output.to_vec())
+        }
+    }
+
+    pub fn assemble(&mut self) -> Result<()> {
+        unsafe {
+            webp_check_muxing(
+                "WebPMuxAssemble error",
+                WebPMuxAssemble(self.mux, self.webp_data.as_mut_ptr()),
+            )
+        }
+    }
+}
+
+impl<'a> Drop for WebPMuxAdapter<'a> {
+    fn drop(&mut self) {
+        unsafe { WebPMuxDelete(self.mux) }
+    }
+}
+
+pub struct WebPAnimFrameAdapter {
+    pub data: RgbaImage,
+    pub duration: u32,
+    pub timestamp: u32,
+    pub has_alpha: bool,
+    pub blend_mode: WebPMuxAnimBlend,
+    pub dispose_method: WebPMuxAnimDispose,
+    pub frame_x: u32,
+    pub frame_y: u32,
+    pub frame_w: u32,
+    pub frame_h: u32,
+}
+
+impl WebPAnimFrameAdapter {
+    pub fn region(&self) -> FrameRegion {
+        FrameRegion {
+            x: self.frame_x,
+            y: self.frame_y,
+            blend: if self.blend_mode == WEBP_MUX_BLEND {
+                BlendOp::Over
+            } else {
+                BlendOp::Source
+            },
+            dispose: if self.dispose_method == WEBP_MUX_DISPOSE_BACKGROUND {
+                DisposeOp::Background
+            } else {
+                DisposeOp::None
+            },
+        }
+    }
+}
+
+pub struct WebPAnimAdapter {
+    pub frames: Vec<WebPAnimFrameAdapter>,
+    pub width: u32,
+    pub height: u32,
+    pub bgcolor: u32,
+    pub loop_count: u32,
+    pub frame_count: u32,
+}
+
+pub struct WebPDemuxAdapter<'a> {
+    pub demux: *mut WebPDemuxer,
+    pub webp_data: &'a WebPDataAdapter,
+}
+
+impl<'a> WebPDemuxAdapter<'a> {
+    pub fn new(webp_data: &'a WebPDataAdapter) -> Self {
+        unsafe {
+            let demux = WebPDemux(webp_data.as_ptr());
+            Self { demux, webp_data }
+        }
+    }
+
+    pub fn get_info(&self, feature: WebPFormatFeature) -> u32 {
+        unsafe { WebPDemuxGetI(self.demux, feature) }
+    }
+
+    pub fn get_bg_color(&self) -> Rgba<u8> {
+        let [b, g, r, a] = self.get_info(WEBP_FF_BACKGROUND_COLOR).to_be_bytes();
+        Rgba([r, g, b, a])
+    }
+
+    /// Contents of the first chunk with the given FourCC, e.g. `EXIF`.
+    pub fn get_chunk(&self, fourcc: &[u8; 4]) -> Option<Vec<u8>> {
+        let fourcc = [fourcc[0], fourcc[1], fourcc[2], fourcc[3], 0];
+        let mut iter = MaybeUninit::<WebPChunkIterator>::uninit();
+
+        unsafe {
+            if WebPDemuxGetChunk(self.demux, fourcc.as_ptr().cast(), 1, iter.as_mut_ptr()) == 0 {
+                return None;
+            }
+
+            let iter = iter.assume_init_mut();
+            let chunk = std::slice::from_raw_parts(iter.chunk.bytes, iter.chunk.size).to_vec();
+            WebPDemuxReleaseChunkIterator(iter as *mut _);
+
+            Some(chunk)
+        }
+    }
+
+    pub fn get_metadata(&self) -> ImageMetadata {
+        ImageMetadata {
+            icc: self.get_chunk(b"ICCP"),
+            exif: self.get_chunk(b"EXIF"),
+            xmp: self.get_chunk(b"XMP "),
+            ..ImageMetadata::default()
+        }
+    }
+
+    pub fn frames_iter(&self) -> WebPAnimIteratorAdapter<'_, 'a> {
+        let frame_count = self.get_info(WEBP_FF_FRAME_COUNT);
+
+        if frame_count < 1 {
+            return WebPAnimIteratorAdapter::new(self, None);
+        }
+
+        WebPAnimIteratorAdapter::new(self, Some(1))
+    }
+
+    pub fn frame_reports(&self) -> Vec<WebPFrameReport> {
+        let mut reports = vec![];
+        let mut iter = MaybeUninit::<WebPIterator>::uninit();
+
+        unsafe {
+            if WebPDemuxGetFrame(self.demux, 1, iter.as_mut_ptr()) == 0 {
+                return reports;
+            }
+
+            let iter = iter.assume_init_mut();
+            loop {
+                let fragment = std::slice::from_raw_parts(iter.fragment.bytes, iter.fragment.size);
+                // lossy frames start with `ALPH` or `VP8 `, lossless ones with `VP8L`
+                let mode = if fragment.starts_with(b"VP8L") {
+                    WebPFrameMode::Lossless
+                } else {
+                    WebPFrameMode::Lossy
+                };
+
+                reports.push(WebPFrameReport {
+                    mode,
+                    duration: iter.duration as u32,
+                    x_offset: iter.x_offset as u32,
+                    y_offset: iter.y_offset as u32,
+                    width: iter.width as u32,
+                    height: iter.height as u32,
+                });
+
+                if WebPDemuxNextFrame(iter as *mut _) == 0 {
+                    break;
+                }
+            }
+            WebPDemuxReleaseIterator(iter as *mut _);
+        }
+
+        reports
+    }
+}
+
+impl<'a> Drop for WebPDemuxAdapter<'a> {
+    fn drop(&mut self) {
+        unsafe { WebPDemuxDelete(self.demux) }
+    }
+}
+
+#[allow(dead_code)]
+pub struct WebPAnimIteratorAdapter<'a, 'b> {
+    timestamp: u32,
+    iter: Option<MaybeUninit<WebPIterator>>,
+    demux: &'a WebPDemuxAdapter<'b>,
+    width: u32,
+    height: u32,
+    bg_color: Rgba<u8>,
+    loop_count: u32,
+    frame_count: u32,
+    flags: u32,
+}
+
+impl<'a, 'b> WebPAnimIteratorAdapter<'a, 'b> {
+    pub fn new(demux: &'a WebPDemuxAdapter<'b>, iter: Option<u32>) -> Self {
+        let iter = if let Some(num) = iter {
+            let mut iter = MaybeUninit::<WebPIterator>::uninit();
+            unsafe {
+                if WebPDemuxGetFrame(demux.demux, num as i32, iter.as_mut_ptr()) != 0 {
+                    Some(iter)
+                } else {
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let width = demux.get_info(WEBP_FF_CANVAS_WIDTH);
+        let height = demux.get_info(WEBP_FF_CANVAS_HEIGHT);
+        let frame_count = demux.get_info(WEBP_FF_FRAME_COUNT);
+        let loop_count = demux.get_info(WEBP_FF_LOOP_COUNT);
+        let bg_color = demux.get_bg_color();
+        let flags = demux.get_info(WEBP_FF_FORMAT_FLAGS);
+
+        Self {
+            timestamp: 0,
+            iter,
+            demux,
+            width,
+            height,
+            frame_count,
+            loop_count,
+            bg_color,
+            flags,
+        }
+    }
+
+    /// Decodes the current fragment as-is; compositing is left to the shared
+    /// model, see `RGBA8AnimatedImageData::composite`.
+    fn get_ani_frame(&mut self) -> Result<WebPAnimFrameAdapter> {
+        unsafe {
+            let iter = self.iter.as_ref().unwrap().assume_init_ref();
+            let frame_x = iter.x_offset as u32;
+            let frame_y = iter.y_offset as u32;
+            let frame_w = iter.width as u32;
+            let frame_h = iter.height as u32;
+            let frame_data = iter.fragment;
+
+            let buf_size = frame_w as usize * frame_h as usize * 4;
+            let mut buf = Vec::with_capacity(buf_size);
+
+            let mut config = MaybeUninit::<WebPDecoderConfig>::uninit();
+
+            if WebPInitDecoderConfig(config.as_mut_ptr()) == 0 {
+                return Err(TransformError::decode(
+                    ImageFormat::WebP,
+                    "WebPInitDecoderConfig error",
+                ));
+            }
+
+            let config = config.assume_init_mut();
+
+            config.options.use_threads = 1;
+            config.output.colorspace = MODE_RGBA;
+            config.output.u.RGBA.rgba = buf.as_mut_ptr();
+            config.output.u.RGBA.stride = (frame_w * 4) as i32;
+            config.output.u.RGBA.size = buf_size;
+            config.output.is_external_memory = 1;
+
+            webp_check_decoding(
+                "WebPDecode error",
+                WebPDecode(frame_data.bytes, frame_data.size, config as *mut _),
+            )?;
+
+            buf.set_len(buf_size);
+
+            let data = RgbaImage::from_raw(frame_w, frame_h, buf)
+                .ok_or_else(|| TransformError::decode(ImageFormat::WebP, "failed to get frame"))?;
+
+            Ok(WebPAnimFrameAdapter {
+                data,
+                duration: iter.duration as u32,
+                timestamp: self.timestamp.saturating_add(iter.duration as u32),
+                has_alpha: iter.has_alpha != 0,
+                blend_mode: iter.blend_method,
+                dispose_method: iter.dispose_method,
+                frame_x,
+                frame_y,
+                frame_w,
+                frame_h,
+            })
+        }
+    }
+
+    /// Like `next`, but checks the frame's header against `tracker` before
+    /// its pixels are decoded.
+    pub fn next_checked(
+        &mut self,
+        tracker: &mut LimitTracker,
+    ) -> Option<Result<WebPAnimFrameAdapter>> {
+        let iter = unsafe { self.iter.as_ref()?.assume_init_ref() };
+        if let Err(err) = tracker.frame(iter.width as u32, iter.height as u32, iter.duration as u32)
+        {
+            return Some(Err(err));
+        }
+        self.next()
+    }
+}
+
+impl<'a, 'b> Iterator for WebPAnimIteratorAdapter<'a, 'b> {
+    type Item = Result<WebPAnimFrameAdapter>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.as_ref()?;
+        let item = self.get_ani_frame();
+        if let Some(iter) = self.iter.as_mut() {
+            unsafe {
+                let iter = iter.assume_init_mut();
+                self.timestamp = self.timestamp.saturating_add(iter.duration as u32);
+                if WebPDemuxNextFrame(iter as *mut _) == 0 {
+                    WebPDemuxReleaseIterator(iter as *mut _);
+                    self.iter = None;
+                }
+            }
+        }
+        Some(item)
+    }
+}
+
+impl<'a, 'b> Drop for WebPAnimIteratorAdapter<'a, 'b> {
+    fn drop(&mut self) {
+        if let Some(iter) = self.iter.as_mut() {
+            unsafe {
+                WebPDemuxReleaseIterator(iter.assume_init_mut() as *mut _);
+            }
+        }
+    }
+}
+
+struct WebPDecoderAdapter<'a> {
+    features: MaybeUninit<WebPBitstreamFeatures>,
+    webp_data: &'a WebPDataAdapter,
+}
+
+impl<'a> WebPDecoderAdapter<'a> {
+    pub fn new(webp_data: &'a WebPDataAdapter) -> Result<Self> {
+        unsafe {
+            let mut features = MaybeUninit::<WebPBitstreamFeatures>::uninit();
+
+            webp_check_decoding(
+                "WebPGetFeatures error",
+                WebPGetFeatures(webp_data.bytes(), webp_data.size(), features.as_mut_ptr()),
+            )?;
+
+            Ok(WebPDecoderAdapter {
+                features,
+                webp_data,
+            })
+        }
+    }
+
+    pub fn has_animation(&self) -> bool {
+        unsafe { self.features.assume_init_ref().has_animation != 0 }
+    }
+
+    pub fn decode_to_rgba8(&mut self, config: &mut WebPDecoderConfig) -> Result<Vec<u8>> {
+        let features = unsafe { self.features.assume_init_ref() };
+        let width = features.width;
+        let height = features.height;
+        let buf_size = width as usize * height as usize * 4;
+        let mut buf = Vec::with_capacity(buf_size);
+
+        config.output.colorspace = MODE_RGBA;
+        config.output.u.RGBA.rgba = buf.as_mut_ptr();
+        config.output.u.RGBA.stride = width * 4;
+        config.output.u.RGBA.size = buf_size;
+        config.output.is_external_memory = 1;
+        config.options.use_threads = 1;
+
+        unsafe {
+            webp_check_decoding(
+                "WebPDecode error",
+                WebPDecode(
+                    self.webp_data.bytes(),
+                    self.webp_data.size(),
+                    config as *mut _,
+                ),
+            )?;
+
+            buf.set_len(buf_size);
+
+            WebPFreeDecBuffer(&mut config.output);
+
+            Ok(buf)
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        unsafe { self.features.assume_init_ref().width as u32 }
+    }
+
+    pub fn height(&self) -> u32 {
+        unsafe { self.features.assume_init_ref().height as u32 }
+    }
+}
+
+pub fn decode_webp_regions(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8ImageDataType> {
+    let webp_data = WebPDataAdapter::from_slice(data);
+
+    let mut base_dec = WebPDecoderAdapter::new(&webp_data)?;
+
+    let width = base_dec.width();
+    let height = base_dec.height();
+
+    let demux = WebPDemuxAdapter::new(&webp_data);
+    if demux.demux.is_null() {
+        return Err(TransformError::decode(ImageFormat::WebP, "WebPDemux error"));
+    }
+
+    let mut tracker = webp_check_limits(&base_dec, &demux, limits)?;
+    let mut image_data = decode_webp_pixels(&mut base_dec, &demux, &mut tracker, width, height)?;
+
+    *image_data.metadata_mut() = demux.get_metadata();
+    image_data.apply_exif_orientation();
+
+    Ok(image_data)
+}
+
+pub fn webp_metadata(data: &[u8]) -> Result<ImageMetadata> {
+    let webp_data = WebPDataAdapter::from_slice(data);
+    let demux = WebPDemuxAdapter::new(&webp_data);
+    if demux.demux.is_null() {
+        return Err(TransformError::decode(ImageFormat::WebP, "WebPDemux error"));
+    }
+
+    Ok(demux.get_metadata())
+}
+
+/// Decodes and composites one frame at a time into `sink`, holding a single
+/// canvas; a still image is one frame of 0 ms.
+pub fn stream_webp_frames(
+    data: &[u8],
+    limits: &DecodeLimits,
+    sink: &mut dyn FrameSink,
+) -> Result<AnimationInfo> {
+    let webp_data = WebPDataAdapter::from_slice(data);
+
+    let mut base_dec = WebPDecoderAdapter::new(&webp_data)?;
+
+    let width = base_dec.width();
+    let height = base_dec.height();
+
+    let demux = WebPDemuxAdapter::new(&webp_data);
+    if demux.demux.is_null() {
+        return Err(TransformError::decode(ImageFormat::WebP, "WebPDemux error"));
+    }
+
+    let mut tracker = webp_check_limits(&base_dec, &demux, limits)?;
+    if base_dec.has_animation() {
+        let mut compositor = Compositor::new(width, height);
+        let mut frames_iter = demux.frames_iter();
+        while let Some(f) = frames_iter.next_checked(&mut tracker) {
+            let f = f?;
+            sink.frame(compositor.render(&f.data, &f.region()), f.duration)?;
+        }
+    } else if let RGBA8ImageDataType::Static(st_img) =
+        decode_webp_pixels(&mut base_dec, &demux, &mut tracker, width, height)?
+    {
+        sink.frame(st_img.data, 0)?;
+    }
+    sink.flush()?;
+
+    Ok(AnimationInfo {
+        loop_count: demux.get_info(WEBP_FF_LOOP_COUNT),
+        bg_color: demux.get_bg_color(),
+    })
+}
+
+/// The canvas and frame count come from the headers, so both are checked
+/// before any frame is decoded.
+fn webp_check_limits<'a>(
+    base_dec: &WebPDecoderAdapter,
+    demux: &WebPDemuxAdapter,
+    limits: &'a DecodeLimits,
+) -> Result<LimitTracker<'a>> {
+    let (width, height) = (base_dec.width(), base_dec.height());
+    let tracker = limits.tracker(width, height)?;
+
+    let frame_count = match base_dec.has_animation() {
+        true => demux.get_info(WEBP_FF_FRAME_COUNT),
+        false => 1,
+    };
+    limits.check_frames(width, height, frame_count)?;
+
+    Ok(tracker)
+}
+
+fn decode_webp_pixels(
+    base_dec: &mut WebPDecoderAdapter,
+    demux: &WebPDemuxAdapter,
+    tracker: &mut LimitTracker,
+    width: u32,
+    height: u32,
+) -> Result<RGBA8ImageDataType> {
+    if base_dec.has_animation() {
+        let mut frames = vec![];
+        let mut durations = vec![];
+        let mut regions = vec![];
+
+        let mut frames_iter = demux.frames_iter();
+        while let Some(f) = frames_iter.next_checked(tracker) {
+            let f = f?;
+            regions.push(f.region());
+            frames.push(f.data);
+            durations.push(f.duration);
+        }
+
+        Ok(RGBA8ImageDataType::Animated(RGBA8AnimatedImageData {
+            width,
+            height,
+            durations,
+            frames,
+            loop_count: demux.get_info(WEBP_FF_LOOP_COUNT),
+            bg_color: demux.get_bg_color(),
+            metadata: ImageMetadata::default(),
+            regions: Some(regions),
+        }))
+    } else {
+        let mut config = MaybeUninit::<WebPDecoderConfig>::uninit();
+
+        unsafe {
+            if WebPInitDecoderConfig(config.as_mut_ptr()) == 0 {
+                return Err(TransformError::decode(
+                    ImageFormat::WebP,
+                    "WebPInitDecoderConfig error",
+                ));
+            }
+
+            let config = config.assume_init_mut();
+
+            tracker.frame(width, height, 0)?;
+            let data = base_dec.decode_to_rgba8(config)?;
+
+            let img_buf = RgbaImage::from_raw(width, height, data).ok_or_else(|| {
+                TransformError::decode(ImageFormat::WebP, "WebPDecode RgbaImage::from_raw error")
+            })?;
+
+            Ok(RGBA8ImageDataType::Static(RGBA8StaticImageData {
+                data: img_buf,
+                width,
+                height,
+                metadata: ImageMetadata::default(),
+            }))
+        }
+    }
+}
+
+impl WebPEncodePreset {
+    pub fn to_webp_preset(self) -> libwebp_sys::WebPPreset {
+        match self {
+            Self::Default => WEBP_PRESET_DEFAULT,
+            Self::Photo => WEBP_PRESET_PHOTO,
+            Self::Picture => WEBP_PRESET_PICTURE,
+            Self::Drawing => WEBP_PRESET_DRAWING,
+            Self::Icon => WEBP_PRESET_ICON,
+            Self::Text => WEBP_PRESET_TEXT,
+        }
+    }
+}
+
+impl WebPEncodeOptions {
+    pub fn to_webp_config(&self, quality: f32) -> Result<WebPConfig> {
+        let mut config = MaybeUninit::<WebPConfig>::uninit();
+
+        unsafe {
+            if WebPConfigPreset(config.as_mut_ptr(), self.preset.to_webp_preset(), quality) == 0 {
+                return Err(TransformError::encode(
+                    ImageFormat::WebP,
+                    "WebPConfigPreset error",
+                ));
+            }
+
+            let mut config = config.assume_init();
+
+            config.quality = quality;
+            config.lossless = self.lossless as i32;
+            config.near_lossless = i32::from(self.near_lossless);
+            config.exact = self.exact as i32;
+            config.alpha_quality = i32::from(self.alpha_quality);
+            config.alpha_filtering = i32::from(self.alpha_filtering);
+            config.method = i32::from(self.method);
+            if let Some(sns_strength) = self.sns_strength {
+                config.sns_strength = i32::from(sns_strength);
+            }
+            if self.target_size.is_some() || self.target_psnr.is_some() {
+                config.target_size = self.target_size.map_or(0, |size| size as i32);
+                config.target_PSNR = self.target_psnr.unwrap_or(0.0);
+                // same as cwebp: the targets need several passes to converge
+                config.pass = 6;
+            }
+
+            if WebPValidateConfig(&config) == 0 {
+                return Err(TransformError::InvalidInput(format!(
+                    "invalid WebP encode options: {:?}",
+                    self
+                )));
+            }
+
+            Ok(config)
+        }
+    }
+}
+
+impl WebPAnimEncodeOptions {
+    pub fn to_webp_anim_encoder_options(&self) -> Result<WebPAnimEncoderOptions> {
+        let mut enc_options = MaybeUninit::<WebPAnimEncoderOptions>::uninit();
+
+        unsafe {
+            if WebPAnimEncoderOptionsInit(enc_options.as_mut_ptr()) == 0 {
+                return Err(TransformError::encode(
+                    ImageFormat::WebP,
+                    "WebPAnimEncoderOptionsInit error",
+                ));
+            }
+
+            let mut enc_options = enc_options.assume_init();
+
+            enc_options.minimize_size = self.minimize_size as i32;
+            enc_options.allow_mixed = self.allow_mixed as i32;
+            if let Some(kmin) = self.kmin {
+                enc_options.kmin = i32::try_from(kmin).unwrap_or(i32::MAX);
+            }
+            if let Some(kmax) = self.kmax {
+                enc_options.kmax = i32::try_from(kmax).unwrap_or(i32::MAX);
+            }
+
+            Ok(enc_options)
+        }
+    }
+}
+
+/// Feeds full canvases to `WebPAnimEncoder` as they arrive, which finds the
+/// deltas itself; the encoder is sized by the first frame.
+pub struct WebPAnimSink {
+    config: WebPConfig,
+    enc_options: WebPAnimEncoderOptions,
+    enc: Option<WebPAnimEncoderAdapter>,
+    timestamp_ms: u64,
+}
+
+impl WebPAnimSink {
+    pub fn new(quality: f32, options: &WebPEncodeOptions) -> Result<Self> {
+        let mut config = options.to_webp_config(quality)?;
+        // size/PSNR targets are per picture and would be applied to every frame
+        config.target_size = 0;
+        config.target_PSNR = 0.0;
+
+        Ok(Self {
+            config,
+            enc_options: options.anim.to_webp_anim_encoder_options()?,
+            enc: None,
+            timestamp_ms: 0,
+        })
+    }
+
+    /// The encoder takes `int` timestamps, so the play time has to fit one.
+    fn timestamp(&self) -> Result<i32> {
+        i32::try_from(self.timestamp_ms).map_err(|_| TransformError::LimitExceeded {
+            limit: "duration".into(),
+            value: self.timestamp_ms,
+            max: i32::MAX as u64,
+        })
+    }
+
+    pub fn add_frame(&mut self, frame: &RgbaImage, duration: u32) -> Result<()> {
+        let (width, height) = frame.dimensions();
+        let timestamp_ms = self.timestamp()?;
+        let enc = match &mut self.enc {
+            Some(enc) => enc,
+            enc => enc.insert(WebPAnimEncoderAdapter::new(
+                width,
+                height,
+                &self.enc_options,
+            )?),
+        };
+
+        // frames are stamped with their start time
+        enc.add_rgba8_frame(frame.as_bytes(), width, height, timestamp_ms, &self.config)?;
+        self.timestamp_ms += u64::from(duration);
+
+        Ok(())
+    }
+
+    pub fn assemble(
+        mut self,
+        loop_count: u32,
+        bg_color: Rgba<u8>,
+        metadata: &ImageMetadata,
+    ) -> Result<Vec<u8>> {
+        let timestamp_ms = self.timestamp()?;
+        let enc = self
+            .enc
+            .as_mut()
+            .ok_or_else(|| TransformError::encode(ImageFormat::WebP, "no frames"))?;
+        // the end timestamp gives the last frame its duration
+        enc.add_end(timestamp_ms)?;
+        let mut webp_data = enc.assemble()?;
+
+        unsafe {
+            let mut anim_params = MaybeUninit::<WebPMuxAnimParams>::zeroed().assume_init();
+
+            // GIF counts plays up to 65536, more than the 16-bit field holds
+            anim_params.loop_count = i32::from(u16::try_from(loop_count).unwrap_or(u16::MAX));
+            let [r, g, b, a] = bg_color.0;
+            anim_params.bgcolor = u32::from_be_bytes([b, g, r, a]);
+
+            let mut mux = WebPMuxAdapter::new(&mut webp_data);
+
+            mux.set_animation_params(&anim_params)?;
+            mux.set_metadata(metadata)?;
+
+            mux.assemble_to_vec()
+        }
+    }
+}
+
+pub fn encode_static_webp(
+    image_data: RGBA8StaticImageData,
+    quality: f32,
+    options: &WebPEncodeOptions,
+) -> Result<Vec<u8>> {
+    let width = image_data.width;
+    let height = image_data.height;
+
+    let config = options.to_webp_config(quality)?;
+
+    let mut pic = WebPPictureAdapter::from_rgba8(image_data.data.as_bytes(), width, height)?;
+
+    pic.encode(&config)?;
+
+    let bytes: Vec<u8> = pic.into();
+    if image_data.metadata.is_empty() {
+        return Ok(bytes);
+    }
+
+    let mut webp_data = WebPDataAdapter::from_slice(&bytes);
+    let mut mux = WebPMuxAdapter::new(&mut webp_data);
+    if mux.mux.is_null() {
+        return Err(TransformError::Mux {
+            code: None,
+            message: "WebPMuxCreate error".into(),
+        });
+    }
+
+    mux.set_metadata(&image_data.metadata)?;
+
+    mux.assemble_to_vec()
+}
+
+/// Lists every frame of an encoded WebP with the bitstream it was stored in,
+/// e.g. to see what `allow_mixed` picked.
+pub fn inspect_webp_frames(data: &[u8]) -> Result<Vec<WebPFrameReport>> {
+    let webp_data = WebPDataAdapter::from_slice(data);
+    let demux = WebPDemuxAdapter::new(&webp_data);
+
+    if demux.demux.is_null() {
+        return Err(TransformError::decode(ImageFormat::WebP, "WebPDemux error"));
+    }
+
+    Ok(demux.frame_reports())
+}
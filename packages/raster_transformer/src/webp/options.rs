@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebPEncodePreset {
+    #[default]
+    Default,
+    Photo,
+    Picture,
+    Drawing,
+    Icon,
+    Text,
+}
+
+/// Encoder knobs shared by the static and animated WebP encoders. The
+/// defaults match the previous hard-coded lossy, `method = 6` setup.
+/// The pure-Rust backend always encodes lossless and ignores them.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WebPEncodeOptions {
+    pub lossless: bool,
+    /// 0 (max preprocessing) to 100 (off); only applies to lossless encoding.
+    pub near_lossless: u8,
+    /// Keep RGB values under fully transparent pixels.
+    pub exact: bool,
+    pub alpha_quality: u8,
+    /// 0 = none, 1 = fast, 2 = best.
+    pub alpha_filtering: u8,
+    /// 0 (fast) to 6 (slowest, smallest).
+    pub method: u8,
+    pub preset: WebPEncodePreset,
+    /// Spatial noise shaping 0-100; `None` keeps the preset's value.
+    pub sns_strength: Option<u8>,
+    /// Let libwebp aim for this many output bytes; static images only.
+    pub target_size: Option<u32>,
+    /// Let libwebp aim for this PSNR in dB; static images only.
+    pub target_psnr: Option<f32>,
+    /// Only used by the animated encoder.
+    pub anim: WebPAnimEncodeOptions,
+}
+
+impl Default for WebPEncodeOptions {
+    fn default() -> Self {
+        Self {
+            lossless: false,
+            near_lossless: 100,
+            exact: false,
+            alpha_quality: 100,
+            alpha_filtering: 1,
+            method: 6,
+            preset: WebPEncodePreset::Default,
+            sns_strength: None,
+            target_size: None,
+            target_psnr: None,
+            anim: WebPAnimEncodeOptions::default(),
+        }
+    }
+}
+
+impl WebPEncodeOptions {
+    pub fn lossless() -> Self {
+        Self {
+            lossless: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// `WebPAnimEncoderOptions` overrides. `None` keeps libwebp's defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WebPAnimEncodeOptions {
+    /// Try harder to shrink the output; slower.
+    pub minimize_size: bool,
+    /// Let the encoder pick lossy or lossless per frame.
+    pub allow_mixed: bool,
+    /// Minimum distance between keyframes.
+    pub kmin: Option<u32>,
+    /// Maximum distance between keyframes; `0` disables keyframes.
+    pub kmax: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebPFrameMode {
+    Lossy,
+    Lossless,
+}
+
+/// How a single frame of an encoded WebP was stored.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebPFrameReport {
+    pub mode: WebPFrameMode,
+    pub duration: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub width: u32,
+    pub height: u32,
+}
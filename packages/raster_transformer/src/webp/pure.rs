@@ -0,0 +1,625 @@
+//! The pure-Rust backend. `image-webp` decodes single bitstreams and encodes
+//! lossless ones; the animation container is read and written here, so
+//! frames keep their sub-rectangles like with libwebp.
+
+use super::{WebPEncodeOptions, WebPFrameMode, WebPFrameReport};
+use crate::core::{
+    BlendOp, DisposeOp, FrameRegion, RGBA8AnimatedImageData, RGBA8ImageDataType,
+    RGBA8StaticImageData,
+};
+use crate::delta::{Compositor, diff_bounds};
+use crate::error::{Result, TransformError};
+use crate::format::ImageFormat;
+use crate::geometry::Rect;
+use crate::limits::{DecodeLimits, LimitTracker};
+use crate::metadata::ImageMetadata;
+use crate::stream::{AnimationInfo, FrameSink};
+use image::{Rgba, RgbaImage, imageops};
+use image_webp::{ColorType, DecodingError, EncodingError, WebPDecoder, WebPEncoder};
+use std::io::Cursor;
+
+const VP8X_ICC: u8 = 0x20;
+const VP8X_ALPHA: u8 = 0x10;
+const VP8X_EXIF: u8 = 0x08;
+const VP8X_XMP: u8 = 0x04;
+const VP8X_ANIMATION: u8 = 0x02;
+
+const ANMF_NO_BLEND: u8 = 0x02;
+const ANMF_DISPOSE_BACKGROUND: u8 = 0x01;
+
+fn decoding_error(err: DecodingError) -> TransformError {
+    TransformError::decode(ImageFormat::WebP, err)
+}
+
+fn encoding_error(err: EncodingError) -> TransformError {
+    TransformError::encode(ImageFormat::WebP, err)
+}
+
+fn read_u24(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
+
+fn write_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+/// The `(fourcc, payload)` pairs of a chunk list, e.g. the body of the RIFF
+/// or of an `ANMF`.
+fn read_chunks(mut data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = vec![];
+    while data.len() >= 8 {
+        let fourcc = [data[0], data[1], data[2], data[3]];
+        let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let rest = &data[8..];
+        let payload = rest
+            .get(..size)
+            .ok_or_else(|| TransformError::decode(ImageFormat::WebP, "truncated chunk"))?;
+        chunks.push((fourcc, payload));
+
+        // chunks are padded to an even size
+        data = rest.get(size + size % 2..).unwrap_or_default();
+    }
+
+    Ok(chunks)
+}
+
+fn riff_chunks(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(TransformError::decode(ImageFormat::WebP, "not a WebP"));
+    }
+    let riff_size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+
+    let body = data
+        .get(12..data.len().min(riff_size.saturating_add(8)))
+        .ok_or_else(|| TransformError::decode(ImageFormat::WebP, "RIFF size too small"))?;
+
+    read_chunks(body)
+}
+
+fn write_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) -> Result<()> {
+    let size = u32::try_from(payload.len())
+        .map_err(|_| TransformError::encode(ImageFormat::WebP, "chunk too large"))?;
+
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(payload);
+    if size % 2 == 1 {
+        out.push(0);
+    }
+
+    Ok(())
+}
+
+fn chunk_metadata(chunks: &[([u8; 4], &[u8])]) -> ImageMetadata {
+    let find = |fourcc: &[u8; 4]| {
+        chunks
+            .iter()
+            .find(|(c, _)| c == fourcc)
+            .map(|(_, payload)| payload.to_vec())
+    };
+
+    ImageMetadata {
+        icc: find(b"ICCP"),
+        exif: find(b"EXIF"),
+        xmp: find(b"XMP "),
+        ..ImageMetadata::default()
+    }
+}
+
+/// One `ANMF` chunk: where the frame goes and its bitstream chunks.
+struct AnmfFrame<'a> {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    duration: u32,
+    flags: u8,
+    bitstream: &'a [u8],
+}
+
+impl AnmfFrame<'_> {
+    fn parse(payload: &[u8]) -> Result<AnmfFrame<'_>> {
+        if payload.len() < 16 {
+            return Err(TransformError::decode(ImageFormat::WebP, "truncated ANMF"));
+        }
+
+        Ok(AnmfFrame {
+            x: read_u24(&payload[0..]) * 2,
+            y: read_u24(&payload[3..]) * 2,
+            width: read_u24(&payload[6..]) + 1,
+            height: read_u24(&payload[9..]) + 1,
+            duration: read_u24(&payload[12..]),
+            flags: payload[15],
+            bitstream: &payload[16..],
+        })
+    }
+
+    fn region(&self) -> FrameRegion {
+        FrameRegion {
+            x: self.x,
+            y: self.y,
+            blend: if self.flags & ANMF_NO_BLEND == 0 {
+                BlendOp::Over
+            } else {
+                BlendOp::Source
+            },
+            dispose: if self.flags & ANMF_DISPOSE_BACKGROUND != 0 {
+                DisposeOp::Background
+            } else {
+                DisposeOp::None
+            },
+        }
+    }
+
+    fn mode(&self) -> WebPFrameMode {
+        // lossy frames start with `ALPH` or `VP8 `, lossless ones with `VP8L`
+        if self.bitstream.starts_with(b"VP8L") {
+            WebPFrameMode::Lossless
+        } else {
+            WebPFrameMode::Lossy
+        }
+    }
+
+    /// Wraps the bitstream in a still WebP of its own and decodes that.
+    fn decode(&self) -> Result<RgbaImage> {
+        let mut body = b"WEBP".to_vec();
+        // only a separate `ALPH` chunk needs the extended format
+        if self.bitstream.starts_with(b"ALPH") {
+            let mut vp8x = vec![VP8X_ALPHA, 0, 0, 0];
+            write_u24(&mut vp8x, self.width - 1);
+            write_u24(&mut vp8x, self.height - 1);
+            write_chunk(&mut body, b"VP8X", &vp8x)?;
+        }
+        body.extend_from_slice(self.bitstream);
+
+        let mut still = b"RIFF".to_vec();
+        still.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        still.extend_from_slice(&body);
+
+        let data = read_pixels(&mut webp_decoder(&still)?)?;
+        if data.dimensions() != (self.width, self.height) {
+            return Err(TransformError::decode(
+                ImageFormat::WebP,
+                "frame size differs from its ANMF header",
+            ));
+        }
+
+        Ok(data)
+    }
+}
+
+/// The animation's frames and `ANIM` values, with every frame checked to be
+/// inside the canvas.
+struct Animation<'a> {
+    frames: Vec<AnmfFrame<'a>>,
+    loop_count: u32,
+    bg_color: Rgba<u8>,
+}
+
+impl Animation<'_> {
+    fn parse<'a>(chunks: &[([u8; 4], &'a [u8])], width: u32, height: u32) -> Result<Animation<'a>> {
+        let mut animation = Animation {
+            frames: vec![],
+            loop_count: 0,
+            bg_color: Rgba([0, 0, 0, 0]),
+        };
+
+        for (fourcc, payload) in chunks {
+            match fourcc {
+                b"ANIM" if payload.len() >= 6 => {
+                    let bgcolor =
+                        u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                    let [b, g, r, a] = bgcolor.to_be_bytes();
+                    animation.bg_color = Rgba([r, g, b, a]);
+                    animation.loop_count = u32::from(u16::from_le_bytes([payload[4], payload[5]]));
+                }
+                b"ANMF" => {
+                    let frame = AnmfFrame::parse(payload)?;
+                    if frame.x + frame.width > width || frame.y + frame.height > height {
+                        return Err(TransformError::decode(
+                            ImageFormat::WebP,
+                            "frame outside the canvas",
+                        ));
+                    }
+                    animation.frames.push(frame);
+                }
+                _ => {}
+            }
+        }
+
+        if animation.frames.is_empty() {
+            return Err(TransformError::decode(ImageFormat::WebP, "no frames"));
+        }
+
+        Ok(animation)
+    }
+}
+
+fn webp_decoder(data: &[u8]) -> Result<WebPDecoder<Cursor<&[u8]>>> {
+    WebPDecoder::new(Cursor::new(data)).map_err(decoding_error)
+}
+
+/// Decodes a still, or the first frame, as RGBA.
+fn read_pixels(decoder: &mut WebPDecoder<Cursor<&[u8]>>) -> Result<RgbaImage> {
+    let (width, height) = decoder.dimensions();
+    let size = decoder
+        .output_buffer_size()
+        .ok_or(TransformError::InvalidDimensions { width, height })?;
+
+    let mut buf = vec![0; size];
+    decoder.read_image(&mut buf).map_err(decoding_error)?;
+    if !decoder.has_alpha() {
+        buf = buf
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect();
+    }
+
+    RgbaImage::from_raw(width, height, buf).ok_or_else(|| {
+        TransformError::decode(ImageFormat::WebP, "WebPDecoder RgbaImage::from_raw error")
+    })
+}
+
+/// The canvas and frame count come from the headers, so both are checked
+/// before any frame is decoded.
+fn webp_check_limits<'a>(
+    decoder: &WebPDecoder<Cursor<&[u8]>>,
+    limits: &'a DecodeLimits,
+) -> Result<LimitTracker<'a>> {
+    let (width, height) = decoder.dimensions();
+    let tracker = limits.tracker(width, height)?;
+    limits.check_frames(width, height, decoder.num_frames().max(1))?;
+
+    Ok(tracker)
+}
+
+pub fn decode_webp_regions(data: &[u8], limits: &DecodeLimits) -> Result<RGBA8ImageDataType> {
+    let mut decoder = webp_decoder(data)?;
+    let (width, height) = decoder.dimensions();
+    let mut tracker = webp_check_limits(&decoder, limits)?;
+    let chunks = riff_chunks(data)?;
+
+    let mut image_data = if decoder.is_animated() {
+        let animation = Animation::parse(&chunks, width, height)?;
+
+        let mut frames = vec![];
+        let mut durations = vec![];
+        let mut regions = vec![];
+        for f in &animation.frames {
+            tracker.frame(f.width, f.height, f.duration)?;
+            frames.push(f.decode()?);
+            durations.push(f.duration);
+            regions.push(f.region());
+        }
+
+        RGBA8ImageDataType::Animated(RGBA8AnimatedImageData {
+            width,
+            height,
+            durations,
+            frames,
+            loop_count: animation.loop_count,
+            bg_color: animation.bg_color,
+            metadata: ImageMetadata::default(),
+            regions: Some(regions),
+        })
+    } else {
+        tracker.frame(width, height, 0)?;
+
+        RGBA8ImageDataType::Static(RGBA8StaticImageData {
+            data: read_pixels(&mut decoder)?,
+            width,
+            height,
+            metadata: ImageMetadata::default(),
+        })
+    };
+
+    *image_data.metadata_mut() = chunk_metadata(&chunks);
+    image_data.apply_exif_orientation();
+
+    Ok(image_data)
+}
+
+pub fn webp_metadata(data: &[u8]) -> Result<ImageMetadata> {
+    Ok(chunk_metadata(&riff_chunks(data)?))
+}
+
+/// Decodes and composites one frame at a time into `sink`, holding a single
+/// canvas; a still image is one frame of 0 ms.
+pub fn stream_webp_frames(
+    data: &[u8],
+    limits: &DecodeLimits,
+    sink: &mut dyn FrameSink,
+) -> Result<AnimationInfo> {
+    let mut decoder = webp_decoder(data)?;
+    let (width, height) = decoder.dimensions();
+    let mut tracker = webp_check_limits(&decoder, limits)?;
+
+    if !decoder.is_animated() {
+        tracker.frame(width, height, 0)?;
+        sink.frame(read_pixels(&mut decoder)?, 0)?;
+        sink.flush()?;
+
+        return Ok(AnimationInfo {
+            loop_count: 0,
+            bg_color: Rgba([0, 0, 0, 0]),
+        });
+    }
+
+    let animation = Animation::parse(&riff_chunks(data)?, width, height)?;
+    let mut compositor = Compositor::new(width, height);
+    for f in &animation.frames {
+        tracker.frame(f.width, f.height, f.duration)?;
+        sink.frame(compositor.render(&f.decode()?, &f.region()), f.duration)?;
+    }
+    sink.flush()?;
+
+    Ok(AnimationInfo {
+        loop_count: animation.loop_count,
+        bg_color: animation.bg_color,
+    })
+}
+
+/// The `VP8L` payload of a lossless encode.
+fn encode_vp8l(frame: &RgbaImage) -> Result<Vec<u8>> {
+    let mut still = vec![];
+    WebPEncoder::new(&mut still)
+        .encode(frame, frame.width(), frame.height(), ColorType::Rgba8)
+        .map_err(encoding_error)?;
+
+    // a metadata-free encode is `RIFF`, size, `WEBP` and the `VP8L` chunk
+    let (_, payload) = riff_chunks(&still)
+        .map_err(|err| TransformError::encode(ImageFormat::WebP, err))?
+        .into_iter()
+        .find(|(fourcc, _)| fourcc == b"VP8L")
+        .ok_or_else(|| TransformError::encode(ImageFormat::WebP, "no VP8L chunk"))?;
+
+    Ok(payload.to_vec())
+}
+
+struct AnimFrame {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    duration: u32,
+    vp8l: Vec<u8>,
+}
+
+/// Stores each frame as the lossless rectangle that changed since the last
+/// one, drawn without blending; `quality` and the lossy options don't apply.
+pub struct WebPAnimSink {
+    canvas: Option<RgbaImage>,
+    frames: Vec<AnimFrame>,
+    has_alpha: bool,
+}
+
+impl WebPAnimSink {
+    pub fn new(_quality: f32, _options: &WebPEncodeOptions) -> Result<Self> {
+        Ok(Self {
+            canvas: None,
+            frames: vec![],
+            has_alpha: false,
+        })
+    }
+
+    pub fn add_frame(&mut self, frame: &RgbaImage, duration: u32) -> Result<()> {
+        let (width, height) = frame.dimensions();
+        let rect = match &self.canvas {
+            None => Some(Rect::new(0, 0, width, height)),
+            Some(prev) if prev.dimensions() != frame.dimensions() => {
+                return Err(TransformError::encode(
+                    ImageFormat::WebP,
+                    "frame size differs from the canvas",
+                ));
+            }
+            Some(prev) => diff_bounds(prev, frame),
+        };
+
+        let Some(rect) = rect else {
+            // nothing changed, so the previous frame just stays up longer
+            if let Some(last) = self.frames.last_mut() {
+                last.duration = last.duration.saturating_add(duration);
+            }
+            return Ok(());
+        };
+
+        // ANMF offsets are stored halved
+        let (x, y) = (rect.x & !1, rect.y & !1);
+        let (width, height) = (rect.x + rect.width - x, rect.y + rect.height - y);
+        let fragment = imageops::crop_imm(frame, x, y, width, height).to_image();
+
+        self.has_alpha |= fragment.pixels().any(|p| p.0[3] < 255);
+        self.frames.push(AnimFrame {
+            x,
+            y,
+            width,
+            height,
+            duration,
+            vp8l: encode_vp8l(&fragment)?,
+        });
+        self.canvas = Some(frame.clone());
+
+        Ok(())
+    }
+
+    pub fn assemble(
+        self,
+        loop_count: u32,
+        bg_color: Rgba<u8>,
+        metadata: &ImageMetadata,
+    ) -> Result<Vec<u8>> {
+        let canvas = self
+            .canvas
+            .ok_or_else(|| TransformError::encode(ImageFormat::WebP, "no frames"))?;
+
+        let mut flags = VP8X_ANIMATION;
+        if self.has_alpha {
+            flags |= VP8X_ALPHA;
+        }
+        if metadata.icc.is_some() {
+            flags |= VP8X_ICC;
+        }
+        if metadata.exif.is_some() {
+            flags |= VP8X_EXIF;
+        }
+        if metadata.xmp.is_some() {
+            flags |= VP8X_XMP;
+        }
+
+        let mut vp8x = vec![flags, 0, 0, 0];
+        write_u24(&mut vp8x, canvas.width() - 1);
+        write_u24(&mut vp8x, canvas.height() - 1);
+
+        let mut body = b"WEBP".to_vec();
+        write_chunk(&mut body, b"VP8X", &vp8x)?;
+        if let Some(icc) = &metadata.icc {
+            write_chunk(&mut body, b"ICCP", icc)?;
+        }
+
+        let mut anim = vec![];
+        let [r, g, b, a] = bg_color.0;
+        anim.extend_from_slice(&u32::from_be_bytes([b, g, r, a]).to_le_bytes());
+        anim.extend_from_slice(&u16::try_from(loop_count).unwrap_or(u16::MAX).to_le_bytes());
+        write_chunk(&mut body, b"ANIM", &anim)?;
+
+        for f in &self.frames {
+            let mut anmf = vec![];
+            write_u24(&mut anmf, f.x / 2);
+            write_u24(&mut anmf, f.y / 2);
+            write_u24(&mut anmf, f.width - 1);
+            write_u24(&mut anmf, f.height - 1);
+            write_u24(&mut anmf, f.duration.min(0xFF_FFFF));
+            anmf.push(ANMF_NO_BLEND);
+            write_chunk(&mut anmf, b"VP8L", &f.vp8l)?;
+            write_chunk(&mut body, b"ANMF", &anmf)?;
+        }
+
+        if let Some(exif) = &metadata.exif {
+            write_chunk(&mut body, b"EXIF", exif)?;
+        }
+        if let Some(xmp) = &metadata.xmp {
+            write_chunk(&mut body, b"XMP ", xmp)?;
+        }
+
+        let mut out = vec![];
+        write_chunk(&mut out, b"RIFF", &body)?;
+
+        Ok(out)
+    }
+}
+
+/// Always lossless; `quality` and the lossy options don't apply.
+pub fn encode_static_webp(
+    image_data: RGBA8StaticImageData,
+    _quality: f32,
+    _options: &WebPEncodeOptions,
+) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut encoder = WebPEncoder::new(&mut out);
+
+    let ImageMetadata { icc, exif, xmp, .. } = image_data.metadata;
+    if let Some(icc) = icc {
+        encoder.set_icc_profile(icc);
+    }
+    if let Some(exif) = exif {
+        encoder.set_exif_metadata(exif);
+    }
+    if let Some(xmp) = xmp {
+        encoder.set_xmp_metadata(xmp);
+    }
+
+    encoder
+        .encode(
+            &image_data.data,
+            image_data.width,
+            image_data.height,
+            ColorType::Rgba8,
+        )
+        .map_err(encoding_error)?;
+
+    Ok(out)
+}
+
+/// Lists every frame of an encoded WebP with the bitstream it was stored in.
+pub fn inspect_webp_frames(data: &[u8]) -> Result<Vec<WebPFrameReport>> {
+    let decoder = webp_decoder(data)?;
+    let (width, height) = decoder.dimensions();
+    let chunks = riff_chunks(data)?;
+
+    if decoder.is_animated() {
+        let animation = Animation::parse(&chunks, width, height)?;
+        return Ok(animation
+            .frames
+            .iter()
+            .map(|f| WebPFrameReport {
+                mode: f.mode(),
+                duration: f.duration,
+                x_offset: f.x,
+                y_offset: f.y,
+                width: f.width,
+                height: f.height,
+            })
+            .collect());
+    }
+
+    let mode = match chunks.iter().any(|(fourcc, _)| fourcc == b"VP8L") {
+        true => WebPFrameMode::Lossless,
+        false => WebPFrameMode::Lossy,
+    };
+
+    Ok(vec![WebPFrameReport {
+        mode,
+        duration: 0,
+        x_offset: 0,
+        y_offset: 0,
+        width,
+        height,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_animation_round_trip() {
+        let first = RgbaImage::from_pixel(8, 6, Rgba([255, 0, 0, 255]));
+        let mut second = first.clone();
+        second.put_pixel(5, 3, Rgba([0, 0, 255, 128]));
+
+        let mut sink = WebPAnimSink::new(80.0, &WebPEncodeOptions::default()).unwrap();
+        sink.add_frame(&first, 100).unwrap();
+        sink.add_frame(&second, 100).unwrap();
+        sink.add_frame(&second, 50).unwrap();
+        let metadata = ImageMetadata {
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            ..ImageMetadata::default()
+        };
+        let bytes = sink.assemble(3, Rgba([1, 2, 3, 4]), &metadata).unwrap();
+
+        let reports = inspect_webp_frames(&bytes).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|r| r.mode == WebPFrameMode::Lossless));
+        // the changed pixel, widened to an even offset
+        assert_eq!(
+            (
+                reports[1].x_offset,
+                reports[1].y_offset,
+                reports[1].width,
+                reports[1].height
+            ),
+            (4, 2, 2, 2)
+        );
+        assert_eq!(reports[1].duration, 150);
+
+        let RGBA8ImageDataType::Animated(anim) =
+            crate::webp::decode_webp(&bytes, &DecodeLimits::default()).unwrap()
+        else {
+            panic!("expected an animation");
+        };
+        assert_eq!(anim.frames, vec![first, second]);
+        assert_eq!(anim.loop_count, 3);
+        assert_eq!(anim.bg_color, Rgba([1, 2, 3, 4]));
+        assert_eq!(anim.metadata, metadata);
+    }
+}